@@ -0,0 +1,21 @@
+use std::process::Command;
+
+/// Records the commit being built as `GIT_HASH`, for the provenance written into outputs.
+fn main() {
+    let hash = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=12"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=GIT_HASH={}", hash.trim());
+    }
+    // New commits move HEAD or the branch it points to, and `--dirty` follows the sources.
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    println!("cargo:rerun-if-changed=../.git/packed-refs");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+}
@@ -1,11 +1,15 @@
-use gdal::raster::{Buffer, RasterCreationOptions};
+use gdal::raster::{Buffer, ColorInterpretation, RasterCreationOptions};
 use gdal::Dataset;
 use gdal::DriverManager;
 use gdal::Metadata;
 use rayon::prelude::*;
+use std::ffi::CString;
 use std::path::Path;
 use std::time::Instant;
 
+/// Number of buckets of the default histogram stored with the output
+const HISTOGRAM_BUCKETS: usize = 256;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let start = Instant::now();
 
@@ -67,20 +71,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     out_ds.set_projection(&nir_ds.projection())?;
     out_ds.set_geo_transform(&nir_ds.geo_transform()?)?;
 
-    // Set dataset-level metadata FIRST (before getting the band)
-    out_ds.set_metadata_item("NDVI_SCALE_FACTOR", "0.0001", "")?;
-    out_ds.set_metadata_item("TIFFTAG_GDAL_NODATA", &nodata_value.to_string(), "")?;
-
-    // Now get the band after setting dataset metadata
     let mut out_band = out_ds.rasterband(1)?;
 
-    // Set band-level metadata
+    // Set nodata and the standard band scale/offset (1/scaling_factor)
     out_band.set_no_data_value(Some(nodata_value as f64))?;
-    out_band.set_metadata_item("SCALE", "0.0001", "")?; // 1/scaling_factor
-    out_band.set_metadata_item("OFFSET", "0", "")?;
-    out_band.set_metadata_item("scale_factor", "0.0001", "")?;
-    out_band.set_metadata_item("UNIT_TYPE", "NDVI", "")?;
+    out_band.set_scale(1.0 / scaling_factor as f64)?;
+    out_band.set_offset(0.0)?;
     out_band.set_description("NDVI")?;
+    out_band.set_color_interpretation(ColorInterpretation::GrayIndex)?;
+    let unit = CString::new("NDVI")?;
+    let rv = unsafe { gdal_sys::GDALSetRasterUnitType(out_band.c_rasterband(), unit.as_ptr()) };
+    if rv != gdal_sys::CPLErr::CE_None {
+        return Err("failed to set raster unit type".into());
+    }
 
     // Calculate NDVI with fixed-point scaling
     println!("Calculating NDVI...");
@@ -91,50 +94,115 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Create result buffer with i16 type
     let mut ndvi_vec = vec![0i16; width as usize * height as usize];
 
-    // Process the entire image in parallel - normalize output to -1 to 1 range
-    ndvi_vec.par_iter_mut().enumerate().for_each(|(i, ndvi)| {
-        // Apply both scale factor and offset: (DN + offset) / scale_factor
-        let nir = (nir_vec[i] - 1000.0) / reflectance_scale;
-        let red = (red_vec[i] - 1000.0) / reflectance_scale;
-
-        if nir + red > 0.0 {
-            // Calculate NDVI (always between -1 and 1)
-            let ndvi_float = (nir - red) / (nir + red);
-            // Clamp to [-0.9999, 0.9999] range to avoid int16 overflow
-            let clamped_ndvi = ndvi_float.max(-0.9999).min(0.9999);
-            // Scale to fixed-point
-            *ndvi = (clamped_ndvi * scaling_factor as f32).round() as i16;
-        } else {
-            *ndvi = nodata_value;
-        }
-    });
+    // Histogram of the stored values, with buckets centred on the integer codes at the ends
+    let histogram_min = -(scaling_factor as f64) - 0.5;
+    let histogram_max = scaling_factor as f64 + 0.5;
+    let bucket = |ndvi: i16| {
+        let position = (ndvi as f64 - histogram_min) / (histogram_max - histogram_min);
+        ((position * HISTOGRAM_BUCKETS as f64) as usize).min(HISTOGRAM_BUCKETS - 1)
+    };
+
+    // Process the entire image in parallel - normalize output to -1 to 1 range,
+    // collecting (valid count, min, max, sum, sum of squares, histogram) of the stored values
+    // on the way
+    let (valid, min, max, sum, sum_sq, mut histogram) = ndvi_vec
+        .par_iter_mut()
+        .enumerate()
+        .fold(
+            || {
+                (
+                    0u64,
+                    i16::MAX,
+                    i16::MIN,
+                    0i64,
+                    0f64,
+                    vec![0u64; HISTOGRAM_BUCKETS],
+                )
+            },
+            |(valid, min, max, sum, sum_sq, mut histogram), (i, ndvi)| {
+                // Apply both scale factor and offset: (DN + offset) / scale_factor
+                let nir = (nir_vec[i] - 1000.0) / reflectance_scale;
+                let red = (red_vec[i] - 1000.0) / reflectance_scale;
+
+                if nir + red > 0.0 {
+                    // Calculate NDVI (always between -1 and 1)
+                    let ndvi_float = (nir - red) / (nir + red);
+                    // Clamp to [-0.9999, 0.9999] range to avoid int16 overflow
+                    let clamped_ndvi = ndvi_float.max(-0.9999).min(0.9999);
+                    // Scale to fixed-point
+                    *ndvi = (clamped_ndvi * scaling_factor as f32).round() as i16;
+                    histogram[bucket(*ndvi)] += 1;
+                    (
+                        valid + 1,
+                        min.min(*ndvi),
+                        max.max(*ndvi),
+                        sum + *ndvi as i64,
+                        sum_sq + *ndvi as f64 * *ndvi as f64,
+                        histogram,
+                    )
+                } else {
+                    *ndvi = nodata_value;
+                    (valid, min, max, sum, sum_sq, histogram)
+                }
+            },
+        )
+        .reduce(
+            || {
+                (
+                    0u64,
+                    i16::MAX,
+                    i16::MIN,
+                    0i64,
+                    0f64,
+                    vec![0u64; HISTOGRAM_BUCKETS],
+                )
+            },
+            |mut a, b| {
+                for (count, other) in a.5.iter_mut().zip(b.5) {
+                    *count += other;
+                }
+                (
+                    a.0 + b.0,
+                    a.1.min(b.1),
+                    a.2.max(b.2),
+                    a.3 + b.3,
+                    a.4 + b.4,
+                    a.5,
+                )
+            },
+        );
 
     // Write the entire result at once
     println!("Writing result...");
+    let ndvi_count = ndvi_vec.len();
     let mut band_data = Buffer::new((width as usize, height as usize), ndvi_vec);
 
     out_band.write((0, 0), (width as usize, height as usize), &mut band_data)?;
 
-    // Add .aux.xml file with display hints
-    let aux_file = format!("{}.aux.xml", output_path);
-    std::fs::write(
-        &aux_file,
-        format!(
-            r#"<PAMDataset>
-  <PAMRasterBand band="1">
-    <Description>NDVI</Description>
-    <UnitType>NDVI</UnitType>
-    <Metadata>
-      <MDI key="STATISTICS_MINIMUM">-10000</MDI>
-      <MDI key="STATISTICS_MAXIMUM">10000</MDI>
-      <MDI key="SCALE">0.0001</MDI>
-      <MDI key="OFFSET">0</MDI>
-    </Metadata>
-    <ColorInterp>Gray</ColorInterp>
-  </PAMRasterBand>
-</PAMDataset>"#
-        ),
-    )?;
+    // Store the real statistics of the stored values; GDAL persists them in the PAM .aux.xml
+    if valid > 0 {
+        let mean = sum as f64 / valid as f64;
+        let std_dev = (sum_sq / valid as f64 - mean * mean).max(0.0).sqrt();
+        let valid_percent = valid as f64 / ndvi_count as f64 * 100.0;
+        let rv = unsafe {
+            gdal_sys::GDALSetRasterStatistics(
+                out_band.c_rasterband(),
+                min as f64,
+                max as f64,
+                mean,
+                std_dev,
+            )
+        };
+        if rv != gdal_sys::CPLErr::CE_None {
+            return Err("failed to set raster statistics".into());
+        }
+        out_band.set_metadata_item(
+            "STATISTICS_VALID_PERCENT",
+            &format!("{:.3}", valid_percent),
+            "",
+        )?;
+        out_band.set_default_histogram(histogram_min, histogram_max, &mut histogram)?;
+    }
 
     out_ds.flush_cache()?;
     println!(
@@ -70,9 +70,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let nodata_value = -10000; // Represents -1.0 in scaled values
     out_band.set_no_data_value(Some(nodata_value as f64))?;
 
-    // Set scale/offset as metadata using GDAL standard metadata keys
-    out_band.set_metadata_item("SCALE", "0.0001", "")?; // 1/scaling_factor
-    out_band.set_metadata_item("OFFSET", "0", "")?;
+    // Set scale/offset through the band API so readers report true NDVI values
+    out_band.set_scale(1.0 / scaling_factor as f64)?;
+    out_band.set_offset(0.0)?;
     out_band.set_description("NDVI (scaled by 10000)")?;
 
     // Calculate NDVI with fixed-point scaling
@@ -0,0 +1,492 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    ops::{DerefMut, Range},
+    panic,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use flume::{Receiver, Sender};
+use gdal::Dataset;
+use parking_lot::{Condvar, Mutex};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator as _, ParallelIterator as _};
+
+use crate::{
+    gdal_ext::{RasterBandExt as _, TypedBuffer},
+    progress::{CancelToken, Progress, ProgressHandler},
+    window::PixelWindow,
+};
+
+pub type BlockReadHandler = Box<dyn Fn(usize, usize, HashMap<usize, TypedBuffer>) + Send + Sync>;
+
+type DatasetPool = Arc<Vec<Box<[Arc<Mutex<Dataset>>]>>>;
+
+struct BlockReadRequest {
+    datasets: DatasetPool,
+    num_datasets: usize,
+    dataset_idx: usize,
+    x: usize,
+    y: usize,
+    state: BlockReadState,
+    handler: Arc<BlockReadHandler>,
+    /// Reorder buffer and position of the block in it, in ordered mode.
+    order: Option<(Arc<ReorderBuffer>, usize)>,
+    progress: Option<Arc<Progress>>,
+    cancel: Option<CancelToken>,
+}
+
+#[derive(Clone)]
+struct BlockReadState {
+    blocks: Arc<Mutex<HashMap<usize, TypedBuffer>>>,
+    region: PixelWindow,
+}
+
+type CompletedBlock = (
+    usize,
+    usize,
+    HashMap<usize, TypedBuffer>,
+    Arc<BlockReadHandler>,
+);
+
+/// Releases completed blocks to their handlers in the order they were requested, holding the
+/// ones that complete early.
+struct ReorderBuffer {
+    /// Most blocks read ahead of the next one to release.
+    capacity: usize,
+    /// Position given to the next requested block.
+    requested: AtomicUsize,
+    state: Mutex<ReorderState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct ReorderState {
+    /// Position of the next block to release.
+    next: usize,
+    pending: BTreeMap<usize, CompletedBlock>,
+    /// Set while a thread is calling the handlers of released blocks.
+    releasing: bool,
+    /// Set when the run is cancelled and blocks will be missing from the sequence.
+    abandoned: bool,
+}
+
+impl ReorderBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            requested: AtomicUsize::new(0),
+            state: Mutex::new(ReorderState::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until block `position` is within `capacity` blocks of the next one to release.
+    ///
+    /// Blocks are queued in order of position, so the next one is always being read by a worker
+    /// that is not waiting here.
+    fn wait_for_room(&self, position: usize) {
+        let mut state = self.state.lock();
+        while !state.abandoned && position >= state.next + self.capacity {
+            self.released.wait(&mut state);
+        }
+    }
+
+    /// Hands completed block `position` over, then calls the handlers of all the blocks that are
+    /// now in order.
+    ///
+    /// Handlers run outside the lock so that workers can keep handing blocks over meanwhile. Only
+    /// one thread releases blocks at a time, which keeps the handlers in order: the others leave
+    /// their blocks for it to pick up before it stops.
+    fn complete(&self, position: usize, block: CompletedBlock) {
+        let mut guard = self.state.lock();
+        if guard.abandoned {
+            return;
+        }
+        guard.pending.insert(position, block);
+        if guard.releasing {
+            return;
+        }
+        guard.releasing = true;
+        loop {
+            let state = &mut *guard;
+            let mut ready = Vec::new();
+            while let Some(block) = state.pending.remove(&state.next) {
+                ready.push(block);
+                state.next += 1;
+            }
+            if ready.is_empty() {
+                state.releasing = false;
+                return;
+            }
+            drop(guard);
+            self.released.notify_all();
+            for (x, y, blocks, handler) in ready {
+                (handler)(x, y, blocks);
+            }
+            guard = self.state.lock();
+        }
+    }
+
+    /// Stops ordering after a cancellation: drops the held blocks and their handlers, and lets
+    /// the waiting workers go on to skip their requests.
+    fn abandon(&self) {
+        let pending = {
+            let mut state = self.state.lock();
+            state.abandoned = true;
+            mem::take(&mut state.pending)
+        };
+        drop(pending);
+        self.released.notify_all();
+    }
+}
+
+pub struct ParallelBlockReader {
+    datasets: DatasetPool,
+    pub region_size: (usize, usize),
+    raster_size: (usize, usize),
+    /// Window of the source rasters being read.
+    pub window: PixelWindow,
+    /// Pixels read around each block, for neighbourhood operations.
+    pub halo: usize,
+    /// Blocks intersecting `window`, as ranges of block indices.
+    pub blocks: (Range<usize>, Range<usize>),
+    workers: Vec<JoinHandle<()>>,
+    req_tx: Sender<BlockReadRequest>,
+    order: Option<Arc<ReorderBuffer>>,
+    progress: Option<Arc<Progress>>,
+    cancel: Option<CancelToken>,
+}
+
+impl ParallelBlockReader {
+    /// Opens `paths` once per thread, reading only the blocks that intersect `window` (the whole
+    /// raster if `None`).
+    pub fn new(
+        paths: &[String],
+        threads: usize,
+        window: Option<PixelWindow>,
+    ) -> gdal::errors::Result<Self> {
+        Self::with_halo(paths, threads, window, 0)
+    }
+
+    /// Like [`ParallelBlockReader::new`], but reads `halo` more pixels on each side of every block
+    /// (as far as the raster extends, even outside `window`), so that neighbourhood operations
+    /// see the same pixels as on the whole raster.
+    pub fn with_halo(
+        paths: &[String],
+        threads: usize,
+        window: Option<PixelWindow>,
+        halo: usize,
+    ) -> gdal::errors::Result<Self> {
+        let datasets = Arc::new(
+            (0..threads)
+                .into_par_iter()
+                .map(|_| -> gdal::errors::Result<Box<[Arc<Mutex<Dataset>>]>> {
+                    Ok(paths
+                        .par_iter()
+                        .map(|p| -> gdal::errors::Result<Arc<Mutex<Dataset>>> {
+                            Ok(Arc::new(Mutex::new(Dataset::open(p)?)))
+                        })
+                        .collect::<gdal::errors::Result<Vec<_>>>()?
+                        .into_boxed_slice())
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+
+        let (req_tx, req_rx) = flume::unbounded();
+
+        let mut workers = Vec::new();
+        for _thread_id in 0..threads {
+            let req_rx: Receiver<BlockReadRequest> = req_rx.clone();
+
+            workers.push(thread::spawn(move || {
+                for request in req_rx {
+                    if let Some((order, position)) = &request.order {
+                        order.wait_for_room(*position);
+                    }
+                    // Dropping the remaining requests drops their handlers, which ends the loops
+                    // receiving blocks from them.
+                    if request
+                        .cancel
+                        .as_ref()
+                        .is_some_and(CancelToken::is_cancelled)
+                    {
+                        if let Some((order, _)) = &request.order {
+                            order.abandon();
+                        }
+                        continue;
+                    }
+                    let block = {
+                        let region = request.state.region;
+                        let dataset = request.datasets[_thread_id][request.dataset_idx].lock();
+                        let band = dataset.rasterband(1).unwrap();
+
+                        // println!(
+                        //     "Reading block {}, {} in dataset {} on thread {}",
+                        //     request.x, request.y, request.idx, thread_id
+                        // );
+
+                        band.read_typed(region.offset, region.size).unwrap()
+                        // band.read_typed_block(request.x, request.y).unwrap()
+                    };
+                    if let Some(progress) = &request.progress {
+                        progress.bytes_read(block.byte_len());
+                    }
+                    let blocks = {
+                        let mut blocks = request.state.blocks.lock();
+                        blocks.insert(request.dataset_idx, block);
+                        if blocks.len() == request.num_datasets {
+                            let blocks = mem::take(blocks.deref_mut());
+                            Some(blocks)
+                        } else {
+                            None
+                        }
+                    };
+                    if let Some(blocks) = blocks {
+                        if let Some(progress) = &request.progress {
+                            progress.block_done();
+                        }
+                        let BlockReadRequest { handler, .. } = request;
+                        match request.order {
+                            Some((order, position)) => {
+                                order.complete(position, (request.x, request.y, blocks, handler));
+                            }
+                            None => (handler)(request.x, request.y, blocks),
+                        }
+                    }
+                }
+            }));
+        }
+
+        let dataset = datasets[0][0].lock();
+        let band = dataset.rasterband(1)?;
+        let raster_size = band.size();
+        let block_size = band.block_size();
+        // let block_size = (2048, 2048);
+        let _geo_transform = dataset.geo_transform()?;
+        drop(dataset);
+
+        let region_size = block_size;
+        let window = window.unwrap_or_else(|| PixelWindow::full(raster_size));
+        let blocks = window.blocks(region_size);
+
+        Ok(Self {
+            datasets,
+            region_size,
+            raster_size,
+            window,
+            halo,
+            blocks,
+            workers,
+            req_tx,
+            order: None,
+            progress: None,
+            cancel: None,
+        })
+    }
+
+    /// Makes handlers receive blocks in the order of the [`ParallelBlockReader::run`] calls
+    /// (raster order for the usual row-major loop) instead of as reads complete, as streaming and
+    /// strip-based outputs need. Workers read at most `capacity` blocks ahead of the next one to
+    /// hand over, which bounds the blocks held back while keeping reads parallel.
+    #[must_use]
+    pub fn ordered(mut self, capacity: usize) -> Self {
+        self.order = Some(Arc::new(ReorderBuffer::new(capacity)));
+        self
+    }
+
+    /// Reports the blocks done out of those requested, the bytes read and the time left to
+    /// `handler` as blocks complete, and once more from [`ParallelBlockReader::join`].
+    #[must_use]
+    pub fn on_progress(mut self, handler: ProgressHandler) -> Self {
+        self.progress = Some(Arc::new(Progress::new(handler)));
+        self
+    }
+
+    /// Skips the blocks not yet read once `token` is cancelled, without calling their handlers.
+    /// Blocks being read still complete, so handlers see whole blocks until the end.
+    #[must_use]
+    pub fn cancel_on(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Pixels read for block `(block_x, block_y)`: the source block clipped to the window.
+    #[must_use]
+    pub fn region(&self, block_x: usize, block_y: usize) -> PixelWindow {
+        self.window.block(self.region_size, (block_x, block_y))
+    }
+
+    /// Pixels actually read for block `(block_x, block_y)`: its region grown by the halo and
+    /// clipped to the raster.
+    #[must_use]
+    pub fn read_region(&self, block_x: usize, block_y: usize) -> PixelWindow {
+        self.region(block_x, block_y)
+            .grow(self.halo, self.raster_size)
+    }
+
+    pub fn run(
+        &self,
+        block_x: usize,
+        block_y: usize,
+        dataset_indices: &[usize],
+        handler: BlockReadHandler,
+    ) {
+        let handler = Arc::new(handler);
+        // A block without reads would never complete and hold back all the following ones.
+        let order = self
+            .order
+            .as_ref()
+            .filter(|_| !dataset_indices.is_empty())
+            .map(|order| {
+                let position = order.requested.fetch_add(1, Ordering::Relaxed);
+                (order.clone(), position)
+            });
+        if let Some(progress) = self
+            .progress
+            .as_ref()
+            .filter(|_| !dataset_indices.is_empty())
+        {
+            progress.block_requested();
+        }
+        let state = BlockReadState {
+            region: self.read_region(block_x, block_y),
+            blocks: Arc::new(Mutex::new(HashMap::new())),
+        };
+        for &idx in dataset_indices {
+            let request = BlockReadRequest {
+                datasets: self.datasets.clone(),
+                num_datasets: dataset_indices.len(),
+                dataset_idx: idx,
+                x: block_x,
+                y: block_y,
+                state: state.clone(),
+                handler: handler.clone(),
+                order: order.clone(),
+                progress: self.progress.clone(),
+                cancel: self.cancel.clone(),
+            };
+            self.req_tx.send(request).unwrap();
+        }
+    }
+
+    pub fn join(self) {
+        drop(self.req_tx);
+
+        let mut errors = Vec::new();
+        for worker in self.workers {
+            if let Err(e) = worker.join() {
+                errors.push(e);
+            }
+        }
+
+        if !errors.is_empty() {
+            panic::resume_unwind(Box::new(errors));
+        }
+
+        if let Some(progress) = self.progress {
+            progress.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Block at `position` whose handler records its position in `released`.
+    fn block(position: usize, released: &Arc<Mutex<Vec<usize>>>) -> CompletedBlock {
+        let released = released.clone();
+        let handler: BlockReadHandler = Box::new(move |x, _, _| released.lock().push(x));
+        (position, 0, HashMap::new(), Arc::new(handler))
+    }
+
+    #[test]
+    fn blocks_are_released_in_request_order() {
+        let order = ReorderBuffer::new(4);
+        let released = Arc::new(Mutex::new(Vec::new()));
+
+        for position in [2, 1, 4] {
+            order.complete(position, block(position, &released));
+        }
+        assert!(released.lock().is_empty());
+        order.complete(0, block(0, &released));
+        assert_eq!(*released.lock(), [0, 1, 2]);
+        order.complete(3, block(3, &released));
+        assert_eq!(*released.lock(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn blocks_completed_concurrently_stay_in_order() {
+        let order = Arc::new(ReorderBuffer::new(3));
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let next = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..4)
+            .map(|_| {
+                let (order, released, next) = (order.clone(), released.clone(), next.clone());
+                thread::spawn(move || loop {
+                    let position = next.fetch_add(1, Ordering::Relaxed);
+                    if position >= 200 {
+                        break;
+                    }
+                    order.wait_for_room(position);
+                    // Later blocks of a batch finish first.
+                    thread::sleep(Duration::from_micros(((7 - position % 8) * 20) as u64));
+                    order.complete(position, block(position, &released));
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert_eq!(*released.lock(), (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn handlers_run_outside_the_lock() {
+        let order = Arc::new(ReorderBuffer::new(2));
+        let released = Arc::new(Mutex::new(Vec::new()));
+
+        // The first handler waits for another worker to hand block 1 over, which would deadlock
+        // if it held the lock.
+        let handler: BlockReadHandler = {
+            let (order, released) = (order.clone(), released.clone());
+            Box::new(move |x, _, _| {
+                released.lock().push(x);
+                let (order, released) = (order.clone(), released.clone());
+                thread::spawn(move || order.complete(1, block(1, &released)))
+                    .join()
+                    .unwrap();
+            })
+        };
+        order.complete(0, (0, 0, HashMap::new(), Arc::new(handler)));
+
+        assert_eq!(*released.lock(), [0, 1]);
+    }
+
+    #[test]
+    fn abandoning_drops_held_blocks_and_wakes_waiting_workers() {
+        let order = Arc::new(ReorderBuffer::new(1));
+        let released = Arc::new(Mutex::new(Vec::new()));
+
+        order.complete(1, block(1, &released));
+        let waiting = {
+            let order = order.clone();
+            thread::spawn(move || order.wait_for_room(5))
+        };
+        order.abandon();
+        waiting.join().unwrap();
+
+        order.complete(0, block(0, &released));
+        assert!(released.lock().is_empty());
+        // The held block and its handler were dropped.
+        assert_eq!(Arc::strong_count(&released), 1);
+    }
+}
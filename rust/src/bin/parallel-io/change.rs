@@ -0,0 +1,346 @@
+use std::time::Instant;
+
+use anyhow::{bail, ensure, Result};
+use gdal::{
+    raster::{Buffer, ColorInterpretation, RasterBand},
+    Dataset, Metadata as _,
+};
+
+use crate::{
+    block_reader::ParallelBlockReader,
+    classify::{self, Breaks},
+    gdal_ext::RasterBandExt as _,
+    index::Index,
+    output_format::OutputFormatArgs,
+    output_type::{Encoding, OutputType},
+    progress,
+    provenance::{ChecksumArgs, Provenance},
+    sensor::SensorArgs,
+    stac,
+    stats::{self, BandInfo, Statistics},
+};
+
+/// Range of the difference of two normalised difference indices.
+const DIFFERENCE_RANGE: (f64, f64) = (-2.0, 2.0);
+
+/// Computes the change of an index between two co-registered scenes, as `before - after` (so that
+/// vegetation loss and burn severity are positive, as in dNBR).
+#[derive(clap::Args)]
+pub struct ChangeArgs {
+    /// Index to difference
+    #[arg(long, value_enum, default_value_t = Index::Ndvi)]
+    index: Index,
+
+    /// The two bands of the index (e.g. B08 and B04 for NDVI, B8A and B12 for NBR) before the
+    /// change, or `item=<path>` of a STAC Item holding both
+    #[arg(long, num_args = 1..=2, value_names = ["A", "B"], required = true)]
+    before: Vec<String>,
+
+    /// The same two bands after the change
+    #[arg(long, num_args = 1..=2, value_names = ["A", "B"], required = true)]
+    after: Vec<String>,
+
+    /// Difference raster
+    #[arg(short, long)]
+    output: String,
+
+    /// Output data type
+    #[arg(long = "ot", value_enum, default_value_t = OutputType::Int16)]
+    output_type: OutputType,
+
+    /// Classify the difference, with `usgs-dnbr` burn severity classes or comma-separated
+    /// thresholds, and report the area of each class
+    #[arg(long)]
+    classes: Option<Breaks>,
+
+    /// Byte raster of the classes, 0 being nodata
+    #[arg(long, requires = "classes")]
+    classes_output: Option<String>,
+
+    #[command(flatten)]
+    format: OutputFormatArgs,
+
+    #[command(flatten)]
+    sensor: SensorArgs,
+
+    #[command(flatten)]
+    checksums: ChecksumArgs,
+
+    /// Number of threads reading blocks
+    #[arg(long, default_value_t = 8)]
+    io_threads: usize,
+}
+
+impl ChangeArgs {
+    /// Paths the command writes.
+    #[must_use]
+    pub fn outputs(&self) -> Vec<&str> {
+        [Some(&self.output), self.classes_output.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Stores the pixel count and area of each class as `CLASS_<n>_LABEL`, `CLASS_<n>_PIXELS` and
+/// `CLASS_<n>_HECTARES` metadata items of `band`, so that the table printed at the end stays with
+/// the outputs.
+fn write_class_areas(
+    band: &mut RasterBand,
+    breaks: &Breaks,
+    class_pixels: &[u64],
+    hectares: Option<f64>,
+) -> gdal::errors::Result<()> {
+    for (class, label) in breaks.labels.iter().enumerate() {
+        let pixels = class_pixels[class + 1];
+        let key = format!("CLASS_{}", class + 1);
+        band.set_metadata_item(&format!("{key}_LABEL"), label, "")?;
+        band.set_metadata_item(&format!("{key}_PIXELS"), &pixels.to_string(), "")?;
+        if let Some(ha) = hectares {
+            band.set_metadata_item(
+                &format!("{key}_HECTARES"),
+                &format!("{:.2}", ha * pixels as f64),
+                "",
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Classes of a block of differences, counting the pixels of each class (0 being nodata) in
+/// `class_pixels`.
+fn classify_block(breaks: &Breaks, difference: &[f32], class_pixels: &mut [u64]) -> Vec<u8> {
+    let classes = difference
+        .iter()
+        .map(|&v| breaks.classify(v))
+        .collect::<Vec<_>>();
+    for &class in &classes {
+        class_pixels[class as usize] += 1;
+    }
+    classes
+}
+
+/// Computes the difference (and classes) block by block.
+pub fn run(args: &ChangeArgs) -> Result<()> {
+    let start = Instant::now();
+    let name = format!("d{}", args.index.name());
+
+    let [before_a, before_b] = stac::resolve_index_bands(&args.before, args.index.bands())?;
+    let [after_a, after_b] = stac::resolve_index_bands(&args.after, args.index.bands())?;
+    let inputs = [before_a, before_b, after_a, after_b];
+    let reference = Dataset::open(&inputs[0])?;
+    let geo_transform = reference.geo_transform()?;
+    for path in &inputs[1..] {
+        let dataset = Dataset::open(path)?;
+        ensure!(
+            dataset.raster_size() == reference.raster_size()
+                && dataset.geo_transform()? == geo_transform,
+            "{path} is not co-registered with {}",
+            inputs[0]
+        );
+    }
+
+    let calibration = inputs
+        .iter()
+        .map(|path| args.sensor.calibration(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let cancel = progress::interrupt_token();
+    let mut block_reader = ParallelBlockReader::new(&inputs, args.io_threads, None)?
+        .on_progress(progress::bar())
+        .cancel_on(cancel.clone());
+    if let Some(capacity) = args.format.ordered {
+        block_reader = block_reader.ordered(capacity);
+    }
+    let window = block_reader.window;
+
+    let encoding = Encoding::new(args.output_type, DIFFERENCE_RANGE, None, None)?;
+    let mut output = args
+        .format
+        .create(encoding.output_type, &args.output, window.size, 1)?;
+    output.set_projection(&reference.projection())?;
+    output.set_geo_transform(&geo_transform)?;
+    args.sensor.set_metadata(&mut output, &inputs)?;
+    let provenance = Provenance::new(
+        &inputs,
+        format!("before - after of {}", args.index.expression()),
+    )
+    .with_calibration(&calibration)
+    .with_checksums(&args.checksums)?;
+    provenance.write(&mut output)?;
+    encoding.apply(&mut output.rasterband(1)?)?;
+
+    let mut classes_output = match &args.classes_output {
+        Some(path) => {
+            let mut dataset = args
+                .format
+                .create(OutputType::UInt8, path, window.size, 1)?;
+            dataset.set_projection(&reference.projection())?;
+            dataset.set_geo_transform(&geo_transform)?;
+            dataset.rasterband(1)?.set_no_data_value(Some(0.0))?;
+            Some(dataset)
+        }
+        None => None,
+    };
+
+    // Bounded, so that readers wait for the writers instead of queueing up blocks in memory.
+    let (tx, rx) = flume::bounded(args.io_threads);
+    let dataset_indices = (0..inputs.len()).collect::<Vec<_>>();
+    for y in block_reader.blocks.1.clone() {
+        for x in block_reader.blocks.0.clone() {
+            let tx = tx.clone();
+            block_reader.run(
+                x,
+                y,
+                &dataset_indices,
+                Box::new(move |x, y, blocks| {
+                    tx.send((x, y, blocks)).unwrap();
+                }),
+            );
+        }
+    }
+    drop(tx);
+
+    let mut stats = Statistics::new(None);
+    let mut class_pixels = vec![0u64; args.classes.as_ref().map_or(0, Breaks::classes) + 1];
+    let (mut before, mut after) = (Vec::new(), Vec::new());
+    let mut difference = Vec::new();
+    for (x, y, mut blocks) in rx {
+        let mut band = |i: usize| blocks.remove(&i).unwrap();
+        let (before_a, before_b, after_a, after_b) = (band(0), band(1), band(2), band(3));
+        args.index.compute(
+            before_a.as_u16().unwrap().data(),
+            before_b.as_u16().unwrap().data(),
+            [calibration[0], calibration[1]],
+            &mut before,
+        );
+        args.index.compute(
+            after_a.as_u16().unwrap().data(),
+            after_b.as_u16().unwrap().data(),
+            [calibration[2], calibration[3]],
+            &mut after,
+        );
+        difference.clear();
+        difference.extend(before.iter().zip(&after).map(|(b, a)| b - a));
+
+        let region = block_reader.region(x, y);
+        let offset = window.relative_offset(&region);
+        stats.add_block(&difference);
+        output
+            .rasterband(1)?
+            .write_typed(offset, &mut encoding.encode(region.size, &difference))?;
+
+        if let Some(breaks) = &args.classes {
+            let classes = classify_block(breaks, &difference, &mut class_pixels);
+            if let Some(dataset) = &mut classes_output {
+                let mut buffer = Buffer::new(region.size, classes);
+                dataset
+                    .rasterband(1)?
+                    .write(offset, region.size, &mut buffer)?;
+            }
+        }
+    }
+
+    block_reader.join();
+
+    if cancel.is_cancelled() {
+        args.format.discard(output, &args.output)?;
+        if let (Some(dataset), Some(path)) = (classes_output, &args.classes_output) {
+            args.format.discard(dataset, path)?;
+        }
+        bail!("interrupted, {} not written", args.output);
+    }
+
+    let hectares = classify::pixel_hectares(&reference)?;
+    if let Some(breaks) = &args.classes {
+        write_class_areas(&mut output.rasterband(1)?, breaks, &class_pixels, hectares)?;
+    }
+    stats::finalize_band(
+        &mut output.rasterband(1)?,
+        &stats,
+        &encoding,
+        &BandInfo {
+            description: &name,
+            unit: args.index.name(),
+        },
+    )?;
+    output.flush_cache()?;
+    let item = stac::raster_item(&output, &args.output, &provenance)?;
+    args.format.finish(output, &args.output, &provenance)?;
+    item.write()?;
+
+    println!(
+        "{name} written to {} in {:.3}s",
+        args.output,
+        start.elapsed().as_secs_f64()
+    );
+    println!("{name} {stats}");
+
+    if let Some(breaks) = &args.classes {
+        if let Some(mut dataset) = classes_output {
+            let mut band = dataset.rasterband(1)?;
+            let mut names = vec!["nodata"];
+            names.extend(breaks.labels.iter().map(String::as_str));
+            band.set_category_names(&names)?;
+            band.set_description(&format!("{name} class"))?;
+            band.set_color_interpretation(ColorInterpretation::GrayIndex)?;
+            write_class_areas(&mut band, breaks, &class_pixels, hectares)?;
+            let provenance = Provenance {
+                expression: breaks.expression(&provenance.expression),
+                ..provenance
+            };
+            provenance.write(&mut dataset)?;
+            dataset.flush_cache()?;
+            let path = args.classes_output.as_deref().unwrap();
+            let item = stac::raster_item(&dataset, path, &provenance)?;
+            args.format.finish(dataset, path, &provenance)?;
+            item.write()?;
+        }
+
+        println!(
+            "{:>5}  {:<28} {:>12} {:>12}",
+            "class", "label", "pixels", "hectares"
+        );
+        for (class, label) in breaks.labels.iter().enumerate() {
+            let pixels = class_pixels[class + 1];
+            let area =
+                hectares.map_or_else(|| "-".to_owned(), |ha| format!("{:.2}", ha * pixels as f64));
+            println!("{:>5}  {label:<28} {pixels:>12} {area:>12}", class + 1);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use gdal::DriverManager;
+
+    use super::*;
+
+    #[test]
+    fn class_pixels_accumulate_over_blocks() {
+        let breaks = Breaks::usgs_dnbr();
+        let mut class_pixels = vec![0; breaks.classes() + 1];
+        let classes = classify_block(&breaks, &[0.05, 0.3, f32::NAN], &mut class_pixels);
+        assert_eq!(classes, [3, 5, 0]);
+        classify_block(&breaks, &[0.7, 0.35], &mut class_pixels);
+        assert_eq!(class_pixels, [1, 0, 0, 1, 0, 2, 0, 1]);
+    }
+
+    #[test]
+    fn class_areas_are_stored_with_the_band() {
+        let breaks = "0.1".parse::<Breaks>().unwrap();
+        let driver = DriverManager::get_driver_by_name("MEM").unwrap();
+        let dataset = driver.create_with_band_type::<u8, _>("", 1, 1, 1).unwrap();
+        let mut band = dataset.rasterband(1).unwrap();
+        write_class_areas(&mut band, &breaks, &[5, 100, 40], Some(0.01)).unwrap();
+
+        let item = |key: &str| band.metadata_item(key, "");
+        assert_eq!(item("CLASS_1_LABEL").as_deref(), Some("< 0.1"));
+        assert_eq!(item("CLASS_2_PIXELS").as_deref(), Some("40"));
+        assert_eq!(item("CLASS_1_HECTARES").as_deref(), Some("1.00"));
+        assert_eq!(item("CLASS_2_HECTARES").as_deref(), Some("0.40"));
+    }
+}
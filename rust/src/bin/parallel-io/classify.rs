@@ -0,0 +1,560 @@
+use std::{str::FromStr, time::Instant};
+
+use anyhow::{bail, ensure, Context as _, Result};
+use gdal::{
+    raster::{Buffer, RasterCreationOptions},
+    vector::{Feature, LayerAccess as _, LayerOptions, OGRFieldType, OGRwkbGeometryType},
+    Dataset, DriverManager, DriverType,
+};
+use rayon::{
+    iter::{IndexedParallelIterator as _, ParallelIterator as _},
+    slice::ParallelSliceMut as _,
+};
+
+use crate::{
+    block_reader::ParallelBlockReader,
+    gdal_ext::RasterBandExt as _,
+    index::{Index, IndexSource},
+    progress,
+    provenance::{ChecksumArgs, Provenance},
+    reproject,
+    sensor::SensorArgs,
+    stac, vsi,
+};
+
+/// USGS dNBR burn severity classes (Key & Benson), as upper bounds with their labels.
+const USGS_DNBR: [(f64, &str); 6] = [
+    (-0.25, "enhanced regrowth, high"),
+    (-0.1, "enhanced regrowth, low"),
+    (0.1, "unburned"),
+    (0.27, "low severity"),
+    (0.44, "moderate-low severity"),
+    (0.66, "moderate-high severity"),
+];
+
+/// Class breaks: values below `thresholds[0]` are class 1, values in
+/// `thresholds[i - 1]..thresholds[i]` are class `i + 1`, and values from the last threshold up
+/// are the last class. Class 0 is nodata.
+#[derive(Clone, Debug)]
+pub struct Breaks {
+    pub thresholds: Vec<f64>,
+    /// One label per class, starting with class 1.
+    pub labels: Vec<String>,
+}
+
+impl Breaks {
+    /// USGS dNBR burn severity classes.
+    #[must_use]
+    pub fn usgs_dnbr() -> Self {
+        let mut labels = USGS_DNBR
+            .iter()
+            .map(|(_, label)| (*label).to_owned())
+            .collect::<Vec<_>>();
+        labels.push("high severity".to_owned());
+        Self {
+            thresholds: USGS_DNBR.iter().map(|&(t, _)| t).collect(),
+            labels,
+        }
+    }
+
+    /// Number of classes, excluding nodata.
+    #[must_use]
+    pub fn classes(&self) -> usize {
+        self.thresholds.len() + 1
+    }
+
+    /// How classes derive from `values`, for provenance records.
+    #[must_use]
+    pub fn expression(&self, values: &str) -> String {
+        let thresholds = self
+            .thresholds
+            .iter()
+            .map(f64::to_string)
+            .collect::<Vec<_>>();
+        format!("class of {values} with breaks at {}", thresholds.join(", "))
+    }
+
+    /// Class of `value`, 0 for NaN.
+    ///
+    /// Thresholds are compared at the precision of the values, so that a value equal to a
+    /// threshold as written (e.g. `0.44`) falls in the class above it.
+    #[must_use]
+    pub fn classify(&self, value: f32) -> u8 {
+        if value.is_nan() {
+            0
+        } else {
+            1 + self.thresholds.partition_point(|&t| t as f32 <= value) as u8
+        }
+    }
+}
+
+impl FromStr for Breaks {
+    type Err = anyhow::Error;
+
+    /// Parses `usgs-dnbr` or comma-separated increasing thresholds, e.g. `-0.1,0.1,0.3`.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("usgs-dnbr") {
+            return Ok(Self::usgs_dnbr());
+        }
+
+        let thresholds = s
+            .split(',')
+            .map(|t| t.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if thresholds.is_empty() || thresholds.len() > 254 {
+            bail!("expected 1 to 254 thresholds");
+        }
+        ensure!(
+            thresholds.windows(2).all(|w| w[0] < w[1]),
+            "thresholds must be increasing"
+        );
+
+        let labels = (0..=thresholds.len())
+            .map(|i| match i {
+                0 => format!("< {}", thresholds[0]),
+                i if i == thresholds.len() => format!(">= {}", thresholds[i - 1]),
+                i => format!("{} to {}", thresholds[i - 1], thresholds[i]),
+            })
+            .collect();
+        Ok(Self { thresholds, labels })
+    }
+}
+
+/// Area of a pixel of `dataset` in hectares, if its CRS is projected.
+pub fn pixel_hectares(dataset: &Dataset) -> Result<Option<f64>> {
+    let srs = dataset.spatial_ref()?;
+    if !srs.is_projected() {
+        return Ok(None);
+    }
+    let gt = dataset.geo_transform()?;
+    let metres = srs.linear_units();
+    let area = (gt[1] * gt[5] - gt[2] * gt[4]).abs() * metres * metres;
+    Ok(Some(area / 10_000.0))
+}
+
+/// Classifies an index with thresholds or breaks and polygonises the classes.
+///
+/// The whole class grid is held in memory for the majority filter, the sieve and
+/// polygonisation: 1 byte per pixel, twice over while it is filtered or copied into the raster
+/// that is polygonised, so about 240 MB for a full 10980 x 10980 tile.
+#[derive(clap::Args)]
+pub struct ClassifyArgs {
+    /// Index to classify
+    #[arg(long, value_enum, default_value_t = Index::Ndvi)]
+    index: Index,
+
+    /// First band of the index (e.g. B03 for MNDWI), or `item=<path>` of a STAC Item holding
+    /// both bands
+    #[arg(long, required_unless_present = "raster")]
+    a: Option<String>,
+
+    /// Second band of the index (e.g. B11 for MNDWI), resampled to the grid of the first one if
+    /// needed; taken from the item of `--a` by default
+    #[arg(long)]
+    b: Option<String>,
+
+    /// Index raster written by a previous run, used instead of computing the index
+    #[arg(long, conflicts_with_all = ["a", "b"])]
+    raster: Option<String>,
+
+    /// A threshold (two classes) or comma-separated increasing breaks
+    #[arg(long)]
+    breaks: Breaks,
+
+    /// Comma-separated class labels, from the lowest class up
+    #[arg(long, value_delimiter = ',')]
+    labels: Option<Vec<String>>,
+
+    /// Classes to polygonise (1 being below the first break), all by default
+    #[arg(long, value_delimiter = ',')]
+    keep: Vec<u8>,
+
+    /// Passes of a 3x3 majority filter smoothing class boundaries
+    #[arg(long, default_value_t = 0)]
+    majority: usize,
+
+    /// Minimum mapping unit in hectares: smaller regions are merged into their largest neighbour
+    #[arg(long)]
+    mmu: Option<f64>,
+
+    /// Polygon output, a GeoPackage or GeoJSON file
+    #[arg(short, long)]
+    output: String,
+
+    /// Also write the cleaned class raster
+    #[arg(long)]
+    raster_output: Option<String>,
+
+    #[command(flatten)]
+    sensor: SensorArgs,
+
+    #[command(flatten)]
+    checksums: ChecksumArgs,
+
+    /// Number of threads reading blocks
+    #[arg(long, default_value_t = 8)]
+    io_threads: usize,
+}
+
+impl ClassifyArgs {
+    /// Paths the command writes.
+    #[must_use]
+    pub fn outputs(&self) -> Vec<&str> {
+        [Some(&self.output), self.raster_output.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// One pass of a 3x3 majority filter over a class grid.
+///
+/// A pixel takes the most frequent class around it only if that class is strictly more frequent
+/// than its own, so boundaries are smoothed without eroding thin features wholesale. Nodata (0)
+/// pixels are left alone and do not vote.
+fn majority_filter(classes: &[u8], size: (usize, usize)) -> Vec<u8> {
+    let (width, height) = size;
+    let mut filtered = classes.to_vec();
+    filtered
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                if *pixel == 0 {
+                    continue;
+                }
+
+                let mut counts = [(0u8, 0u8); 9];
+                let mut distinct = 0;
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        let class = classes[ny * width + nx];
+                        if class == 0 {
+                            continue;
+                        }
+                        match counts[..distinct].iter_mut().find(|(c, _)| *c == class) {
+                            Some((_, count)) => *count += 1,
+                            None => {
+                                counts[distinct] = (class, 1);
+                                distinct += 1;
+                            }
+                        }
+                    }
+                }
+
+                let own = counts[..distinct]
+                    .iter()
+                    .find(|(c, _)| *c == *pixel)
+                    .map_or(0, |&(_, count)| count);
+                if let Some(&(class, count)) = counts[..distinct].iter().max_by_key(|(_, n)| *n) {
+                    if count > own {
+                        *pixel = class;
+                    }
+                }
+            }
+        });
+    filtered
+}
+
+/// Classifies the index block by block into an in-memory class grid, cleans it up and writes its
+/// polygons with their class, label and area.
+pub fn run(args: &ClassifyArgs) -> Result<()> {
+    let start = Instant::now();
+    let mut breaks = args.breaks.clone();
+    if let Some(labels) = &args.labels {
+        ensure!(
+            labels.len() == breaks.classes(),
+            "expected {} labels, one per class",
+            breaks.classes()
+        );
+        breaks.labels.clone_from(labels);
+    }
+
+    // As given, before any warping of `b`.
+    let inputs_given = match (&args.raster, &args.a) {
+        (Some(path), _) => vec![path.clone()],
+        (None, Some(a)) => {
+            let b = args
+                .b
+                .as_ref()
+                .or(Some(a).filter(|a| a.starts_with(stac::ITEM_PREFIX)))
+                .context("--b is required unless --a is a STAC Item")?;
+            stac::resolve_index_bands(&[a.clone(), b.clone()], args.index.bands())?.to_vec()
+        }
+        (None, None) => unreachable!("--a is required without --raster"),
+    };
+    let (source, inputs) = match &args.raster {
+        Some(path) => (IndexSource::open_raster(path)?.0, vec![path.clone()]),
+        None => {
+            let a = inputs_given[0].clone();
+            let mut b = inputs_given[1].clone();
+            let calibration = [args.sensor.calibration(&a)?, args.sensor.calibration(&b)?];
+            let (a_dataset, b_dataset) = (Dataset::open(&a)?, Dataset::open(&b)?);
+            if b_dataset.raster_size() != a_dataset.raster_size()
+                || b_dataset.geo_transform()? != a_dataset.geo_transform()?
+            {
+                // E.g. the 20 m B11 of MNDWI with the 10 m B03.
+                b = reproject::warp_to_grid(&b, &a_dataset, "classify_b")?;
+            }
+            (IndexSource::Computed(args.index, calibration), vec![a, b])
+        }
+    };
+    let reference = Dataset::open(&inputs[0])?;
+    let srs = reference.spatial_ref()?;
+
+    let cancel = progress::interrupt_token();
+    let block_reader = ParallelBlockReader::new(&inputs, args.io_threads, None)?
+        .on_progress(progress::bar())
+        .cancel_on(cancel.clone());
+    let window = block_reader.window;
+    let (width, height) = window.size;
+
+    // Bounded, so that at most a few blocks wait beside the class grid.
+    let (tx, rx) = flume::bounded(args.io_threads);
+    let dataset_indices = (0..inputs.len()).collect::<Vec<_>>();
+    for y in block_reader.blocks.1.clone() {
+        for x in block_reader.blocks.0.clone() {
+            let tx = tx.clone();
+            block_reader.run(
+                x,
+                y,
+                &dataset_indices,
+                Box::new(move |x, y, blocks| {
+                    tx.send((x, y, blocks)).unwrap();
+                }),
+            );
+        }
+    }
+    drop(tx);
+
+    let mut classes = vec![0u8; width * height];
+    let mut values = Vec::new();
+    for (x, y, mut blocks) in rx {
+        let blocks = dataset_indices
+            .iter()
+            .map(|i| blocks.remove(i).unwrap())
+            .collect::<Vec<_>>();
+        source.values(&blocks, &mut values);
+
+        let region = block_reader.region(x, y);
+        let (x0, y0) = window.relative_offset(&region);
+        for (row, row_values) in values.chunks(region.size.0).enumerate() {
+            let row_start = (y0 as usize + row) * width + x0 as usize;
+            for (class, &value) in classes[row_start..row_start + region.size.0]
+                .iter_mut()
+                .zip(row_values)
+            {
+                *class = breaks.classify(value);
+            }
+        }
+    }
+
+    block_reader.join();
+    ensure!(
+        !cancel.is_cancelled(),
+        "interrupted, {} not written",
+        args.output
+    );
+
+    for _ in 0..args.majority {
+        classes = majority_filter(&classes, window.size);
+    }
+
+    let driver = DriverManager::get_driver_by_name("MEM")?;
+    let mut grid = driver.create_with_band_type::<u8, _>("", width, height, 1)?;
+    grid.set_projection(&reference.projection())?;
+    grid.set_geo_transform(&reference.geo_transform()?)?;
+    if args.raster.is_none() {
+        args.sensor.set_metadata(&mut grid, &inputs_given)?;
+    }
+    let mut band = grid.rasterband(1)?;
+    band.set_no_data_value(Some(0.0))?;
+    band.write((0, 0), window.size, &mut Buffer::new(window.size, classes))?;
+
+    let hectares = pixel_hectares(&reference)?;
+    if let Some(mmu) = args.mmu {
+        let hectares = hectares.context("--mmu needs a projected CRS")?;
+        band.sieve((mmu / hectares).ceil() as usize, true)?;
+    }
+    if !args.keep.is_empty() {
+        let (shape, mut data) = band.read_band_as::<u8>()?.into_shape_and_vec();
+        for class in &mut data {
+            if !args.keep.contains(class) {
+                *class = 0;
+            }
+        }
+        band.write((0, 0), shape, &mut Buffer::new(shape, data))?;
+    }
+
+    let mut names = vec!["nodata"];
+    names.extend(breaks.labels.iter().map(String::as_str));
+    band.set_category_names(&names)?;
+    let expression = match &args.raster {
+        Some(path) => format!("values of {path}"),
+        None => args.index.expression(),
+    };
+    let mut provenance = Provenance::new(&inputs_given, breaks.expression(&expression))
+        .with_calibration(&source.calibration());
+    if let Some(path) = &args.raster_output {
+        let driver = DriverManager::get_output_driver_for_dataset_name(path, DriverType::Raster)
+            .context("unknown class raster format")?;
+        let options = RasterCreationOptions::from_iter(["COMPRESS=DEFLATE", "TILED=YES"]);
+        let mut copy = grid.create_copy(&driver, vsi::writable_path(path), &options)?;
+        provenance = provenance.with_checksums(&args.checksums)?;
+        provenance.write(&mut copy)?;
+        copy.flush_cache()?;
+        let item = stac::raster_item(&copy, path, &provenance)?;
+        copy.close()?;
+        vsi::commit(&vsi::writable_path(path), path)?;
+        item.write()?;
+    }
+
+    let memory = DriverManager::get_driver_by_name("Memory")?;
+    let mut polygons = memory.create_vector_only("")?;
+    let polygon_layer = polygons.create_layer(LayerOptions {
+        name: "classes",
+        srs: Some(&srs),
+        ty: OGRwkbGeometryType::wkbPolygon,
+        ..Default::default()
+    })?;
+    polygon_layer.create_defn_fields(&[("class", OGRFieldType::OFTInteger)])?;
+    grid.rasterband(1)?.polygonize(&polygon_layer, 0, true)?;
+
+    let driver =
+        DriverManager::get_output_driver_for_dataset_name(&args.output, DriverType::Vector)
+            .context("unknown polygon output format")?;
+    let mut output = driver.create_vector_only(vsi::writable_path(&args.output))?;
+    let layer = output.create_layer(LayerOptions {
+        name: "classes",
+        srs: Some(&srs),
+        ty: OGRwkbGeometryType::wkbPolygon,
+        ..Default::default()
+    })?;
+    layer.create_defn_fields(&[
+        ("class", OGRFieldType::OFTInteger),
+        ("label", OGRFieldType::OFTString),
+        ("area_ha", OGRFieldType::OFTReal),
+    ])?;
+
+    let metres = srs.linear_units();
+    let mut count = 0;
+    let mut polygon_layer = polygons.layer(0)?;
+    for polygon in polygon_layer.features() {
+        let class = polygon.field_as_integer(0)?.unwrap_or(0);
+        let Some(geometry) = polygon.geometry() else {
+            continue;
+        };
+
+        let mut feature = Feature::new(layer.defn())?;
+        feature.set_field_integer("class", class)?;
+        if let Some(label) = breaks.labels.get((class - 1) as usize) {
+            feature.set_field_string("label", label)?;
+        }
+        if hectares.is_some() {
+            feature.set_field_double("area_ha", geometry.area() * metres * metres / 10_000.0)?;
+        }
+        feature.set_geometry(geometry.clone())?;
+        feature.create(&layer)?;
+        count += 1;
+    }
+    let item = stac::vector_item(&output, &args.output, &provenance)?;
+    output.close()?;
+    vsi::commit(&vsi::writable_path(&args.output), &args.output)?;
+    item.write()?;
+
+    println!(
+        "{count} polygons written to {} in {:.3}s",
+        args.output,
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use gdal::spatial_ref::SpatialRef;
+
+    use super::*;
+
+    #[test]
+    fn values_on_a_threshold_fall_in_the_class_above() {
+        let breaks = "0,0.5".parse::<Breaks>().unwrap();
+        assert_eq!(breaks.classes(), 3);
+        let classes = [-0.1, 0.0, 0.49, 0.5, 0.9, f32::NAN].map(|v| breaks.classify(v));
+        assert_eq!(classes, [1, 2, 2, 3, 3, 0]);
+        assert_eq!(breaks.labels, ["< 0", "0 to 0.5", ">= 0.5"]);
+    }
+
+    #[test]
+    fn usgs_dnbr_preset() {
+        let breaks = "USGS-dNBR".parse::<Breaks>().unwrap();
+        assert_eq!(breaks.classes(), 7);
+        assert_eq!(breaks.labels[2], "unburned");
+        assert_eq!(breaks.labels[6], "high severity");
+        let classes = [-0.3, -0.25, 0.0, 0.1, 0.27, 0.44, 0.66, 1.2].map(|v| breaks.classify(v));
+        assert_eq!(classes, [1, 2, 3, 4, 5, 6, 7, 7]);
+    }
+
+    #[test]
+    fn malformed_breaks_are_rejected() {
+        for breaks in ["", "low", "0.3,0.1", "0.1,0.1", "0.1,,0.2"] {
+            assert!(breaks.parse::<Breaks>().is_err(), "{breaks:?}");
+        }
+        let too_many = (0..255).map(|t| t.to_string()).collect::<Vec<_>>();
+        assert!(too_many.join(",").parse::<Breaks>().is_err());
+        assert!(too_many[..254].join(",").parse::<Breaks>().is_ok());
+        assert_eq!(
+            " -0.1 , 0.2".parse::<Breaks>().unwrap().thresholds,
+            [-0.1, 0.2]
+        );
+    }
+
+    #[test]
+    fn majority_filter_at_the_borders() {
+        #[rustfmt::skip]
+        let classes = [
+            2, 1, 1, 1,
+            1, 1, 1, 3,
+            1, 1, 3, 3,
+            0, 1, 3, 3,
+        ];
+        #[rustfmt::skip]
+        let expected = [
+            // The corner only has 3 neighbours, which outvote it.
+            1, 1, 1, 1,
+            // On the right edge, 3 against 3: pixels keep their class on ties.
+            1, 1, 1, 3,
+            1, 1, 3, 3,
+            0, 1, 3, 3,
+        ];
+        assert_eq!(majority_filter(&classes, (4, 4)), expected);
+    }
+
+    #[test]
+    fn nodata_does_not_vote() {
+        let row = [0, 0, 4, 0, 0];
+        assert_eq!(majority_filter(&row, (5, 1)), row);
+        let column = [5, 5, 0, 2];
+        assert_eq!(majority_filter(&column, (1, 4)), [5, 5, 0, 2]);
+    }
+
+    #[test]
+    fn pixel_area_in_hectares() {
+        let driver = DriverManager::get_driver_by_name("MEM").unwrap();
+        let mut dataset = driver.create_with_band_type::<u8, _>("", 1, 1, 1).unwrap();
+        dataset
+            .set_spatial_ref(&SpatialRef::from_epsg(32633).unwrap())
+            .unwrap();
+        dataset
+            .set_geo_transform(&[500_000.0, 20.0, 0.0, 5_000_000.0, 0.0, -20.0])
+            .unwrap();
+        assert_eq!(pixel_hectares(&dataset).unwrap(), Some(0.04));
+
+        dataset
+            .set_spatial_ref(&SpatialRef::from_epsg(4326).unwrap())
+            .unwrap();
+        assert_eq!(pixel_hectares(&dataset).unwrap(), None);
+    }
+}
@@ -0,0 +1,212 @@
+use clap::ValueEnum;
+use rayon::{
+    iter::{IndexedParallelIterator as _, ParallelIterator as _},
+    slice::ParallelSliceMut as _,
+};
+
+use crate::window::PixelWindow;
+
+/// Statistic computed over the square neighbourhood of each pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FocalOp {
+    Mean,
+    Median,
+    #[value(name = "stddev")]
+    StdDev,
+    Min,
+    Max,
+}
+
+impl FocalOp {
+    /// Computes the statistic of the valid values of a neighbourhood.
+    fn reduce(self, values: &mut [f32]) -> f32 {
+        if values.is_empty() {
+            return f32::NAN;
+        }
+        let n = values.len() as f64;
+        match self {
+            Self::Mean => (values.iter().map(|&v| v as f64).sum::<f64>() / n) as f32,
+            Self::Median => {
+                values.sort_unstable_by(f32::total_cmp);
+                let mid = values.len() / 2;
+                if values.len().is_multiple_of(2) {
+                    (values[mid - 1] + values[mid]) / 2.0
+                } else {
+                    values[mid]
+                }
+            }
+            Self::StdDev => {
+                let mean = values.iter().map(|&v| v as f64).sum::<f64>() / n;
+                let var = values
+                    .iter()
+                    .map(|&v| (v as f64 - mean) * (v as f64 - mean))
+                    .sum::<f64>()
+                    / n;
+                var.sqrt() as f32
+            }
+            Self::Min => values.iter().copied().fold(f32::INFINITY, f32::min),
+            Self::Max => values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        }
+    }
+
+    /// Applies the operation over `size`x`size` neighbourhoods to the pixels of `output` (a
+    /// block), reading from `values` covering `input` (the block with its halo).
+    ///
+    /// Each pixel only depends on the raster pixels of its neighbourhood, visited in the same
+    /// order wherever the block boundaries are, so results at block seams are identical to a
+    /// whole-image computation. Neighbourhoods are truncated at the raster edges, NaN (nodata)
+    /// neighbours are ignored and nodata pixels stay nodata.
+    pub fn apply(
+        self,
+        size: usize,
+        input: PixelWindow,
+        values: &[f32],
+        output: PixelWindow,
+        result: &mut Vec<f32>,
+    ) {
+        let radius = size / 2;
+        let (dx, dy) = (
+            output.offset.0 - input.offset.0,
+            output.offset.1 - input.offset.1,
+        );
+
+        result.clear();
+        result.resize(output.size.0 * output.size.1, f32::NAN);
+        result
+            .par_chunks_mut(output.size.0)
+            .enumerate()
+            .for_each(|(row, out_row)| {
+                let mut neighbourhood = Vec::with_capacity(size * size);
+                let y = dy + row;
+                let (y0, y1) = (y.saturating_sub(radius), (y + radius + 1).min(input.size.1));
+                for (col, out) in out_row.iter_mut().enumerate() {
+                    let x = dx + col;
+                    if values[y * input.size.0 + x].is_nan() {
+                        continue;
+                    }
+                    let (x0, x1) = (x.saturating_sub(radius), (x + radius + 1).min(input.size.0));
+
+                    neighbourhood.clear();
+                    for ny in y0..y1 {
+                        neighbourhood.extend(
+                            values[ny * input.size.0 + x0..ny * input.size.0 + x1]
+                                .iter()
+                                .filter(|v| !v.is_nan()),
+                        );
+                    }
+                    *out = self.reduce(&mut neighbourhood);
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPS: [FocalOp; 5] = [
+        FocalOp::Mean,
+        FocalOp::Median,
+        FocalOp::StdDev,
+        FocalOp::Min,
+        FocalOp::Max,
+    ];
+
+    /// Raster of pseudo-random values with a nodata pixel every 7 pixels.
+    fn raster(size: (usize, usize)) -> Vec<f32> {
+        let mut state = 12345u32;
+        (0..size.0 * size.1)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                if i % 7 == 3 {
+                    f32::NAN
+                } else {
+                    (state >> 16) as f32 / 65536.0 * 2.0 - 1.0
+                }
+            })
+            .collect()
+    }
+
+    fn read(values: &[f32], raster_width: usize, window: PixelWindow) -> Vec<f32> {
+        (0..window.size.1)
+            .flat_map(|row| {
+                let start = (window.offset.1 + row) * raster_width + window.offset.0;
+                values[start..start + window.size.0].iter().copied()
+            })
+            .collect()
+    }
+
+    fn assert_same(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (i, (a, b)) in a.iter().zip(b).enumerate() {
+            assert!(a.to_bits() == b.to_bits(), "pixel {i}: {a} != {b}");
+        }
+    }
+
+    #[test]
+    fn blocks_with_halo_match_a_whole_raster_pass() {
+        let raster_size = (37, 23);
+        let values = raster(raster_size);
+        let full = PixelWindow::full(raster_size);
+        // A window away from the raster edges on two sides, so halos reach outside it.
+        let windows = [
+            full,
+            PixelWindow {
+                offset: (3, 2),
+                size: (30, 21),
+            },
+        ];
+
+        for op in OPS {
+            for size in [3, 5] {
+                let mut whole = Vec::new();
+                op.apply(size, full, &values, full, &mut whole);
+
+                for window in windows {
+                    let (xs, ys) = window.blocks((8, 6));
+                    for by in ys {
+                        for bx in xs.clone() {
+                            let region = window.block((8, 6), (bx, by));
+                            let input = region.grow(size / 2, raster_size);
+                            let mut block = Vec::new();
+                            op.apply(
+                                size,
+                                input,
+                                &read(&values, raster_size.0, input),
+                                region,
+                                &mut block,
+                            );
+                            assert_same(&block, &read(&whole, raster_size.0, region));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn neighbourhoods_are_truncated_at_the_edges_and_skip_nodata() {
+        #[rustfmt::skip]
+        let values = [
+            1.0, 2.0, 3.0,
+            4.0, f32::NAN, 6.0,
+            7.0, 8.0, 9.0,
+        ];
+        let window = PixelWindow::full((3, 3));
+        let apply = |op: FocalOp| {
+            let mut result = Vec::new();
+            op.apply(3, window, &values, window, &mut result);
+            result
+        };
+
+        let mean = apply(FocalOp::Mean);
+        assert_eq!(mean[0], (1.0 + 2.0 + 4.0) / 3.0);
+        assert_eq!(mean[8], (6.0 + 8.0 + 9.0) / 3.0);
+        assert!(mean[4].is_nan());
+        assert_eq!(apply(FocalOp::Median)[1], 3.0);
+        assert_eq!(apply(FocalOp::Median)[6], 7.0);
+        assert_eq!(apply(FocalOp::Min)[5], 2.0);
+        assert_eq!(apply(FocalOp::Max)[3], 8.0);
+        assert_eq!(apply(FocalOp::StdDev)[0], (14.0f64.sqrt() / 3.0) as f32);
+    }
+}
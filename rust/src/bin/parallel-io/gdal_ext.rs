@@ -1,9 +1,20 @@
 #![allow(dead_code)]
 
+use std::{
+    ffi::{CStr, CString},
+    marker::PhantomData,
+    ptr,
+};
+
 use gdal::{
-    errors,
+    cpl::CslStringList,
+    errors::{self, GdalError},
     raster::{Buffer, GdalDataType, RasterBand},
+    vector::LayerAccess,
+    Dataset,
 };
+use gdal_sys::CPLErr;
+use libc::{c_char, c_int};
 
 #[derive(Debug)]
 pub enum TypedBuffer {
@@ -270,6 +281,23 @@ impl TypedBuffer {
         }
     }
 
+    /// Converts the values to `f64`, regardless of the buffer type.
+    #[must_use]
+    pub fn to_f64(&self) -> Vec<f64> {
+        match self {
+            TypedBuffer::U8(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::I8(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::U16(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::I16(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::U32(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::I32(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::U64(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::I64(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::F32(buf) => buf.data().iter().map(|&v| v as f64).collect(),
+            TypedBuffer::F64(buf) => buf.data().to_vec(),
+        }
+    }
+
     #[must_use]
     pub fn shape(&self) -> (usize, usize) {
         match self {
@@ -285,10 +313,68 @@ impl TypedBuffer {
             TypedBuffer::F64(buf) => buf.shape(),
         }
     }
+
+    /// Size of the values in bytes.
+    #[must_use]
+    pub fn byte_len(&self) -> usize {
+        let (width, height) = self.shape();
+        let value_size = match self {
+            TypedBuffer::U8(_) | TypedBuffer::I8(_) => 1,
+            TypedBuffer::U16(_) | TypedBuffer::I16(_) => 2,
+            TypedBuffer::U32(_) | TypedBuffer::I32(_) | TypedBuffer::F32(_) => 4,
+            TypedBuffer::U64(_) | TypedBuffer::I64(_) | TypedBuffer::F64(_) => 8,
+        };
+        width * height * value_size
+    }
 }
 
 pub trait RasterBandExt {
     fn read_typed_block(&self, x: usize, y: usize) -> errors::Result<TypedBuffer>;
+
+    fn read_typed(
+        &self,
+        window: (usize, usize),
+        window_size: (usize, usize),
+    ) -> errors::Result<TypedBuffer>;
+
+    fn write_typed(
+        &mut self,
+        window: (isize, isize),
+        buffer: &mut TypedBuffer,
+    ) -> errors::Result<()>;
+
+    fn set_statistics(&mut self, min: f64, max: f64, mean: f64, std_dev: f64)
+        -> errors::Result<()>;
+
+    fn set_unit_type(&mut self, unit: &str) -> errors::Result<()>;
+
+    /// Sets the names of the classes stored in the band, `names[i]` naming pixel value `i`.
+    fn set_category_names(&mut self, names: &[&str]) -> errors::Result<()>;
+
+    /// Replaces connected regions of fewer than `threshold` pixels by the value of their largest
+    /// neighbouring region, in place. Nodata pixels are neither sieved nor merged into.
+    fn sieve(&mut self, threshold: usize, eight_connected: bool) -> errors::Result<()>;
+
+    /// Adds a polygon feature to `layer` for each connected region of equal valid pixels, storing
+    /// the pixel value in field `field`.
+    fn polygonize<L: LayerAccess>(
+        &self,
+        layer: &L,
+        field: usize,
+        eight_connected: bool,
+    ) -> errors::Result<()>;
+}
+
+fn last_cpl_err(class: CPLErr::Type) -> GdalError {
+    let (number, msg) = unsafe {
+        let number = gdal_sys::CPLGetLastErrorNo();
+        let msg = CStr::from_ptr(gdal_sys::CPLGetLastErrorMsg())
+            .to_string_lossy()
+            .into_owned();
+        gdal_sys::CPLErrorReset();
+        (number, msg)
+    };
+    GdalError::CplError { class, number, msg }
 }
 
 impl RasterBandExt for RasterBand<'_> {
@@ -337,4 +423,605 @@ impl RasterBandExt for RasterBand<'_> {
             }
         }
     }
+
+    fn read_typed(
+        &self,
+        window: (usize, usize),
+        window_size: (usize, usize),
+    ) -> errors::Result<TypedBuffer> {
+        let window = (window.0 as isize, window.1 as isize);
+        match self.band_type() {
+            GdalDataType::Unknown => unimplemented!(),
+            GdalDataType::UInt8 => Ok(TypedBuffer::U8(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::Int8 => Ok(TypedBuffer::I8(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::UInt16 => Ok(TypedBuffer::U16(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::Int16 => Ok(TypedBuffer::I16(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::UInt32 => Ok(TypedBuffer::U32(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::Int32 => Ok(TypedBuffer::I32(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::UInt64 => Ok(TypedBuffer::U64(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::Int64 => Ok(TypedBuffer::I64(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::Float32 => Ok(TypedBuffer::F32(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+            GdalDataType::Float64 => Ok(TypedBuffer::F64(self.read_as(
+                window,
+                window_size,
+                window_size,
+                None,
+            )?)),
+        }
+    }
+
+    fn write_typed(
+        &mut self,
+        window: (isize, isize),
+        buffer: &mut TypedBuffer,
+    ) -> errors::Result<()> {
+        let shape = buffer.shape();
+        match buffer {
+            TypedBuffer::U8(buf) => self.write(window, shape, buf),
+            TypedBuffer::I8(buf) => self.write(window, shape, buf),
+            TypedBuffer::U16(buf) => self.write(window, shape, buf),
+            TypedBuffer::I16(buf) => self.write(window, shape, buf),
+            TypedBuffer::U32(buf) => self.write(window, shape, buf),
+            TypedBuffer::I32(buf) => self.write(window, shape, buf),
+            TypedBuffer::U64(buf) => self.write(window, shape, buf),
+            TypedBuffer::I64(buf) => self.write(window, shape, buf),
+            TypedBuffer::F32(buf) => self.write(window, shape, buf),
+            TypedBuffer::F64(buf) => self.write(window, shape, buf),
+        }
+    }
+    fn set_statistics(
+        &mut self,
+        min: f64,
+        max: f64,
+        mean: f64,
+        std_dev: f64,
+    ) -> errors::Result<()> {
+        let rv = unsafe {
+            gdal_sys::GDALSetRasterStatistics(self.c_rasterband(), min, max, mean, std_dev)
+        };
+        if rv != CPLErr::CE_None {
+            return Err(last_cpl_err(rv));
+        }
+        Ok(())
+    }
+
+    fn set_unit_type(&mut self, unit: &str) -> errors::Result<()> {
+        let unit = CString::new(unit)?;
+        let rv = unsafe { gdal_sys::GDALSetRasterUnitType(self.c_rasterband(), unit.as_ptr()) };
+        if rv != CPLErr::CE_None {
+            return Err(last_cpl_err(rv));
+        }
+        Ok(())
+    }
+
+    fn sieve(&mut self, threshold: usize, eight_connected: bool) -> errors::Result<()> {
+        let rv = unsafe {
+            let band = self.c_rasterband();
+            gdal_sys::GDALSieveFilter(
+                band,
+                gdal_sys::GDALGetMaskBand(band),
+                band,
+                threshold as c_int,
+                if eight_connected { 8 } else { 4 },
+                ptr::null_mut(),
+                None,
+                ptr::null_mut(),
+            )
+        };
+        if rv != CPLErr::CE_None {
+            return Err(last_cpl_err(rv));
+        }
+        Ok(())
+    }
+
+    fn polygonize<L: LayerAccess>(
+        &self,
+        layer: &L,
+        field: usize,
+        eight_connected: bool,
+    ) -> errors::Result<()> {
+        let mut options = CslStringList::new();
+        if eight_connected {
+            options.set_name_value("8CONNECTED", "8")?;
+        }
+        let rv = unsafe {
+            let band = self.c_rasterband();
+            gdal_sys::GDALPolygonize(
+                band,
+                gdal_sys::GDALGetMaskBand(band),
+                layer.c_layer(),
+                field as c_int,
+                options.as_ptr(),
+                None,
+                ptr::null_mut(),
+            )
+        };
+        if rv != CPLErr::CE_None {
+            return Err(last_cpl_err(rv));
+        }
+        Ok(())
+    }
+
+    fn set_category_names(&mut self, names: &[&str]) -> errors::Result<()> {
+        let names = names.iter().copied().collect::<CslStringList>();
+        let rv =
+            unsafe { gdal_sys::GDALSetRasterCategoryNames(self.c_rasterband(), names.as_ptr()) };
+        if rv != CPLErr::CE_None {
+            return Err(last_cpl_err(rv));
+        }
+        Ok(())
+    }
+}
+
+/// Runs `gdalwarp` with command line `args` on `sources`, writing to `dest`.
+///
+/// With `-of VRT` the result is a virtual warped dataset, so pixels are only resampled when
+/// blocks of it are read.
+pub fn warp(sources: &[&Dataset], dest: &str, args: &[String]) -> errors::Result<Dataset> {
+    let dest = CString::new(dest)?;
+    let args = args
+        .iter()
+        .map(|arg| CString::new(arg.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut c_args = args
+        .iter()
+        .map(|arg| arg.as_ptr() as *mut c_char)
+        .chain(std::iter::once(ptr::null_mut()))
+        .collect::<Vec<_>>();
+    let mut c_sources = sources
+        .iter()
+        .map(|dataset| dataset.c_dataset())
+        .collect::<Vec<_>>();
+
+    unsafe {
+        let options = gdal_sys::GDALWarpAppOptionsNew(c_args.as_mut_ptr(), ptr::null_mut());
+        if options.is_null() {
+            return Err(last_cpl_err(CPLErr::CE_Failure));
+        }
+        let mut usage_error = 0;
+        let dataset = gdal_sys::GDALWarp(
+            dest.as_ptr(),
+            ptr::null_mut(),
+            c_sources.len() as c_int,
+            c_sources.as_mut_ptr(),
+            options,
+            &mut usage_error,
+        );
+        gdal_sys::GDALWarpAppOptionsFree(options);
+        if dataset.is_null() {
+            return Err(last_cpl_err(CPLErr::CE_Failure));
+        }
+        Ok(Dataset::from_c_dataset(dataset))
+    }
+}
+
+/// Deletes the closed dataset at `path` with all its files, as `gdalmanage delete` does, or the
+/// directory tree of stores such as Zarr that drivers do not delete.
+pub fn delete_dataset(path: &str) -> errors::Result<()> {
+    let c_path = CString::new(path)?;
+    let rv = unsafe { gdal_sys::GDALDeleteDataset(ptr::null_mut(), c_path.as_ptr()) };
+    if rv == CPLErr::CE_None {
+        return Ok(());
+    }
+    let err = last_cpl_err(rv);
+    if unsafe { gdal_sys::VSIRmdirRecursive(c_path.as_ptr()) } == 0 {
+        return Ok(());
+    }
+    Err(err)
+}
+
+/// XML document parsed by GDAL's `minixml`, with namespace prefixes stripped.
+pub struct XmlDocument(*mut gdal_sys::CPLXMLNode);
+
+impl XmlDocument {
+    pub fn open(path: &str) -> errors::Result<Self> {
+        let c_path = CString::new(path)?;
+        let root = unsafe { gdal_sys::CPLParseXMLFile(c_path.as_ptr()) };
+        if root.is_null() {
+            return Err(last_cpl_err(CPLErr::CE_Failure));
+        }
+        unsafe { gdal_sys::CPLStripXMLNamespace(root, ptr::null(), 1) };
+        Ok(Self(root))
+    }
+
+    /// First element named `name`, searching every top-level node (the root element follows the
+    /// `<?xml ...?>` declaration).
+    fn find(&self, name: &str) -> Option<*mut gdal_sys::CPLXMLNode> {
+        let c_name = CString::new(name).ok()?;
+        let mut node = self.0;
+        while !node.is_null() {
+            let found = unsafe { gdal_sys::CPLSearchXMLNode(node, c_name.as_ptr()) };
+            if !found.is_null() {
+                return Some(found);
+            }
+            node = unsafe { (*node).psNext };
+        }
+        None
+    }
+
+    /// Text of `node`, or of its attribute or child element `path` if not empty.
+    fn value(node: *const gdal_sys::CPLXMLNode, path: &str) -> Option<String> {
+        let c_path = CString::new(path).ok()?;
+        let value = unsafe { gdal_sys::CPLGetXMLValue(node, c_path.as_ptr(), ptr::null()) };
+        (!value.is_null()).then(|| {
+            unsafe { CStr::from_ptr(value) }
+                .to_string_lossy()
+                .trim()
+                .to_owned()
+        })
+    }
+
+    /// Text of the first element named `path`, anywhere in the document, or of a descendant of
+    /// it with a dotted path such as `Mean_Sun_Angle.ZENITH_ANGLE`.
+    #[must_use]
+    pub fn find_value(&self, path: &str) -> Option<String> {
+        let (name, rest) = path.split_once('.').unwrap_or((path, ""));
+        Self::value(self.find(name)?, rest)
+    }
+
+    /// Texts of the child elements of the first element named `list`, with the value of their
+    /// attribute `key`, e.g. the `<RADIO_ADD_OFFSET band_id="3">` of a `Radiometric_Offset_List`.
+    #[must_use]
+    pub fn list_values(&self, list: &str, key: &str) -> Vec<(String, String)> {
+        let Some(list) = self.find(list) else {
+            return Vec::new();
+        };
+        let mut values = Vec::new();
+        let mut child = unsafe { (*list).psChild };
+        while !child.is_null() {
+            if unsafe { (*child).eType } == gdal_sys::CPLXMLNodeType::CXT_Element {
+                if let (Some(key), Some(value)) = (Self::value(child, key), Self::value(child, ""))
+                {
+                    values.push((key, value));
+                }
+            }
+            child = unsafe { (*child).psNext };
+        }
+        values
+    }
+}
+
+impl Drop for XmlDocument {
+    fn drop(&mut self) {
+        unsafe { gdal_sys::CPLDestroyXMLNode(self.0) };
+    }
+}
+
+/// File opened through GDAL's virtual file systems, so that `/vsimem/`, `/vsizip/`, `/vsis3/`
+/// and other GDAL paths read like local files.
+pub struct VsiFile(*mut gdal_sys::VSILFILE);
+
+impl VsiFile {
+    pub fn open(path: &str) -> errors::Result<Self> {
+        Self::open_with_mode(path, c"rb")
+    }
+
+    /// Creates or truncates `path` for writing. Object stores, archives and `/vsistdout/` only
+    /// take sequential writes.
+    pub fn create(path: &str) -> errors::Result<Self> {
+        Self::open_with_mode(path, c"wb")
+    }
+
+    fn open_with_mode(path: &str, mode: &CStr) -> errors::Result<Self> {
+        let c_path = CString::new(path)?;
+        let file = unsafe { gdal_sys::VSIFOpenL(c_path.as_ptr(), mode.as_ptr()) };
+        if file.is_null() {
+            return Err(last_cpl_err(CPLErr::CE_Failure));
+        }
+        Ok(Self(file))
+    }
+
+    /// Closes the file, reporting errors that dropping it would ignore, such as a failed upload
+    /// to an object store.
+    pub fn close(self) -> errors::Result<()> {
+        let rv = unsafe { gdal_sys::VSIFCloseL(self.0) };
+        std::mem::forget(self);
+        if rv != 0 {
+            return Err(last_cpl_err(CPLErr::CE_Failure));
+        }
+        Ok(())
+    }
+}
+
+/// `VSI_STAT_EXISTS_FLAG` of `cpl_vsi.h`, a macro that bindgen does not export.
+const VSI_STAT_EXISTS_FLAG: c_int = 0x1;
+
+/// Whether `path` exists, on the local file system or a GDAL virtual one.
+#[must_use]
+pub fn vsi_exists(path: &str) -> bool {
+    let Ok(c_path) = CString::new(path) else {
+        return false;
+    };
+    let mut stat = unsafe { std::mem::zeroed::<gdal_sys::VSIStatBufL>() };
+    unsafe { gdal_sys::VSIStatExL(c_path.as_ptr(), &mut stat, VSI_STAT_EXISTS_FLAG) == 0 }
+}
+
+impl std::io::Read for VsiFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(unsafe { gdal_sys::VSIFReadL(buf.as_mut_ptr().cast(), 1, buf.len(), self.0) })
+    }
+}
+
+impl std::io::Write for VsiFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = unsafe { gdal_sys::VSIFWriteL(buf.as_ptr().cast(), 1, buf.len(), self.0) };
+        if written == 0 && !buf.is_empty() {
+            return Err(std::io::Error::other(last_cpl_err(CPLErr::CE_Failure)));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if unsafe { gdal_sys::VSIFFlushL(self.0) } != 0 {
+            return Err(std::io::Error::other(last_cpl_err(CPLErr::CE_Failure)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VsiFile {
+    fn drop(&mut self) {
+        unsafe { gdal_sys::VSIFCloseL(self.0) };
+    }
+}
+
+/// Value of an attribute written through GDAL's multidimensional API.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Number(f64),
+    Numbers(Vec<f64>),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<f64> for AttributeValue {
+    fn from(value: f64) -> Self {
+        Self::Number(value)
+    }
+}
+
+impl From<Vec<f64>> for AttributeValue {
+    fn from(value: Vec<f64>) -> Self {
+        Self::Numbers(value)
+    }
+}
+
+/// Writes `value` to the attribute returned by `get`, or to a new one from `create` (given the
+/// dimension count, sizes and data type) if there is none yet.
+fn write_attribute(
+    get: impl FnOnce() -> gdal_sys::GDALAttributeH,
+    create: impl FnOnce(
+        usize,
+        *const gdal_sys::GUInt64,
+        gdal_sys::GDALExtendedDataTypeH,
+    ) -> gdal_sys::GDALAttributeH,
+    value: &AttributeValue,
+) -> errors::Result<()> {
+    let string = match value {
+        AttributeValue::String(string) => CString::new(string.as_str())?,
+        _ => CString::default(),
+    };
+    unsafe {
+        let mut attribute = get();
+        if attribute.is_null() {
+            let (data_type, sizes) = match value {
+                AttributeValue::String(_) => {
+                    (gdal_sys::GDALExtendedDataTypeCreateString(0), vec![])
+                }
+                AttributeValue::Number(_) => (
+                    gdal_sys::GDALExtendedDataTypeCreate(gdal_sys::GDALDataType::GDT_Float64),
+                    vec![],
+                ),
+                AttributeValue::Numbers(values) => (
+                    gdal_sys::GDALExtendedDataTypeCreate(gdal_sys::GDALDataType::GDT_Float64),
+                    vec![values.len() as gdal_sys::GUInt64],
+                ),
+            };
+            attribute = create(sizes.len(), sizes.as_ptr(), data_type);
+            gdal_sys::GDALExtendedDataTypeRelease(data_type);
+            if attribute.is_null() {
+                return Err(last_cpl_err(CPLErr::CE_Failure));
+            }
+        }
+        let written = match value {
+            AttributeValue::String(_) => {
+                gdal_sys::GDALAttributeWriteString(attribute, string.as_ptr())
+            }
+            AttributeValue::Number(number) => {
+                gdal_sys::GDALAttributeWriteDouble(attribute, *number)
+            }
+            AttributeValue::Numbers(values) => {
+                gdal_sys::GDALAttributeWriteDoubleArray(attribute, values.as_ptr(), values.len())
+            }
+        };
+        gdal_sys::GDALAttributeRelease(attribute);
+        if written == 0 {
+            return Err(last_cpl_err(CPLErr::CE_Failure));
+        }
+    }
+    Ok(())
+}
+
+/// Group of a dataset opened with `GDAL_OF_MULTIDIM_RASTER`, for metadata that the classic raster
+/// API does not reach, such as the attributes of the arrays of a Zarr store.
+pub struct MdGroup<'a>(gdal_sys::GDALGroupH, PhantomData<&'a Dataset>);
+
+impl<'a> MdGroup<'a> {
+    pub fn root(dataset: &'a Dataset) -> errors::Result<Self> {
+        let group = unsafe { gdal_sys::GDALDatasetGetRootGroup(dataset.c_dataset()) };
+        if group.is_null() {
+            return Err(last_cpl_err(CPLErr::CE_Failure));
+        }
+        Ok(Self(group, PhantomData))
+    }
+
+    /// Names of the arrays directly in this group.
+    #[must_use]
+    pub fn array_names(&self) -> Vec<String> {
+        let names = unsafe { gdal_sys::GDALGroupGetMDArrayNames(self.0, ptr::null_mut()) };
+        if names.is_null() {
+            return Vec::new();
+        }
+        let mut result = Vec::new();
+        for i in 0.. {
+            let name = unsafe { *names.add(i) };
+            if name.is_null() {
+                break;
+            }
+            result.push(
+                unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+        unsafe { gdal_sys::CSLDestroy(names) };
+        result
+    }
+
+    /// Array `name` of this group, if there is one.
+    #[must_use]
+    pub fn array(&self, name: &str) -> Option<MdArray<'a>> {
+        let c_name = CString::new(name).ok()?;
+        let array =
+            unsafe { gdal_sys::GDALGroupOpenMDArray(self.0, c_name.as_ptr(), ptr::null_mut()) };
+        (!array.is_null()).then_some(MdArray(array, PhantomData))
+    }
+
+    /// Creates an array without dimensions, such as a CF grid mapping variable.
+    pub fn create_scalar_array(&self, name: &str) -> errors::Result<MdArray<'a>> {
+        let c_name = CString::new(name)?;
+        let array = unsafe {
+            let data_type = gdal_sys::GDALExtendedDataTypeCreate(gdal_sys::GDALDataType::GDT_Int32);
+            let array = gdal_sys::GDALGroupCreateMDArray(
+                self.0,
+                c_name.as_ptr(),
+                0,
+                ptr::null_mut(),
+                data_type,
+                ptr::null_mut(),
+            );
+            gdal_sys::GDALExtendedDataTypeRelease(data_type);
+            array
+        };
+        if array.is_null() {
+            return Err(last_cpl_err(CPLErr::CE_Failure));
+        }
+        Ok(MdArray(array, PhantomData))
+    }
+
+    /// Sets attribute `name` of this group, creating it if needed.
+    pub fn set_attribute(&self, name: &str, value: &AttributeValue) -> errors::Result<()> {
+        let c_name = CString::new(name)?;
+        write_attribute(
+            || unsafe { gdal_sys::GDALGroupGetAttribute(self.0, c_name.as_ptr()) },
+            |count, sizes, data_type| unsafe {
+                gdal_sys::GDALGroupCreateAttribute(
+                    self.0,
+                    c_name.as_ptr(),
+                    count,
+                    sizes,
+                    data_type,
+                    ptr::null_mut(),
+                )
+            },
+            value,
+        )
+    }
+}
+
+impl Drop for MdGroup<'_> {
+    fn drop(&mut self) {
+        unsafe { gdal_sys::GDALGroupRelease(self.0) };
+    }
+}
+
+/// Array of an [`MdGroup`].
+pub struct MdArray<'a>(gdal_sys::GDALMDArrayH, PhantomData<&'a Dataset>);
+
+impl MdArray<'_> {
+    #[must_use]
+    pub fn dimension_count(&self) -> usize {
+        unsafe { gdal_sys::GDALMDArrayGetDimensionCount(self.0) }
+    }
+
+    /// Sets attribute `name` of this array, creating it if needed.
+    pub fn set_attribute(&self, name: &str, value: &AttributeValue) -> errors::Result<()> {
+        let c_name = CString::new(name)?;
+        write_attribute(
+            || unsafe { gdal_sys::GDALMDArrayGetAttribute(self.0, c_name.as_ptr()) },
+            |count, sizes, data_type| unsafe {
+                gdal_sys::GDALMDArrayCreateAttribute(
+                    self.0,
+                    c_name.as_ptr(),
+                    count,
+                    sizes,
+                    data_type,
+                    ptr::null_mut(),
+                )
+            },
+            value,
+        )
+    }
+}
+
+impl Drop for MdArray<'_> {
+    fn drop(&mut self) {
+        unsafe { gdal_sys::GDALMDArrayRelease(self.0) };
+    }
 }
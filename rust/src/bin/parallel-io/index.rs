@@ -0,0 +1,176 @@
+use anyhow::{ensure, Result};
+use clap::ValueEnum;
+use gdal::Dataset;
+
+use crate::{
+    gdal_ext::TypedBuffer,
+    sensor::{Band, Calibration},
+};
+
+/// Normalised difference index `(a - b) / (a + b)` of two reflectance bands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Index {
+    /// Normalized Difference Vegetation Index, from B08 and B04
+    Ndvi,
+    /// Normalized Burn Ratio, from B8A and B12
+    Nbr,
+    /// Normalized Difference Water Index (McFeeters), from B03 and B08
+    Ndwi,
+    /// Modified Normalized Difference Water Index, from B03 and B11
+    Mndwi,
+}
+
+impl Index {
+    /// Short name, as used for band descriptions and units.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ndvi => "NDVI",
+            Self::Nbr => "NBR",
+            Self::Ndwi => "NDWI",
+            Self::Mndwi => "MNDWI",
+        }
+    }
+
+    /// The two bands of the index, `a` and `b`.
+    #[must_use]
+    pub fn bands(self) -> [Band; 2] {
+        match self {
+            Self::Ndvi => [Band::Nir, Band::Red],
+            Self::Nbr => [Band::Nir08, Band::Swir22],
+            Self::Ndwi => [Band::Green, Band::Nir],
+            Self::Mndwi => [Band::Green, Band::Swir16],
+        }
+    }
+
+    /// Formula of the index over the common names of its bands, e.g. `(nir - red) / (nir + red)`.
+    #[must_use]
+    pub fn expression(self) -> String {
+        let [a, b] = self.bands().map(Band::common_name);
+        format!("({a} - {b}) / ({a} + {b})")
+    }
+
+    /// Computes the index from digital numbers of its two bands, converted to reflectance with
+    /// `calibration`, into `values`.
+    pub fn compute(
+        self,
+        a: &[u16],
+        b: &[u16],
+        calibration: [Calibration; 2],
+        values: &mut Vec<f32>,
+    ) {
+        normalized_difference(a, b, calibration, values);
+    }
+}
+
+/// Computes `(a - b) / (a + b)` from the reflectances of two bands of digital numbers into
+/// `values`, with NaN marking nodata.
+pub fn normalized_difference(
+    a: &[u16],
+    b: &[u16],
+    calibration: [Calibration; 2],
+    values: &mut Vec<f32>,
+) {
+    let [(a_scale, a_offset, a_nodata), (b_scale, b_offset, b_nodata)] =
+        calibration.map(|c| (c.scale as f32, c.offset as f32, c.nodata.map(|n| n as u16)));
+    values.clear();
+    values.extend(a.iter().zip(b).map(|(&a, &b)| {
+        if Some(a) == a_nodata || Some(b) == b_nodata {
+            return f32::NAN;
+        }
+        let a = a as f32 * a_scale + a_offset;
+        let b = b as f32 * b_scale + b_offset;
+
+        if a + b > 0.0 {
+            ((a - b) / (a + b)).clamp(-1.0, 1.0)
+        } else {
+            f32::NAN
+        }
+    }));
+}
+
+/// Where index values come from.
+pub enum IndexSource {
+    /// Index computed from the digital numbers of its two bands.
+    Computed(Index, [Calibration; 2]),
+    /// Decoded codes of a previous output.
+    Raster {
+        scale: f64,
+        offset: f64,
+        nodata: Option<f64>,
+    },
+}
+
+impl IndexSource {
+    /// Computes the index values of a block from the blocks read for it: the two bands of a
+    /// computed index, or the single band of a raster.
+    pub fn values(&self, blocks: &[TypedBuffer], values: &mut Vec<f32>) {
+        match self {
+            Self::Computed(index, calibration) => {
+                let a = blocks[0].as_u16().unwrap();
+                let b = blocks[1].as_u16().unwrap();
+                index.compute(a.data(), b.data(), *calibration, values);
+            }
+            Self::Raster {
+                scale,
+                offset,
+                nodata,
+            } => {
+                values.clear();
+                values.extend(blocks[0].to_f64().into_iter().map(|code| {
+                    if Some(code) == *nodata || code.is_nan() {
+                        f32::NAN
+                    } else {
+                        (code * scale + offset) as f32
+                    }
+                }));
+            }
+        }
+    }
+
+    /// Conversion of each input to the values the index is computed from.
+    #[must_use]
+    pub fn calibration(&self) -> Vec<Calibration> {
+        match *self {
+            Self::Computed(_, calibration) => calibration.to_vec(),
+            Self::Raster {
+                scale,
+                offset,
+                nodata,
+            } => vec![Calibration {
+                scale,
+                offset,
+                nodata,
+            }],
+        }
+    }
+
+    /// Opens an index raster written by a previous run and returns how to decode it and the
+    /// range of its values.
+    pub fn open_raster(path: &str) -> Result<(Self, (f64, f64))> {
+        let dataset = Dataset::open(path)?;
+        let band = dataset.rasterband(1)?;
+        let scale = band.scale().unwrap_or(1.0);
+        let offset = band.offset().unwrap_or(0.0);
+
+        let (min, max) = match band.get_statistics(false, true)? {
+            Some(stats) => (stats.min, stats.max),
+            None => {
+                let min_max = band.compute_raster_min_max(true)?;
+                (min_max.min, min_max.max)
+            }
+        };
+        let (lo, hi) = (min * scale + offset, max * scale + offset);
+        let range = (lo.min(hi), lo.max(hi));
+        ensure!(range.1 > range.0, "{path} has a constant value");
+
+        Ok((
+            Self::Raster {
+                scale,
+                offset,
+                nodata: band.no_data_value(),
+            },
+            range,
+        ))
+    }
+}
@@ -1,243 +1,455 @@
-use std::{
-    collections::HashMap,
-    mem,
-    num::NonZero,
-    ops::DerefMut,
-    panic,
-    sync::Arc,
-    thread::{self, JoinHandle},
-};
-
-use anyhow::Result;
-use flume::{Receiver, Sender};
-use gdal::{
-    raster::{Buffer, RasterCreationOptions},
-    Dataset, DriverManager, DriverType,
-};
-use itertools::izip;
-use parking_lot::Mutex;
-use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator as _, ParallelIterator as _};
-
-use gdal_ext::TypedBuffer;
-
+use std::{mem, num::NonZero, path::Path};
+
+use anyhow::{bail, ensure, Context as _, Result};
+use clap::{Parser, Subcommand, ValueEnum as _};
+use gdal::Dataset;
+
+use block_reader::ParallelBlockReader;
+use focal::FocalOp;
+use gdal_ext::RasterBandExt as _;
+use mosaic::OverlapRule;
+use output_format::OutputFormatArgs;
+use output_type::{Encoding, OutputType};
+use polygons::PolygonLayer;
+use provenance::{ChecksumArgs, Provenance};
+use render::{ColorRamp, RenderMode, Renderer};
+use reproject::{Resampling, WarpOptions};
+use sensor::{Band, SensorArgs};
+use stats::{BandInfo, Histogram, Statistics};
+use window::PixelWindow;
+
+mod block_reader;
+mod change;
+mod classify;
+mod focal;
 mod gdal_ext;
-
-type BlockReadHandler = Box<dyn Fn(usize, usize, HashMap<usize, TypedBuffer>) + Send + Sync>;
-
-struct BlockReadRequest {
-    datasets: Arc<Vec<Box<[Arc<Mutex<Dataset>>]>>>,
-    num_datasets: usize,
-    dataset_idx: usize,
-    x: usize,
-    y: usize,
-    state: BlockReadState,
-    handler: Arc<BlockReadHandler>,
+mod index;
+mod mosaic;
+mod output_format;
+mod output_type;
+mod polygons;
+mod preview;
+mod progress;
+mod provenance;
+mod render;
+mod reproject;
+mod sample;
+mod sensor;
+mod stac;
+mod stats;
+mod temporal;
+mod transform;
+mod vsi;
+mod window;
+mod zonal;
+
+/// Computes NDVI from Sentinel-2 bands using parallel block reads.
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// NIR band (B08), or several tiles of it to mosaic; `item=<path>` takes the band of each
+    /// item of a STAC Item, ItemCollection or Catalog
+    #[arg(
+        long,
+        num_args = 1..,
+        default_value = "../data/T33TTG_20250305T100029_B08_10m.jp2"
+    )]
+    nir: Vec<String>,
+
+    /// Red band (B04), with tiles in the same order as `--nir`, or `item=<path>`
+    #[arg(
+        long,
+        num_args = 1..,
+        default_value = "../data/T33TTG_20250305T100029_B04_10m.jp2"
+    )]
+    red: Vec<String>,
+
+    /// How overlapping tiles are combined
+    #[arg(long, value_enum, default_value_t = OverlapRule::First)]
+    overlap: OverlapRule,
+
+    /// Output raster
+    #[arg(short, long, default_value = "../output/rust_parallel_io.tif")]
+    output: String,
+
+    /// Output data type
+    #[arg(long = "ot", value_enum, default_value_t = OutputType::Int16)]
+    output_type: OutputType,
+
+    #[command(flatten)]
+    format: OutputFormatArgs,
+
+    /// Scale written to the output band (`value = code * scale + offset`), picked from the
+    /// output type if not given
+    #[arg(long)]
+    scale: Option<f64>,
+
+    /// Offset written to the output band, picked from the output type if not given
+    #[arg(long, allow_hyphen_values = true)]
+    offset: Option<f64>,
+
+    /// Also store a histogram of the output with this many buckets
+    #[arg(long)]
+    histogram_buckets: Option<usize>,
+
+    /// Also render the output through a colour ramp: `rdylgn`, `viridis` or a colour file with
+    /// `value red green blue [alpha]` lines
+    #[arg(long, requires = "render_output")]
+    render: Option<String>,
+
+    /// Rendered image, a GeoTIFF or a PNG with a world file. PNG images are built in memory
+    /// before being written, taking 4 bytes per pixel in RGBA mode (about 480 MB for a full
+    /// 10980 x 10980 tile), while GeoTIFF is written block by block
+    #[arg(long, requires = "render")]
+    render_output: Option<String>,
+
+    /// Layout of the rendered image
+    #[arg(long, value_enum, default_value_t = RenderMode::Rgba)]
+    render_mode: RenderMode,
+
+    /// Only compute a quick-look at 1/N resolution from the JP2 resolution levels or overviews,
+    /// printing summary statistics and writing a small colour-ramped PNG
+    #[arg(long, value_name = "N")]
+    preview: Option<usize>,
+
+    /// Quick-look image, next to the output by default
+    #[arg(long, requires = "preview")]
+    preview_output: Option<String>,
+
+    /// Only process a pixel window of the inputs
+    #[arg(
+        long,
+        num_args = 4,
+        value_names = ["XOFF", "YOFF", "XSIZE", "YSIZE"],
+        allow_hyphen_values = true,
+        conflicts_with_all = ["projwin", "bbox"]
+    )]
+    srcwin: Option<Vec<isize>>,
+
+    /// Only process the window covering these coordinates in the CRS of the inputs
+    #[arg(
+        long,
+        num_args = 4,
+        value_names = ["ULX", "ULY", "LRX", "LRY"],
+        allow_hyphen_values = true,
+        conflicts_with = "bbox"
+    )]
+    projwin: Option<Vec<f64>>,
+
+    /// Only process the window covering this WGS84 longitude/latitude box
+    #[arg(
+        long,
+        num_args = 4,
+        value_names = ["WEST", "SOUTH", "EAST", "NORTH"],
+        allow_hyphen_values = true
+    )]
+    bbox: Option<Vec<f64>>,
+
+    /// Vector file (GeoJSON, Shapefile, GeoPackage, ...) with polygons outside of which the
+    /// output is set to nodata
+    #[arg(long)]
+    cutline: Option<String>,
+
+    /// Layer of the cutline file to use, the first one by default
+    #[arg(long, requires = "cutline")]
+    cutline_layer: Option<String>,
+
+    /// Crop the output to the extent of the cutline polygons
+    #[arg(long, requires = "cutline")]
+    crop_to_cutline: bool,
+
+    /// Warp the inputs to this CRS (e.g. `EPSG:3857`) before computing the index
+    #[arg(long = "t_srs", value_name = "SRS")]
+    t_srs: Option<String>,
+
+    /// Warp the inputs to this pixel size, in units of the target CRS
+    #[arg(long = "tr", num_args = 2, value_names = ["XRES", "YRES"])]
+    tr: Option<Vec<f64>>,
+
+    /// Resampling used when warping the inputs
+    #[arg(short = 'r', long, value_enum, default_value_t = Resampling::Near)]
+    resampling: Resampling,
+
+    /// Replace the index by a statistic over the neighbourhood of each pixel, e.g. to smooth it
+    /// or measure texture
+    #[arg(long, value_enum, conflicts_with = "preview")]
+    focal: Option<FocalOp>,
+
+    /// Width in pixels of the square `--focal` neighbourhood
+    #[arg(long, default_value_t = 3, requires = "focal")]
+    focal_size: usize,
+
+    #[command(flatten)]
+    sensor: SensorArgs,
+
+    #[command(flatten)]
+    checksums: ChecksumArgs,
+
+    /// Number of threads reading blocks
+    #[arg(long, default_value_t = 8)]
+    io_threads: usize,
 }
 
-#[derive(Clone)]
-struct BlockReadState {
-    blocks: Arc<Mutex<HashMap<usize, TypedBuffer>>>,
-    region_size: (usize, usize),
+#[derive(Subcommand)]
+enum Command {
+    /// Statistics of the index per polygon of a vector layer
+    Zonal(zonal::ZonalArgs),
+    /// Composite of the index over a time stack of aligned scenes
+    Composite(temporal::CompositeArgs),
+    /// Difference of an index between two dates, optionally classified (e.g. dNBR severity)
+    Change(change::ChangeArgs),
+    /// Classify an index with thresholds and polygonise the classes
+    ///
+    /// The whole class grid is held in memory, at 1 byte per pixel and twice that while it is
+    /// filtered or polygonised: about 240 MB for a full 10980 x 10980 tile.
+    Classify(classify::ClassifyArgs),
+    /// Time series of an index at sample points
+    Sample(sample::SampleArgs),
+    /// Tasselled cap, custom linear transform or principal components of a band stack
+    Transform(transform::TransformArgs),
+    /// Print the provenance recorded in an output
+    Provenance(provenance::ProvenanceArgs),
 }
 
-struct ParallelBlockReader {
-    datasets: Arc<Vec<Box<[Arc<Mutex<Dataset>>]>>>,
-    region_size: (usize, usize),
-    blocks: (usize, usize),
-    workers: Vec<JoinHandle<()>>,
-    req_tx: Sender<BlockReadRequest>,
+/// Value range of NDVI.
+const NDVI_RANGE: (f64, f64) = (-1.0, 1.0);
+
+/// Resolves the window selected on the command line against `dataset`.
+fn select_window(args: &Args, dataset: &Dataset) -> Result<PixelWindow> {
+    let raster_size = dataset.raster_size();
+    if let Some(srcwin) = &args.srcwin {
+        PixelWindow::from_srcwin([srcwin[0], srcwin[1], srcwin[2], srcwin[3]], raster_size)
+    } else if let Some(projwin) = &args.projwin {
+        PixelWindow::from_projwin(
+            &dataset.geo_transform()?,
+            [projwin[0], projwin[1], projwin[2], projwin[3]],
+            raster_size,
+        )
+    } else if let Some(bbox) = &args.bbox {
+        PixelWindow::from_lonlat_bbox(dataset, [bbox[0], bbox[1], bbox[2], bbox[3]])
+    } else {
+        Ok(PixelWindow::full(raster_size))
+    }
 }
 
-impl ParallelBlockReader {
-    pub fn new(paths: &[String], threads: usize) -> gdal::errors::Result<Self> {
-        let datasets = Arc::new(
-            (0..threads)
-                .into_par_iter()
-                .map(|_| -> gdal::errors::Result<Box<[Arc<Mutex<Dataset>>]>> {
-                    Ok(paths
-                        .par_iter()
-                        .map(|p| -> gdal::errors::Result<Arc<Mutex<Dataset>>> {
-                            Ok(Arc::new(Mutex::new(Dataset::open(p)?)))
-                        })
-                        .collect::<gdal::errors::Result<Vec<_>>>()?
-                        .into_boxed_slice())
-                })
-                .collect::<Result<Vec<_>, _>>()?,
-        );
-
-        let (req_tx, req_rx) = flume::unbounded();
-
-        let mut workers = Vec::new();
-        for _thread_id in 0..threads {
-            let req_rx: Receiver<BlockReadRequest> = req_rx.clone();
-
-            workers.push(thread::spawn(move || {
-                for request in req_rx {
-                    let block = {
-                        let region_size = request.state.region_size;
-                        let dataset = request.datasets[_thread_id][request.dataset_idx].lock();
-                        let band = dataset.rasterband(1).unwrap();
-                        let size = band.size();
-                        let window = (request.x * region_size.0, request.y * region_size.1);
-                        let window_size = (
-                            if window.0 + region_size.0 <= size.0 {
-                                region_size.0
-                            } else {
-                                size.0 - window.0
-                            },
-                            if window.1 + region_size.1 <= size.1 {
-                                region_size.1
-                            } else {
-                                size.1 - window.1
-                            },
-                        );
-
-                        // println!(
-                        //     "Reading block {}, {} in dataset {} on thread {}",
-                        //     request.x, request.y, request.idx, thread_id
-                        // );
-
-                        let buffer = band
-                            .read_as::<u16>(
-                                (window.0 as isize, window.1 as isize),
-                                window_size,
-                                window_size,
-                                None,
-                            )
-                            .unwrap();
-
-                        TypedBuffer::U16(buffer)
-                        // band.read_typed_block(request.x, request.y).unwrap()
-                    };
-                    let blocks = {
-                        let mut blocks = request.state.blocks.lock();
-                        blocks.insert(request.dataset_idx, block);
-                        if blocks.len() == request.num_datasets {
-                            let blocks = mem::take(blocks.deref_mut());
-                            Some(blocks)
-                        } else {
-                            None
-                        }
-                    };
-                    if let Some(blocks) = blocks {
-                        let BlockReadRequest { handler, .. } = request;
-                        (handler)(request.x, request.y, blocks);
-                    }
-                }
-            }));
+impl Args {
+    /// Paths the command writes.
+    fn outputs(&self) -> Vec<&str> {
+        match &self.command {
+            Some(Command::Zonal(zonal_args)) => zonal_args.outputs(),
+            Some(Command::Composite(composite_args)) => composite_args.outputs(),
+            Some(Command::Change(change_args)) => change_args.outputs(),
+            Some(Command::Classify(classify_args)) => classify_args.outputs(),
+            Some(Command::Sample(sample_args)) => sample_args.outputs(),
+            Some(Command::Transform(transform_args)) => transform_args.outputs(),
+            Some(Command::Provenance(_)) => Vec::new(),
+            None => [
+                Some(&self.output),
+                self.render_output.as_ref(),
+                self.preview_output.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect(),
         }
-
-        let dataset = datasets[0][0].lock();
-        let band = dataset.rasterband(1)?;
-        let raster_size = band.size();
-        let block_size = band.block_size();
-        // let block_size = (2048, 2048);
-        let _geo_transform = dataset.geo_transform()?;
-        drop(dataset);
-
-        let region_size = block_size;
-        let blocks = (
-            raster_size.0.div_ceil(block_size.0),
-            raster_size.1.div_ceil(block_size.1),
-        );
-
-        Ok(Self {
-            datasets,
-            region_size,
-            blocks,
-            workers,
-            req_tx,
-        })
     }
+}
 
-    pub fn run(
-        &self,
-        block_x: usize,
-        block_y: usize,
-        dataset_indices: &[usize],
-        handler: BlockReadHandler,
-    ) {
-        let handler = Arc::new(handler);
-        let state = BlockReadState {
-            region_size: self.region_size,
-            blocks: Arc::new(Mutex::new(HashMap::new())),
-        };
-        for &idx in dataset_indices {
-            let request = BlockReadRequest {
-                datasets: self.datasets.clone(),
-                num_datasets: dataset_indices.len(),
-                dataset_idx: idx,
-                x: block_x,
-                y: block_y,
-                state: state.clone(),
-                handler: handler.clone(),
-            };
-            self.req_tx.send(request).unwrap();
-        }
+pub fn main() -> Result<()> {
+    let args = Args::parse();
+    // Before anything is printed, so that outputs piped to `/vsistdout/` stay intact.
+    if args.outputs().into_iter().any(vsi::is_stdout) {
+        vsi::reserve_stdout()?;
+    }
+    let result = run(args);
+    if result.is_err() {
+        vsi::remove_staged();
     }
+    result
+}
 
-    pub fn join(self) {
-        drop(self.req_tx);
+fn run(mut args: Args) -> Result<()> {
+    match &args.command {
+        Some(Command::Zonal(zonal_args)) => return zonal::run(zonal_args),
+        Some(Command::Composite(composite_args)) => return temporal::run(composite_args),
+        Some(Command::Change(change_args)) => return change::run(change_args),
+        Some(Command::Classify(classify_args)) => return classify::run(classify_args),
+        Some(Command::Sample(sample_args)) => return sample::run(sample_args),
+        Some(Command::Transform(transform_args)) => return transform::run(transform_args),
+        Some(Command::Provenance(provenance_args)) => return provenance::run(provenance_args),
+        None => {}
+    }
+    ensure!(
+        args.histogram_buckets != Some(0),
+        "--histogram-buckets must be at least 1"
+    );
+    args.nir = stac::resolve_bands(&args.nir, Band::Nir)?;
+    args.red = stac::resolve_bands(&args.red, Band::Red)?;
+    let output_path = args.output.as_str();
 
-        let mut errors = Vec::new();
-        for worker in self.workers {
-            if let Err(e) = worker.join() {
-                errors.push(e);
-            }
-        }
+    ensure!(
+        args.nir.len() == args.red.len(),
+        "--nir and --red need the same number of tiles"
+    );
+    let tiles = args.nir.len();
+    // From the original file names and product metadata, before any warping.
+    let calibration = args
+        .nir
+        .iter()
+        .zip(&args.red)
+        .map(|(nir, red)| Ok([args.sensor.calibration(nir)?, args.sensor.calibration(red)?]))
+        .collect::<Result<Vec<_>>>()?;
+    let mut bands = vec![args.nir.clone(), args.red.clone()];
+    if args.t_srs.is_some() || args.tr.is_some() || tiles > 1 {
+        bands = reproject::warp_inputs(
+            &bands,
+            &WarpOptions {
+                t_srs: args.t_srs.as_deref(),
+                resolution: args.tr.as_ref().map(|tr| (tr[0], tr[1])),
+                resampling: args.resampling,
+                // Every tile of a band shares the calibration of the first one.
+                nodata: &[calibration[0][0].nodata, calibration[0][1].nodata],
+            },
+        )?;
+    }
+    // NIR tiles followed by red tiles.
+    let inputs = bands.concat();
 
-        if !errors.is_empty() {
-            panic::resume_unwind(Box::new(errors));
-        }
+    let dataset = Dataset::open(&inputs[0])?;
+    let source_geo_transform = dataset.geo_transform()?;
+    let mut window = select_window(&args, &dataset)?;
+
+    let cutline = match &args.cutline {
+        Some(path) => Some(PolygonLayer::open(
+            path,
+            args.cutline_layer.as_deref(),
+            &dataset.spatial_ref()?,
+        )?),
+        None => None,
+    };
+    if let (Some(cutline), true) = (&cutline, args.crop_to_cutline) {
+        let cutline_window = PixelWindow::from_bounds(
+            &source_geo_transform,
+            cutline.bounds(),
+            dataset.raster_size(),
+        )?;
+        window = window
+            .intersection(&cutline_window)
+            .context("cutline does not intersect the selected window")?;
     }
-}
 
-pub fn main() -> Result<()> {
-    let granule_path = "../data/";
-    // let nir_path = format!("{}T33TTG_20250305T100029_B8A_20m.jp2", granule_path);
-    // let red_path = format!("{}T33TTG_20250305T100029_B04_20m.jp2", granule_path);
-    let nir_path = format!("{}T33TTG_20250305T100029_B08_10m.jp2", granule_path);
-    let red_path = format!("{}T33TTG_20250305T100029_B04_10m.jp2", granule_path);
-    let output_path = "../output/rust_parallel_io.tif";
-
-    let inputs = [nir_path, red_path];
+    let projection = dataset.projection();
+    let geo_transform = window.geo_transform(&source_geo_transform);
+    // NIR tiles, then red tiles.
+    let input_calibration = calibration
+        .iter()
+        .map(|[nir, _]| *nir)
+        .chain(calibration.iter().map(|[_, red]| *red))
+        .collect::<Vec<_>>();
+
+    if let Some(factor) = args.preview {
+        let preview_output = args.preview_output.unwrap_or_else(|| {
+            Path::new(output_path)
+                .with_extension("preview.png")
+                .to_string_lossy()
+                .into_owned()
+        });
+        let ramp = ColorRamp::parse(args.render.as_deref().unwrap_or("rdylgn"), NDVI_RANGE)?;
+        let provenance = Provenance::new(
+            &[args.nir.clone(), args.red.clone()].concat(),
+            format!(
+                "{} at 1/{factor} resolution",
+                index::Index::Ndvi.expression()
+            ),
+        )
+        .with_calibration(&input_calibration)
+        .with_checksums(&args.checksums)?;
+        return preview::run(
+            &bands,
+            &calibration,
+            args.overlap,
+            window,
+            factor,
+            &preview_output,
+            ramp,
+            &provenance,
+        );
+    }
     let _io_threads = 4.max(
         std::thread::available_parallelism()
             .unwrap_or(NonZero::<usize>::MIN)
             .get(),
     );
-    let io_threads = 8;
+    let io_threads = args.io_threads;
 
-    let block_reader = ParallelBlockReader::new(&inputs, io_threads)?;
+    let encoding = Encoding::new(args.output_type, NDVI_RANGE, args.scale, args.offset)?;
 
-    let dataset = Dataset::open(&inputs[0])?;
-    let (width, height) = dataset.raster_size();
-
-    let driver = DriverManager::get_output_driver_for_dataset_name(output_path, DriverType::Raster)
-        .expect("unknown output format");
-    let creation_options =
-        RasterCreationOptions::from_iter(["COMPRESS=DEFLATE", "TILED=YES", "NUM_THREADS=ALL_CPUS"]);
-    let mut output = driver.create_with_band_type_with_options::<i16, _>(
-        output_path,
-        width,
-        height,
-        1,
-        &creation_options,
-    )?;
-
-    const SCALING_FACTOR: f32 = 10000.0;
-    const NODATA_VALUE: i16 = -20000;
-
-    output.set_projection(&dataset.projection())?;
-    output.set_geo_transform(&dataset.geo_transform()?)?;
-    output
-        .rasterband(1)?
-        .set_no_data_value(Some(NODATA_VALUE as f64))?;
+    ensure!(
+        args.focal_size % 2 == 1,
+        "--focal-size must be odd, so that neighbourhoods are centred"
+    );
+    let halo = args.focal.map_or(0, |_| args.focal_size / 2);
+    let cancel = progress::interrupt_token();
+    let mut block_reader = ParallelBlockReader::with_halo(&inputs, io_threads, Some(window), halo)?
+        .on_progress(progress::bar())
+        .cancel_on(cancel.clone());
+    if let Some(capacity) = args.format.ordered {
+        block_reader = block_reader.ordered(capacity);
+    }
 
-    let (tx, rx) = flume::unbounded();
+    let mut output = args
+        .format
+        .create(encoding.output_type, output_path, window.size, 1)?;
+
+    output.set_projection(&projection)?;
+    output.set_geo_transform(&geo_transform)?;
+    args.sensor
+        .set_metadata(&mut output, &[args.nir.clone(), args.red.clone()].concat())?;
+    let expression = index::Index::Ndvi.expression();
+    let expression = match args.focal {
+        Some(focal) => format!(
+            "focal {} over {size}x{size} pixels of {expression}",
+            focal.to_possible_value().unwrap().get_name(),
+            size = args.focal_size
+        ),
+        None => expression,
+    };
+    let provenance = Provenance::new(&[args.nir.clone(), args.red.clone()].concat(), expression)
+        .with_calibration(&input_calibration)
+        .with_mask(
+            args.cutline
+                .as_ref()
+                .map(|path| format!("outside the cutline {path}")),
+        )
+        .with_checksums(&args.checksums)?;
+    provenance.write(&mut output)?;
+    encoding.apply(&mut output.rasterband(1)?)?;
+
+    let mut renderer = match (&args.render, &args.render_output) {
+        (Some(ramp), Some(render_output)) => Some(Renderer::create(
+            render_output,
+            args.render_mode,
+            ColorRamp::parse(ramp, NDVI_RANGE)?,
+            NDVI_RANGE,
+            window.size,
+            &projection,
+            &geo_transform,
+            &provenance,
+        )?),
+        _ => None,
+    };
+
+    // Bounded, so that readers wait for the writer instead of queueing up every block in memory
+    // when writing (or uploading) is the bottleneck; `--ordered` only bounds the blocks held
+    // back for ordering.
+    let (tx, rx) = flume::bounded(io_threads);
     let dataset_indices = (0..inputs.len()).collect::<Vec<_>>();
-    for y in 0..block_reader.blocks.1 {
-        for x in 0..block_reader.blocks.0 {
+    for y in block_reader.blocks.1.clone() {
+        for x in block_reader.blocks.0.clone() {
             let tx = tx.clone();
             block_reader.run(
                 x,
@@ -251,49 +463,91 @@ pub fn main() -> Result<()> {
     }
     drop(tx);
 
-    let block_size = 1024; // FIXME
-    let mut ndvi_data = vec![0; block_size * block_size];
+    let mut stats = Statistics::new(
+        args.histogram_buckets
+            .map(|buckets| Histogram::new(NDVI_RANGE, buckets)),
+    );
+
+    let region_size = block_reader.region_size;
+    let mut ndvi_data = Vec::with_capacity(region_size.0 * region_size.1);
+    let mut tile_ndvi = Vec::new();
+    let mut focal_data = Vec::new();
     for (x, y, mut blocks) in rx {
-        let nir_block = blocks.remove(&0).unwrap();
-        let red_block = blocks.remove(&1).unwrap();
-        let nir_block = nir_block.as_u16().unwrap();
-        let red_block = red_block.as_u16().unwrap();
-
-        ndvi_data.resize(nir_block.data().len(), 0);
-        for (ndvi, nir, red) in izip!(
-            ndvi_data.iter_mut(),
-            nir_block.data().iter().copied(),
-            red_block.data().iter().copied()
-        ) {
-            let red = red as f32 - 1000.0;
-            let nir = nir as f32 - 1000.0;
-
-            let t = if red + nir > 0.0 {
-                ((nir - red) / (nir + red)).clamp(-1.0, 1.0)
-            } else {
-                NODATA_VALUE as f32
-            };
-
-            *ndvi = (t * SCALING_FACTOR).round_ties_even() as i16;
+        let blocks = (0..inputs.len())
+            .map(|i| blocks.remove(&i).unwrap())
+            .collect::<Vec<_>>();
+        let data = blocks
+            .iter()
+            .map(|block| block.as_u16().unwrap().data())
+            .collect::<Vec<_>>();
+        mosaic::compute_ndvi(
+            &data[..tiles],
+            &data[tiles..],
+            &calibration,
+            args.overlap,
+            &mut tile_ndvi,
+            &mut ndvi_data,
+        );
+
+        let region = block_reader.region(x, y);
+        if let Some(focal) = args.focal {
+            focal.apply(
+                args.focal_size,
+                block_reader.read_region(x, y),
+                &ndvi_data,
+                region,
+                &mut focal_data,
+            );
+            mem::swap(&mut ndvi_data, &mut focal_data);
+        }
+        if let Some(cutline) = &cutline {
+            cutline.clip(
+                &region.geo_transform(&source_geo_transform),
+                region.size,
+                &mut ndvi_data,
+            )?;
         }
 
-        let mut buffer = Buffer::new(nir_block.shape(), ndvi_data);
+        let offset = window.relative_offset(&region);
+        stats.add_block(&ndvi_data);
+        if let Some(renderer) = &mut renderer {
+            renderer.write_block(offset, region.size, &ndvi_data)?;
+        }
+        let mut buffer = encoding.encode(region.size, &ndvi_data);
 
         let mut output_band = output.rasterband(1)?;
 
-        output_band.write(
-            (
-                x as isize * block_size as isize,
-                y as isize * block_size as isize,
-            ),
-            red_block.shape(),
-            &mut buffer,
-        )?;
-
-        ndvi_data = buffer.into_shape_and_vec().1;
+        output_band.write_typed(offset, &mut buffer)?;
     }
 
     block_reader.join();
 
+    if cancel.is_cancelled() {
+        args.format.discard(output, output_path)?;
+        if let Some(renderer) = renderer {
+            renderer.discard()?;
+        }
+        bail!("interrupted, {output_path} not written");
+    }
+
+    stats::finalize_band(
+        &mut output.rasterband(1)?,
+        &stats,
+        &encoding,
+        &BandInfo {
+            description: "NDVI",
+            unit: "NDVI",
+        },
+    )?;
+    output.flush_cache()?;
+    let item = stac::raster_item(&output, output_path, &provenance)?;
+    args.format.finish(output, output_path, &provenance)?;
+    item.write()?;
+    if let Some(renderer) = renderer {
+        renderer.finish()?;
+    }
+
+    println!("NDVI {stats}");
+
     Ok(())
 }
@@ -0,0 +1,132 @@
+use clap::ValueEnum;
+
+use crate::{index::Index, sensor::Calibration};
+
+/// How index values of overlapping tiles are combined into the mosaic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OverlapRule {
+    /// Value of the first tile (in command line order) that has valid data
+    First,
+    /// Value of the last tile that has valid data
+    Last,
+    /// Highest valid value, e.g. the greenest observation
+    MaxValid,
+    /// Mean of the valid values
+    Mean,
+}
+
+impl OverlapRule {
+    /// Combines the index values of a block computed from each tile, where NaN marks nodata.
+    pub fn combine(self, tiles: &[Vec<f32>], mosaic: &mut Vec<f32>) {
+        mosaic.clear();
+        mosaic.extend((0..tiles[0].len()).map(|i| {
+            let mut valid = tiles.iter().map(|tile| tile[i]).filter(|v| !v.is_nan());
+            let value = match self {
+                Self::First => valid.next(),
+                Self::Last => valid.next_back(),
+                Self::MaxValid => valid.reduce(f32::max),
+                Self::Mean => {
+                    let (sum, count) = valid.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+                    (count > 0).then(|| sum / count as f32)
+                }
+            };
+            value.unwrap_or(f32::NAN)
+        }));
+    }
+}
+
+/// Computes NDVI over a mosaic from the blocks of matching NIR and red tiles and their
+/// calibrations, using `scratch` for the per-tile values.
+pub fn compute_ndvi(
+    nir: &[&[u16]],
+    red: &[&[u16]],
+    calibration: &[[Calibration; 2]],
+    rule: OverlapRule,
+    scratch: &mut Vec<Vec<f32>>,
+    ndvi: &mut Vec<f32>,
+) {
+    if nir.len() == 1 {
+        Index::Ndvi.compute(nir[0], red[0], calibration[0], ndvi);
+        return;
+    }
+
+    scratch.resize_with(nir.len(), Vec::new);
+    for (((nir, red), &calibration), tile) in
+        nir.iter().zip(red).zip(calibration).zip(scratch.iter_mut())
+    {
+        Index::Ndvi.compute(nir, red, calibration, tile);
+    }
+    rule.combine(scratch, ndvi);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAN: f32 = f32::NAN;
+
+    /// Three tiles over four pixels: valid in all, in some, in one, and in none of them.
+    fn tiles() -> Vec<Vec<f32>> {
+        vec![
+            vec![0.1, NAN, NAN, NAN],
+            vec![0.5, 0.2, NAN, NAN],
+            vec![0.3, 0.6, 0.4, NAN],
+        ]
+    }
+
+    fn combine(rule: OverlapRule) -> Vec<f32> {
+        let mut mosaic = vec![9.0; 10];
+        rule.combine(&tiles(), &mut mosaic);
+        assert_eq!(mosaic.len(), 4);
+        assert!(mosaic[3].is_nan());
+        mosaic
+    }
+
+    #[test]
+    fn first_takes_the_first_valid_tile() {
+        assert_eq!(combine(OverlapRule::First)[..3], [0.1, 0.2, 0.4]);
+    }
+
+    #[test]
+    fn last_takes_the_last_valid_tile() {
+        assert_eq!(combine(OverlapRule::Last)[..3], [0.3, 0.6, 0.4]);
+    }
+
+    #[test]
+    fn max_valid_ignores_nodata() {
+        assert_eq!(combine(OverlapRule::MaxValid)[..3], [0.5, 0.6, 0.4]);
+    }
+
+    #[test]
+    fn mean_averages_the_valid_tiles_only() {
+        let mosaic = combine(OverlapRule::Mean);
+        assert!((mosaic[0] - 0.3).abs() < 1e-6);
+        assert!((mosaic[1] - 0.4).abs() < 1e-6);
+        assert_eq!(mosaic[2], 0.4);
+    }
+
+    #[test]
+    fn ndvi_of_tiles_outside_the_swath_does_not_count() {
+        let calibration = [[Calibration::SENTINEL2_L2A; 2]; 2];
+        // The second tile is nodata (0) where the first one has data, and the other way round.
+        let nir: [&[u16]; 2] = [&[4000, 0], &[0, 5000]];
+        let red: [&[u16]; 2] = [&[2000, 0], &[0, 1500]];
+        let mut scratch = Vec::new();
+        let mut ndvi = Vec::new();
+        compute_ndvi(
+            &nir,
+            &red,
+            &calibration,
+            OverlapRule::Mean,
+            &mut scratch,
+            &mut ndvi,
+        );
+
+        let mut single = Vec::new();
+        Index::Ndvi.compute(nir[0], red[0], calibration[0], &mut single);
+        assert_eq!(ndvi[0], single[0]);
+        Index::Ndvi.compute(nir[1], red[1], calibration[1], &mut single);
+        assert_eq!(ndvi[1], single[1]);
+        assert!(ndvi.iter().all(|v| !v.is_nan()));
+    }
+}
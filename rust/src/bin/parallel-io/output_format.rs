@@ -0,0 +1,484 @@
+//! Raster output formats: GeoTIFF, and Zarr and NetCDF stores of chunked arrays following the CF
+//! conventions, for multi-band outputs that tools such as xarray read with their coordinates, grid
+//! mapping and encoding.
+//!
+//! Each band is written as a 2-D `(y, x)` array of its own. Composites keep their reductions in
+//! separate arrays and their scene dates in metadata: there is no `time` dimension or coordinate
+//! variable, so a stack of scenes is not a CF time series.
+//!
+//! Every format is written through the same block pipeline: [`OutputFormatArgs::create`] gives a
+//! GDAL dataset whose bands take blocks with `write_typed`, and [`OutputFormatArgs::finish`]
+//! closes it once statistics and metadata are set.
+
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context as _, Result};
+use clap::ValueEnum;
+use gdal::{
+    raster::RasterCreationOptions, spatial_ref::SpatialRef, Dataset, DatasetOptions, Driver,
+    DriverManager, DriverType, GdalOpenFlags, GeoTransform, Metadata as _,
+};
+
+use crate::{
+    gdal_ext::{delete_dataset, AttributeValue, MdGroup},
+    output_type::OutputType,
+    provenance::Provenance,
+    vsi,
+};
+
+/// Name of the CF grid mapping variable of Zarr outputs, as written by rioxarray.
+const GRID_MAPPING: &str = "spatial_ref";
+
+/// Format of raster outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Tiled, DEFLATE-compressed GeoTIFF, or the format GDAL picks from another extension
+    #[value(name = "gtiff")]
+    GTiff,
+    /// Zarr v2 store with one chunked array per band
+    #[value(name = "zarr")]
+    Zarr,
+    /// Zarr v3 store with one chunked array per band
+    #[value(name = "zarr3")]
+    Zarr3,
+    /// NetCDF-4 file with one compressed variable per band
+    #[value(name = "netcdf")]
+    NetCdf,
+}
+
+impl OutputFormat {
+    /// Format of `path` from its extension: `.zarr` for Zarr v2, `.nc` for NetCDF, GeoTIFF (or
+    /// whatever GDAL picks) otherwise.
+    #[must_use]
+    pub fn from_path(path: &str) -> Self {
+        let extension = Path::new(path.trim_end_matches('/'))
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("zarr") => Self::Zarr,
+            Some("nc" | "nc4") => Self::NetCdf,
+            _ => Self::GTiff,
+        }
+    }
+
+    fn driver(self, path: &str) -> Result<Driver> {
+        Ok(match self {
+            Self::GTiff => {
+                match DriverManager::get_output_driver_for_dataset_name(path, DriverType::Raster) {
+                    Some(driver) => driver,
+                    // Such as a bare `/vsistdout/`.
+                    None if Path::new(path).extension().is_none() => {
+                        DriverManager::get_driver_by_name("GTiff")?
+                    }
+                    None => bail!("unknown output format"),
+                }
+            }
+            Self::Zarr | Self::Zarr3 => DriverManager::get_driver_by_name("Zarr")?,
+            Self::NetCdf => DriverManager::get_driver_by_name("netCDF")?,
+        })
+    }
+
+    /// Path that GDAL writes the output bound for `path` to, staging it locally when the format
+    /// cannot be written there directly (see [`vsi::writable_path`]).
+    fn writable_path(self, path: &str) -> Result<String> {
+        Ok(match self {
+            // Every chunk is a file of its own, written whole, which object stores take.
+            Self::Zarr | Self::Zarr3 => {
+                ensure!(
+                    !vsi::is_stdout(path)
+                        && !["/vsizip/", "/vsigzip/", "/vsitar/"]
+                            .iter()
+                            .any(|prefix| path.starts_with(prefix)),
+                    "a Zarr store cannot be streamed to {path}"
+                );
+                path.to_owned()
+            }
+            // The netCDF library only writes local files.
+            Self::NetCdf if path.starts_with("/vsi") => vsi::staging_path(path),
+            _ => vsi::writable_path(path),
+        })
+    }
+
+    /// Creation options of the format. GDAL's netCDF driver chunks variables by rows, so
+    /// `chunk_size` only applies to Zarr.
+    fn creation_options(self, chunk_size: usize) -> Result<RasterCreationOptions> {
+        let options = match self {
+            Self::GTiff => vec!["COMPRESS=DEFLATE", "TILED=YES", "NUM_THREADS=ALL_CPUS"],
+            Self::Zarr => vec!["FORMAT=ZARR_V2", "COMPRESS=ZLIB", "SINGLE_ARRAY=NO"],
+            Self::Zarr3 => vec!["FORMAT=ZARR_V3", "COMPRESS=GZIP", "SINGLE_ARRAY=NO"],
+            Self::NetCdf => vec!["FORMAT=NC4", "COMPRESS=DEFLATE"],
+        };
+        let mut options = RasterCreationOptions::from_iter(options);
+        if matches!(self, Self::Zarr | Self::Zarr3) {
+            options.set_name_value("BLOCKSIZE", &format!("{chunk_size},{chunk_size}"))?;
+        }
+        Ok(options)
+    }
+}
+
+/// Output format options of the commands writing rasters block by block.
+#[derive(clap::Args)]
+pub struct OutputFormatArgs {
+    /// Output format, picked from the output extension (`.zarr`, `.nc`) if not given
+    #[arg(long = "of", value_enum)]
+    pub output_format: Option<OutputFormat>,
+
+    /// Width and height in pixels of the chunks of Zarr outputs
+    #[arg(long, default_value_t = 512)]
+    pub chunk_size: usize,
+
+    /// Write blocks in raster order, reading at most N blocks (64 by default) ahead of the next
+    /// one, so that outputs are reproducible byte for byte and strips are written sequentially
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "64")]
+    pub ordered: Option<usize>,
+}
+
+impl OutputFormatArgs {
+    /// Format of the output `path`.
+    #[must_use]
+    pub fn format(&self, path: &str) -> OutputFormat {
+        self.output_format
+            .unwrap_or_else(|| OutputFormat::from_path(path))
+    }
+
+    /// Creates raster `path` with `bands` bands of `output_type`, or its staging file if `path`
+    /// only takes sequential writes.
+    pub fn create(
+        &self,
+        output_type: OutputType,
+        path: &str,
+        size: (usize, usize),
+        bands: usize,
+    ) -> Result<Dataset> {
+        let format = self.format(path);
+        let driver = format.driver(path)?;
+        let options = format.creation_options(self.chunk_size)?;
+        let target = format.writable_path(path)?;
+        Ok(output_type.create(&driver, &target, size, bands, &options)?)
+    }
+
+    /// Closes `dataset`, created by [`OutputFormatArgs::create`] for `path`, completes the CF
+    /// attributes GDAL does not write itself, with `provenance` as the global `history`, and
+    /// streams a staged output to `path`.
+    ///
+    /// GDAL's netCDF driver writes the coordinate variables, grid mapping, `scale_factor`,
+    /// `add_offset` and `_FillValue` from the band API. Its Zarr driver writes the coordinate
+    /// arrays and encoding but keeps the CRS in a `_CRS` attribute, so the store is reopened in
+    /// multidimensional mode to add the grid mapping variable and coordinate attributes.
+    pub fn finish(&self, mut dataset: Dataset, path: &str, provenance: &Provenance) -> Result<()> {
+        let history = format!("{}: {}", provenance.timestamp, provenance.command_line);
+        let source = format!("{} {}", provenance.tool, provenance.version);
+        let format = self.format(path);
+        let target = format.writable_path(path)?;
+        match format {
+            OutputFormat::GTiff => dataset.close()?,
+            OutputFormat::NetCdf => {
+                dataset.set_metadata_item("NC_GLOBAL#history", &history, "")?;
+                dataset.set_metadata_item("NC_GLOBAL#source", &source, "")?;
+                dataset.close()?;
+            }
+            OutputFormat::Zarr | OutputFormat::Zarr3 => {
+                let spatial_ref = dataset.spatial_ref()?;
+                let geo_transform = dataset.geo_transform()?;
+                dataset.close()?;
+
+                let store = Dataset::open_ex(
+                    &target,
+                    DatasetOptions {
+                        open_flags: GdalOpenFlags::GDAL_OF_MULTIDIM_RASTER
+                            | GdalOpenFlags::GDAL_OF_UPDATE,
+                        ..Default::default()
+                    },
+                )?;
+                let root = MdGroup::root(&store)?;
+                write_zarr_cf(&root, &spatial_ref, &geo_transform)
+                    .with_context(|| format!("writing CF attributes of {path}"))?;
+                root.set_attribute("history", &history.into())?;
+                root.set_attribute("source", &source.into())?;
+            }
+        }
+        vsi::commit(&target, path)
+    }
+
+    /// Closes `dataset`, created by [`OutputFormatArgs::create`] for `path`, unfinished and
+    /// deletes it, so that an interrupted run leaves no partial output behind.
+    pub fn discard(&self, dataset: Dataset, path: &str) -> Result<()> {
+        let target = self.format(path).writable_path(path)?;
+        dataset.close()?;
+        Ok(delete_dataset(&target)?)
+    }
+}
+
+/// Adds a CF grid mapping variable, coordinate attributes and `Conventions` to the Zarr store
+/// with root group `root`, written by GDAL's classic raster API.
+fn write_zarr_cf(
+    root: &MdGroup,
+    spatial_ref: &SpatialRef,
+    geo_transform: &GeoTransform,
+) -> Result<()> {
+    let grid_mapping = root.create_scalar_array(GRID_MAPPING)?;
+    for (name, value) in grid_mapping_attributes(spatial_ref, geo_transform)? {
+        grid_mapping.set_attribute(name, &value)?;
+    }
+
+    let geographic = spatial_ref.is_geographic();
+    let units = match spatial_ref.linear_units_name()?.as_str() {
+        "metre" | "meter" => "m".to_owned(),
+        name => name.to_owned(),
+    };
+    for name in root.array_names() {
+        let Some(array) = root.array(&name) else {
+            continue;
+        };
+        let attributes: Vec<(&str, AttributeValue)> = match name.as_str() {
+            GRID_MAPPING => continue,
+            "X" if geographic => vec![
+                ("standard_name", "longitude".into()),
+                ("units", "degrees_east".into()),
+                ("axis", "X".into()),
+            ],
+            "Y" if geographic => vec![
+                ("standard_name", "latitude".into()),
+                ("units", "degrees_north".into()),
+                ("axis", "Y".into()),
+            ],
+            "X" => vec![
+                ("standard_name", "projection_x_coordinate".into()),
+                ("long_name", "x coordinate of projection".into()),
+                ("units", units.as_str().into()),
+                ("axis", "X".into()),
+            ],
+            "Y" => vec![
+                ("standard_name", "projection_y_coordinate".into()),
+                ("long_name", "y coordinate of projection".into()),
+                ("units", units.as_str().into()),
+                ("axis", "Y".into()),
+            ],
+            _ if array.dimension_count() == 2 => vec![("grid_mapping", GRID_MAPPING.into())],
+            _ => continue,
+        };
+        for (attribute, value) in attributes {
+            array.set_attribute(attribute, &value)?;
+        }
+    }
+    root.set_attribute("Conventions", &"CF-1.8".into())?;
+    Ok(())
+}
+
+/// Attributes of the CF grid mapping variable of `spatial_ref`.
+///
+/// The WKT is given both as `crs_wkt` (CF) and `spatial_ref` (GDAL and rioxarray), with the
+/// `GeoTransform` read by GDAL. `grid_mapping_name` and its parameters are only set for the
+/// projections of the usual Earth observation grids, from which readers can rebuild the CRS
+/// without the WKT: geographic, transverse Mercator (UTM), Lambert conformal conic, Albers and
+/// Lambert azimuthal equal area.
+fn grid_mapping_attributes(
+    spatial_ref: &SpatialRef,
+    geo_transform: &GeoTransform,
+) -> Result<Vec<(&'static str, AttributeValue)>> {
+    let wkt = spatial_ref.to_wkt()?;
+    let mut attributes = vec![
+        ("crs_wkt", wkt.as_str().into()),
+        ("spatial_ref", wkt.into()),
+        (
+            "GeoTransform",
+            geo_transform
+                .iter()
+                .map(f64::to_string)
+                .collect::<Vec<_>>()
+                .join(" ")
+                .into(),
+        ),
+    ];
+
+    let param =
+        |name: &str| -> Result<f64> { Ok(spatial_ref.get_proj_param(name)?.unwrap_or(0.0)) };
+    let projection = spatial_ref.get_attr_value("PROJECTION", 0)?;
+    let (name, params): (&str, Vec<(&str, AttributeValue)>) = if spatial_ref.is_geographic() {
+        ("latitude_longitude", vec![])
+    } else {
+        match projection.as_deref() {
+            Some("Transverse_Mercator") => (
+                "transverse_mercator",
+                vec![
+                    (
+                        "scale_factor_at_central_meridian",
+                        param("scale_factor")?.into(),
+                    ),
+                    (
+                        "longitude_of_central_meridian",
+                        param("central_meridian")?.into(),
+                    ),
+                    (
+                        "latitude_of_projection_origin",
+                        param("latitude_of_origin")?.into(),
+                    ),
+                ],
+            ),
+            Some("Lambert_Conformal_Conic_2SP") => (
+                "lambert_conformal_conic",
+                vec![
+                    (
+                        "standard_parallel",
+                        vec![param("standard_parallel_1")?, param("standard_parallel_2")?].into(),
+                    ),
+                    (
+                        "longitude_of_central_meridian",
+                        param("central_meridian")?.into(),
+                    ),
+                    (
+                        "latitude_of_projection_origin",
+                        param("latitude_of_origin")?.into(),
+                    ),
+                ],
+            ),
+            Some("Albers_Conic_Equal_Area") => (
+                "albers_conical_equal_area",
+                vec![
+                    (
+                        "standard_parallel",
+                        vec![param("standard_parallel_1")?, param("standard_parallel_2")?].into(),
+                    ),
+                    (
+                        "longitude_of_central_meridian",
+                        param("longitude_of_center")?.into(),
+                    ),
+                    (
+                        "latitude_of_projection_origin",
+                        param("latitude_of_center")?.into(),
+                    ),
+                ],
+            ),
+            Some("Lambert_Azimuthal_Equal_Area") => (
+                "lambert_azimuthal_equal_area",
+                vec![
+                    (
+                        "longitude_of_projection_origin",
+                        param("longitude_of_center")?.into(),
+                    ),
+                    (
+                        "latitude_of_projection_origin",
+                        param("latitude_of_center")?.into(),
+                    ),
+                ],
+            ),
+            _ => return Ok(attributes),
+        }
+    };
+    attributes.push(("grid_mapping_name", name.into()));
+    attributes.extend(params);
+    if !spatial_ref.is_geographic() {
+        attributes.push(("false_easting", param("false_easting")?.into()));
+        attributes.push(("false_northing", param("false_northing")?.into()));
+    }
+    let (semi_major, semi_minor) = (spatial_ref.semi_major()?, spatial_ref.semi_minor()?);
+    attributes.push(("semi_major_axis", semi_major.into()));
+    attributes.push((
+        "inverse_flattening",
+        if semi_major > semi_minor {
+            semi_major / (semi_major - semi_minor)
+        } else {
+            0.0
+        }
+        .into(),
+    ));
+    Ok(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_are_picked_from_the_extension() {
+        assert_eq!(OutputFormat::from_path("ndvi.zarr"), OutputFormat::Zarr);
+        assert_eq!(
+            OutputFormat::from_path("/vsis3/bucket/NDVI.ZARR/"),
+            OutputFormat::Zarr
+        );
+        assert_eq!(
+            OutputFormat::from_path("composite.nc"),
+            OutputFormat::NetCdf
+        );
+        assert_eq!(
+            OutputFormat::from_path("composite.nc4"),
+            OutputFormat::NetCdf
+        );
+        assert_eq!(OutputFormat::from_path("ndvi.tif"), OutputFormat::GTiff);
+        assert_eq!(OutputFormat::from_path("ndvi.png"), OutputFormat::GTiff);
+        assert_eq!(OutputFormat::from_path("/vsistdout/"), OutputFormat::GTiff);
+    }
+
+    #[test]
+    fn utm_grid_mapping_is_transverse_mercator() {
+        let spatial_ref = SpatialRef::from_epsg(32633).unwrap();
+        let geo_transform = [500_000.0, 10.0, 0.0, 5_000_000.0, 0.0, -10.0];
+        let attributes = grid_mapping_attributes(&spatial_ref, &geo_transform).unwrap();
+        let attribute = |name: &str| {
+            attributes
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        assert_eq!(
+            attribute("grid_mapping_name"),
+            Some("transverse_mercator".into())
+        );
+        assert_eq!(
+            attribute("scale_factor_at_central_meridian"),
+            Some(0.9996.into())
+        );
+        assert_eq!(
+            attribute("longitude_of_central_meridian"),
+            Some(15.0.into())
+        );
+        assert_eq!(attribute("false_easting"), Some(500_000.0.into()));
+        assert_eq!(attribute("semi_major_axis"), Some(6_378_137.0.into()));
+        assert_eq!(
+            attribute("GeoTransform"),
+            Some("500000 10 0 5000000 0 -10".into())
+        );
+        assert!(attribute("crs_wkt").is_some());
+    }
+
+    #[test]
+    fn zarr_stores_carry_the_cf_attributes() {
+        let path = "/vsimem/output_format/ndvi.zarr";
+        let args = OutputFormatArgs {
+            output_format: None,
+            chunk_size: 2,
+            ordered: None,
+        };
+        let mut dataset = args.create(OutputType::Int16, path, (4, 4), 1).unwrap();
+        dataset
+            .set_spatial_ref(&SpatialRef::from_epsg(32633).unwrap())
+            .unwrap();
+        dataset
+            .set_geo_transform(&[500_000.0, 10.0, 0.0, 5_000_000.0, 0.0, -10.0])
+            .unwrap();
+        let mut band = dataset.rasterband(1).unwrap();
+        band.set_scale(1e-4).unwrap();
+        band.set_offset(0.0).unwrap();
+        band.set_no_data_value(Some(-32768.0)).unwrap();
+        let mut buffer = gdal::raster::Buffer::new((4, 4), vec![5000i16; 16]);
+        band.write((0, 0), (4, 4), &mut buffer).unwrap();
+        let provenance = Provenance::new(&[], "(B08 - B04) / (B08 + B04)".to_owned());
+        args.finish(dataset, path, &provenance).unwrap();
+
+        let store = Dataset::open(path).unwrap();
+        assert_eq!(
+            store.metadata_item("Conventions", "").as_deref(),
+            Some("CF-1.8")
+        );
+        let band = store.rasterband(1).unwrap();
+        assert_eq!(
+            band.metadata_item("grid_mapping", "").as_deref(),
+            Some(GRID_MAPPING)
+        );
+        assert_eq!(band.scale(), Some(1e-4));
+        assert_eq!(band.no_data_value(), Some(-32768.0));
+        assert_eq!(store.spatial_ref().unwrap().auth_code().unwrap(), 32633);
+        delete_dataset(path).unwrap();
+    }
+}
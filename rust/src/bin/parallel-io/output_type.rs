@@ -0,0 +1,316 @@
+use clap::ValueEnum;
+use gdal::{
+    errors,
+    raster::{Buffer, GdalType, RasterBand, RasterCreationOptions},
+    Dataset, Driver,
+};
+
+use crate::gdal_ext::TypedBuffer;
+
+/// Pixel type of the output raster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputType {
+    #[value(name = "float32")]
+    Float32,
+    /// Half-precision float, stored as a `Float32` band with `NBITS=16`.
+    #[value(name = "float16")]
+    Float16,
+    #[value(name = "int16")]
+    Int16,
+    #[value(name = "uint16")]
+    UInt16,
+    #[value(name = "uint8")]
+    UInt8,
+}
+
+impl OutputType {
+    /// Returns `true` if index values are stored as-is, without scale and offset.
+    #[must_use]
+    pub fn is_float(self) -> bool {
+        matches!(self, Self::Float32 | Self::Float16)
+    }
+
+    /// Value used to mark pixels without a valid index.
+    ///
+    /// For integer types this is the extreme of the type range, so it can never collide with an
+    /// encoded value.
+    #[must_use]
+    pub fn nodata(self) -> f64 {
+        match self {
+            Self::Float32 | Self::Float16 => -999.0,
+            Self::Int16 => i16::MIN as f64,
+            Self::UInt16 => u16::MAX as f64,
+            Self::UInt8 => u8::MAX as f64,
+        }
+    }
+
+    /// Range of codes available for valid values, excluding [`OutputType::nodata`].
+    #[must_use]
+    pub fn code_range(self) -> (f64, f64) {
+        match self {
+            Self::Float32 => (f32::MIN as f64, f32::MAX as f64),
+            Self::Float16 => (-65504.0, 65504.0),
+            Self::Int16 => (i16::MIN as f64 + 1.0, i16::MAX as f64),
+            Self::UInt16 => (0.0, u16::MAX as f64 - 1.0),
+            Self::UInt8 => (0.0, u8::MAX as f64 - 1.0),
+        }
+    }
+
+    /// Largest whole number stored exactly with scale 1, for bands of counts and indices.
+    #[must_use]
+    pub fn max_integer(self) -> f64 {
+        match self {
+            Self::Float32 => 16_777_216.0,
+            Self::Float16 => 2048.0,
+            Self::Int16 | Self::UInt16 | Self::UInt8 => self.code_range().1,
+        }
+    }
+
+    /// Creates a dataset with bands of this type.
+    pub fn create(
+        self,
+        driver: &Driver,
+        path: &str,
+        size: (usize, usize),
+        bands: usize,
+        options: &RasterCreationOptions,
+    ) -> errors::Result<Dataset> {
+        let (width, height) = size;
+        match self {
+            Self::Float32 => driver
+                .create_with_band_type_with_options::<f32, _>(path, width, height, bands, options),
+            Self::Float16 => {
+                let mut options = options.clone();
+                options.set_name_value("NBITS", "16")?;
+                driver.create_with_band_type_with_options::<f32, _>(
+                    path, width, height, bands, &options,
+                )
+            }
+            Self::Int16 => driver
+                .create_with_band_type_with_options::<i16, _>(path, width, height, bands, options),
+            Self::UInt16 => driver
+                .create_with_band_type_with_options::<u16, _>(path, width, height, bands, options),
+            Self::UInt8 => driver
+                .create_with_band_type_with_options::<u8, _>(path, width, height, bands, options),
+        }
+    }
+}
+
+/// Mapping between index values and the codes stored in the output raster, following the GDAL
+/// convention `value = code * scale + offset`.
+#[derive(Clone, Copy, Debug)]
+pub struct Encoding {
+    pub output_type: OutputType,
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl Encoding {
+    /// Picks a scale and offset so that `range` fits in the codes of `output_type`.
+    ///
+    /// The automatic scale is the smallest power of ten that covers the range, so that stored
+    /// codes stay readable (e.g. NDVI in `int16` is stored as `ndvi * 10000`). An explicit
+    /// `scale` or `offset` takes precedence over the computed one.
+    pub fn new(
+        output_type: OutputType,
+        range: (f64, f64),
+        scale: Option<f64>,
+        offset: Option<f64>,
+    ) -> anyhow::Result<Self> {
+        if output_type.is_float() {
+            let scale = scale.unwrap_or(1.0);
+            anyhow::ensure!(
+                scale.is_finite() && scale > 0.0,
+                "invalid scale {scale} for output type {output_type:?}"
+            );
+            return Ok(Self {
+                output_type,
+                scale,
+                offset: offset.unwrap_or(0.0),
+            });
+        }
+
+        let (min, max) = range;
+        let (code_min, code_max) = output_type.code_range();
+        let scale = match scale {
+            Some(scale) => scale,
+            None => {
+                let step = (max - min) / (code_max - code_min);
+                10f64.powf(step.log10().ceil())
+            }
+        };
+        anyhow::ensure!(
+            scale.is_finite() && scale > 0.0,
+            "invalid scale {scale} for output type {output_type:?}"
+        );
+
+        let offset = match offset {
+            Some(offset) => offset,
+            None if min / scale >= code_min && max / scale <= code_max => 0.0,
+            None => min - code_min * scale,
+        };
+
+        let encoding = Self {
+            output_type,
+            scale,
+            offset,
+        };
+        let (lo, hi) = (encoding.code(min), encoding.code(max));
+        anyhow::ensure!(
+            lo >= code_min && hi <= code_max,
+            "value range {min}..{max} does not fit in {output_type:?} with scale {scale} and offset {offset}"
+        );
+        Ok(encoding)
+    }
+
+    #[must_use]
+    pub fn nodata(&self) -> f64 {
+        self.output_type.nodata()
+    }
+
+    fn code(&self, value: f64) -> f64 {
+        ((value - self.offset) / self.scale).round_ties_even()
+    }
+
+    /// Converts index values to output codes, mapping NaN to nodata.
+    #[must_use]
+    pub fn encode(&self, shape: (usize, usize), values: &[f32]) -> TypedBuffer {
+        fn encode_int<T: Copy + GdalType>(
+            encoding: &Encoding,
+            shape: (usize, usize),
+            values: &[f32],
+            convert: impl Fn(f64) -> T,
+        ) -> Buffer<T> {
+            let (code_min, code_max) = encoding.output_type.code_range();
+            let nodata = convert(encoding.nodata());
+            let data = values
+                .iter()
+                .map(|&v| {
+                    if v.is_nan() {
+                        nodata
+                    } else {
+                        convert(encoding.code(v as f64).clamp(code_min, code_max))
+                    }
+                })
+                .collect();
+            Buffer::new(shape, data)
+        }
+
+        match self.output_type {
+            OutputType::Float32 | OutputType::Float16 => {
+                let nodata = self.nodata() as f32;
+                let (scale, offset) = (self.scale as f32, self.offset as f32);
+                let data = values
+                    .iter()
+                    .map(|&v| {
+                        if v.is_nan() {
+                            nodata
+                        } else {
+                            (v - offset) / scale
+                        }
+                    })
+                    .collect();
+                TypedBuffer::F32(Buffer::new(shape, data))
+            }
+            OutputType::Int16 => TypedBuffer::I16(encode_int(self, shape, values, |c| c as i16)),
+            OutputType::UInt16 => TypedBuffer::U16(encode_int(self, shape, values, |c| c as u16)),
+            OutputType::UInt8 => TypedBuffer::U8(encode_int(self, shape, values, |c| c as u8)),
+        }
+    }
+
+    /// Writes nodata, scale and offset to `band` through the standard band API, so that readers
+    /// such as QGIS and `gdalinfo -stats` report true index values.
+    pub fn apply(&self, band: &mut RasterBand) -> errors::Result<()> {
+        band.set_no_data_value(Some(self.nodata()))?;
+        band.set_scale(self.scale)?;
+        band.set_offset(self.offset)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NDVI_RANGE: (f64, f64) = (-1.0, 1.0);
+
+    #[test]
+    fn int16_uses_a_power_of_ten_scale_without_offset() {
+        let encoding = Encoding::new(OutputType::Int16, NDVI_RANGE, None, None).unwrap();
+        assert_eq!(encoding.scale, 1e-4);
+        assert_eq!(encoding.offset, 0.0);
+
+        let TypedBuffer::I16(buffer) = encoding.encode((3, 1), &[-1.0, 0.25, f32::NAN]) else {
+            panic!("expected an int16 buffer");
+        };
+        assert_eq!(buffer.data(), [-10000, 2500, i16::MIN]);
+    }
+
+    #[test]
+    fn uint8_shifts_negative_values_with_an_offset() {
+        let encoding = Encoding::new(OutputType::UInt8, NDVI_RANGE, None, None).unwrap();
+        assert_eq!(encoding.scale, 0.01);
+        assert_eq!(encoding.offset, -1.0);
+
+        let TypedBuffer::U8(buffer) = encoding.encode((4, 1), &[-1.0, 0.5, 1.0, f32::NAN]) else {
+            panic!("expected a uint8 buffer");
+        };
+        assert_eq!(buffer.data(), [0, 150, 200, u8::MAX]);
+    }
+
+    #[test]
+    fn codes_outside_the_range_are_clamped_short_of_nodata() {
+        let encoding = Encoding::new(OutputType::UInt8, NDVI_RANGE, None, None).unwrap();
+        let TypedBuffer::U8(buffer) = encoding.encode((2, 1), &[-5.0, 5.0]) else {
+            panic!("expected a uint8 buffer");
+        };
+        assert_eq!(buffer.data(), [0, u8::MAX - 1]);
+    }
+
+    #[test]
+    fn explicit_scale_and_offset_take_precedence() {
+        let encoding =
+            Encoding::new(OutputType::Int16, NDVI_RANGE, Some(0.001), Some(0.5)).unwrap();
+        assert_eq!((encoding.scale, encoding.offset), (0.001, 0.5));
+    }
+
+    #[test]
+    fn float_types_store_values_as_they_are() {
+        let encoding = Encoding::new(OutputType::Float32, NDVI_RANGE, None, None).unwrap();
+        let TypedBuffer::F32(buffer) = encoding.encode((2, 1), &[0.25, f32::NAN]) else {
+            panic!("expected a float32 buffer");
+        };
+        assert_eq!(buffer.data(), [0.25, -999.0]);
+    }
+
+    #[test]
+    fn invalid_scales_are_rejected() {
+        for output_type in [OutputType::Float32, OutputType::Float16, OutputType::Int16] {
+            for scale in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+                assert!(Encoding::new(output_type, NDVI_RANGE, Some(scale), None).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn max_integer_leaves_room_for_nodata() {
+        assert_eq!(OutputType::UInt8.max_integer(), 254.0);
+        assert_eq!(OutputType::Int16.max_integer(), 32767.0);
+        assert_eq!(OutputType::Float16.max_integer(), 2048.0);
+
+        let plain = Encoding {
+            output_type: OutputType::UInt8,
+            scale: 1.0,
+            offset: 0.0,
+        };
+        let TypedBuffer::U8(buffer) = plain.encode((2, 1), &[0.0, 254.0]) else {
+            panic!("expected a uint8 buffer");
+        };
+        assert_eq!(buffer.data(), [0, 254]);
+    }
+
+    #[test]
+    fn ranges_that_do_not_fit_are_rejected() {
+        assert!(Encoding::new(OutputType::UInt8, NDVI_RANGE, Some(0.001), None).is_err());
+    }
+}
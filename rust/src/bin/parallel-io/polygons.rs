@@ -0,0 +1,309 @@
+use anyhow::{ensure, Result};
+use gdal::{
+    raster::{rasterize, RasterizeOptions},
+    spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef},
+    vector::{Geometry, LayerAccess as _},
+    Dataset, DriverManager, GeoTransform,
+};
+
+use crate::window::PixelWindow;
+
+/// Polygons read from an OGR layer, in the CRS of the raster they are rasterised on.
+///
+/// Features without a geometry are skipped, so polygon `i` is the `i`-th feature of the layer
+/// that has a geometry.
+pub struct PolygonLayer {
+    geometries: Vec<Geometry>,
+    /// Extents of `geometries`, as `[min_x, min_y, max_x, max_y]`.
+    extents: Vec<[f64; 4]>,
+}
+
+impl PolygonLayer {
+    /// Reads all geometries of `layer` (the first layer if `None`) in the vector dataset at
+    /// `path`, transforming them to `target`.
+    pub fn open(path: &str, layer: Option<&str>, target: &SpatialRef) -> Result<Self> {
+        let dataset = Dataset::open(path)?;
+        let mut layer = match layer {
+            Some(name) => dataset.layer_by_name(name)?,
+            None => dataset.layer(0)?,
+        };
+
+        let transform = match layer.spatial_ref() {
+            Some(mut source) => {
+                source.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                let mut target = target.clone();
+                target.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                Some(CoordTransform::new(&source, &target)?)
+            }
+            None => None,
+        };
+
+        let mut geometries = Vec::new();
+        for feature in layer.features() {
+            let Some(geometry) = feature.geometry() else {
+                continue;
+            };
+            let geometry = match &transform {
+                Some(transform) => geometry.transform(transform)?,
+                None => geometry.clone(),
+            };
+            geometries.push(geometry);
+        }
+        ensure!(!geometries.is_empty(), "no geometries in {path}");
+        Ok(Self::from_geometries(geometries))
+    }
+
+    /// Polygons already in the CRS of the raster.
+    #[must_use]
+    pub fn from_geometries(geometries: Vec<Geometry>) -> Self {
+        let extents = geometries
+            .iter()
+            .map(|geometry| {
+                let envelope = geometry.envelope();
+                [envelope.MinX, envelope.MinY, envelope.MaxX, envelope.MaxY]
+            })
+            .collect();
+        Self {
+            geometries,
+            extents,
+        }
+    }
+
+    /// Extent of all polygons, as `[min_x, min_y, max_x, max_y]`.
+    #[must_use]
+    pub fn bounds(&self) -> [f64; 4] {
+        self.extents.iter().fold(
+            [
+                f64::INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::NEG_INFINITY,
+            ],
+            |acc, e| {
+                [
+                    acc[0].min(e[0]),
+                    acc[1].min(e[1]),
+                    acc[2].max(e[2]),
+                    acc[3].max(e[3]),
+                ]
+            },
+        )
+    }
+
+    /// Indices of the polygons whose extent overlaps the grid of a block.
+    fn overlapping(
+        &self,
+        geo_transform: &GeoTransform,
+        size: (usize, usize),
+    ) -> impl Iterator<Item = usize> + '_ {
+        let (x0, y0) = (geo_transform[0], geo_transform[3]);
+        let (x1, y1) = (
+            x0 + size.0 as f64 * geo_transform[1] + size.1 as f64 * geo_transform[2],
+            y0 + size.0 as f64 * geo_transform[4] + size.1 as f64 * geo_transform[5],
+        );
+        let block = [x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1)];
+        self.extents
+            .iter()
+            .enumerate()
+            .filter(move |(_, e)| {
+                e[0] <= block[2] && e[2] >= block[0] && e[1] <= block[3] && e[3] >= block[1]
+            })
+            .map(|(i, _)| i)
+    }
+
+    /// Rasterises polygons `indices` on the grid of a block, returning 1 for pixels inside any
+    /// of them and 0 outside.
+    fn rasterize(
+        &self,
+        indices: &[usize],
+        geo_transform: &GeoTransform,
+        size: (usize, usize),
+    ) -> Result<Vec<u32>> {
+        if indices.is_empty() {
+            return Ok(vec![0; size.0 * size.1]);
+        }
+        let geometries = indices
+            .iter()
+            .map(|&i| self.geometries[i].clone())
+            .collect::<Vec<_>>();
+
+        let driver = DriverManager::get_driver_by_name("MEM")?;
+        let mut raster = driver.create_with_band_type::<u32, _>("", size.0, size.1, 1)?;
+        raster.set_geo_transform(geo_transform)?;
+        rasterize(
+            &mut raster,
+            &[1],
+            &geometries,
+            &vec![1.0; geometries.len()],
+            Some(RasterizeOptions::default()),
+        )?;
+
+        let buffer = raster.rasterband(1)?.read_band_as::<u32>()?;
+        Ok(buffer.into_shape_and_vec().1)
+    }
+
+    /// Rasterises the polygons on the grid of a block, returning 1 for pixels inside and 0
+    /// outside.
+    pub fn mask(&self, geo_transform: &GeoTransform, size: (usize, usize)) -> Result<Vec<u32>> {
+        let indices = self.overlapping(geo_transform, size).collect::<Vec<_>>();
+        self.rasterize(&indices, geo_transform, size)
+    }
+
+    /// Rasterises each polygon overlapping the grid of a block on its own, within its extent, so
+    /// that pixels shared by overlapping polygons count for all of them.
+    pub fn coverages(
+        &self,
+        geo_transform: &GeoTransform,
+        size: (usize, usize),
+    ) -> Result<Vec<Coverage>> {
+        let mut coverages = Vec::new();
+        for polygon in self.overlapping(geo_transform, size) {
+            // Extents touching the block only along an edge cover no pixel.
+            let Ok(window) = PixelWindow::from_bounds(geo_transform, self.extents[polygon], size)
+            else {
+                continue;
+            };
+            let inside = self.rasterize(
+                &[polygon],
+                &window.geo_transform(geo_transform),
+                window.size,
+            )?;
+            if inside.contains(&1) {
+                coverages.push(Coverage {
+                    polygon,
+                    window,
+                    mask: inside.into_iter().map(|v| v != 0).collect(),
+                });
+            }
+        }
+        Ok(coverages)
+    }
+
+    /// Number of polygons.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.geometries.len()
+    }
+
+    /// Sets the values of a block that fall outside the polygons to NaN (nodata).
+    pub fn clip(
+        &self,
+        geo_transform: &GeoTransform,
+        size: (usize, usize),
+        values: &mut [f32],
+    ) -> Result<()> {
+        let mask = self.mask(geo_transform, size)?;
+        for (value, inside) in values.iter_mut().zip(mask) {
+            if inside == 0 {
+                *value = f32::NAN;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pixels of a block covered by one polygon.
+pub struct Coverage {
+    pub polygon: usize,
+    /// Extent of the polygon within the block, in pixels of the block.
+    pub window: PixelWindow,
+    /// Whether each pixel of `window` is inside the polygon, row by row.
+    pub mask: Vec<bool>,
+}
+
+impl Coverage {
+    /// Values of a block `width` pixels wide that are inside the polygon.
+    pub fn values<'a>(&'a self, values: &'a [f32], width: usize) -> impl Iterator<Item = f32> + 'a {
+        let (x0, y0) = self.window.offset;
+        let row_width = self.window.size.0;
+        self.mask
+            .chunks(row_width)
+            .enumerate()
+            .flat_map(move |(row, inside)| {
+                let start = (y0 + row) * width + x0;
+                values[start..start + row_width]
+                    .iter()
+                    .zip(inside)
+                    .filter(|(_, &inside)| inside)
+                    .map(|(&value, _)| value)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 x 10 grid of unit pixels covering `(0, 0)` to `(10, 10)`.
+    const GEO_TRANSFORM: GeoTransform = [0.0, 1.0, 0.0, 10.0, 0.0, -1.0];
+
+    fn overlapping_squares() -> PolygonLayer {
+        PolygonLayer::from_geometries(
+            [
+                "POLYGON ((0 0, 6 0, 6 6, 0 6, 0 0))",
+                "POLYGON ((4 4, 10 4, 10 10, 4 10, 4 4))",
+            ]
+            .into_iter()
+            .map(|wkt| Geometry::from_wkt(wkt).unwrap())
+            .collect(),
+        )
+    }
+
+    #[test]
+    fn overlapping_polygons_both_cover_shared_pixels() {
+        let layer = overlapping_squares();
+        let values = (0..100).map(|v| v as f32).collect::<Vec<_>>();
+        let coverages = layer.coverages(&GEO_TRANSFORM, (10, 10)).unwrap();
+
+        let counts = coverages
+            .iter()
+            .map(|coverage| (coverage.polygon, coverage.values(&values, 10).count()))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [(0, 36), (1, 36)]);
+
+        // The 2 x 2 pixels in rows 4 and 5, columns 4 and 5 belong to both squares.
+        let shared = [44.0, 45.0, 54.0, 55.0];
+        for coverage in &coverages {
+            let inside = coverage.values(&values, 10).collect::<Vec<_>>();
+            assert!(shared.iter().all(|value| inside.contains(value)));
+        }
+
+        let mask = layer.mask(&GEO_TRANSFORM, (10, 10)).unwrap();
+        assert_eq!(
+            mask.iter().filter(|&&inside| inside == 1).count(),
+            36 + 36 - 4
+        );
+    }
+
+    #[test]
+    fn coverages_are_clipped_to_the_block() {
+        let layer = overlapping_squares();
+        // Right half of the grid.
+        let geo_transform = [5.0, 1.0, 0.0, 10.0, 0.0, -1.0];
+        let values = vec![1.0; 50];
+        let counts = layer
+            .coverages(&geo_transform, (5, 10))
+            .unwrap()
+            .iter()
+            .map(|coverage| (coverage.polygon, coverage.values(&values, 5).count()))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [(0, 6), (1, 30)]);
+    }
+
+    #[test]
+    fn coverage_values_follow_the_window_and_mask() {
+        let coverage = Coverage {
+            polygon: 0,
+            window: PixelWindow {
+                offset: (1, 1),
+                size: (2, 2),
+            },
+            mask: vec![true, false, true, true],
+        };
+        let values = (0..12).map(|v| v as f32).collect::<Vec<_>>();
+        assert_eq!(
+            coverage.values(&values, 4).collect::<Vec<_>>(),
+            [5.0, 9.0, 10.0]
+        );
+    }
+}
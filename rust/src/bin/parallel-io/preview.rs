@@ -0,0 +1,134 @@
+use std::time::Instant;
+
+use anyhow::{ensure, Result};
+use gdal::{
+    raster::{Buffer, ResampleAlg},
+    Dataset, GeoTransform,
+};
+use rayon::iter::{IntoParallelRefIterator as _, ParallelIterator as _};
+
+use crate::{
+    mosaic::{self, OverlapRule},
+    provenance::Provenance,
+    render::{ColorRamp, RenderMode, Renderer},
+    sensor::Calibration,
+    stats::Statistics,
+    window::PixelWindow,
+    NDVI_RANGE,
+};
+
+struct DecimatedBand {
+    buffer: Buffer<u16>,
+    projection: String,
+    geo_transform: GeoTransform,
+}
+
+/// Reads `window` of band 1 of `path` at 1/`factor` resolution.
+///
+/// Asking GDAL for a buffer smaller than the window lets the JP2 driver decode a lower resolution
+/// level (or the GeoTIFF driver read an overview), so only a fraction of the data is decoded.
+/// Pixels are picked rather than averaged, since averaging would mix the DN 0 of edges and gaps
+/// into valid pixels of bands without a nodata value, where the full run masks them.
+fn read_decimated(path: &str, window: PixelWindow, factor: usize) -> Result<DecimatedBand> {
+    let dataset = Dataset::open(path)?;
+    let band = dataset.rasterband(1)?;
+    let size = window.size;
+    let shape = (size.0.div_ceil(factor), size.1.div_ceil(factor));
+    let buffer = band.read_as::<u16>(
+        (window.offset.0 as isize, window.offset.1 as isize),
+        size,
+        shape,
+        Some(ResampleAlg::NearestNeighbour),
+    )?;
+
+    let mut geo_transform = window.geo_transform(&dataset.geo_transform()?);
+    let (sx, sy) = (
+        size.0 as f64 / shape.0 as f64,
+        size.1 as f64 / shape.1 as f64,
+    );
+    geo_transform[1] *= sx;
+    geo_transform[2] *= sy;
+    geo_transform[4] *= sx;
+    geo_transform[5] *= sy;
+
+    Ok(DecimatedBand {
+        buffer,
+        projection: dataset.projection(),
+        geo_transform,
+    })
+}
+
+/// Computes a quick-look NDVI of `window` at 1/`factor` resolution, prints its statistics and
+/// renders it to `output`.
+///
+/// `bands` holds the NIR and red tiles, which must already share a grid, and `calibration` the
+/// calibration of each pair of tiles. `provenance` is recorded in the quick-look.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    bands: &[Vec<String>],
+    calibration: &[[Calibration; 2]],
+    overlap: OverlapRule,
+    window: PixelWindow,
+    factor: usize,
+    output: &str,
+    ramp: ColorRamp,
+    provenance: &Provenance,
+) -> Result<()> {
+    ensure!(factor >= 1, "preview factor must be at least 1");
+    let start = Instant::now();
+
+    let tiles = bands[0].len();
+    let decimated = bands
+        .concat()
+        .par_iter()
+        .map(|path| read_decimated(path, window, factor))
+        .collect::<Result<Vec<_>>>()?;
+    let first = &decimated[0];
+    ensure!(
+        decimated
+            .iter()
+            .all(|band| band.buffer.shape() == first.buffer.shape()),
+        "input bands have different sizes"
+    );
+
+    let shape = first.buffer.shape();
+    let data = decimated
+        .iter()
+        .map(|band| band.buffer.data())
+        .collect::<Vec<_>>();
+    let mut ndvi = Vec::with_capacity(shape.0 * shape.1);
+    mosaic::compute_ndvi(
+        &data[..tiles],
+        &data[tiles..],
+        calibration,
+        overlap,
+        &mut Vec::new(),
+        &mut ndvi,
+    );
+
+    let mut stats = Statistics::new(None);
+    stats.add_block(&ndvi);
+
+    let mut renderer = Renderer::create(
+        output,
+        RenderMode::Rgba,
+        ramp,
+        NDVI_RANGE,
+        shape,
+        &first.projection,
+        &first.geo_transform,
+        provenance,
+    )?;
+    renderer.write_block((0, 0), shape, &ndvi)?;
+    renderer.finish()?;
+
+    println!(
+        "Preview {}x{} (1/{factor}) written to {output} in {:.3}s",
+        shape.0,
+        shape.1,
+        start.elapsed().as_secs_f64()
+    );
+    println!("NDVI {stats}");
+
+    Ok(())
+}
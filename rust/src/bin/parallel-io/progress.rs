@@ -0,0 +1,343 @@
+//! Progress reporting and cooperative cancellation of block processing.
+//!
+//! [`ParallelBlockReader`](crate::block_reader::ParallelBlockReader) reports blocks done out of
+//! the blocks requested so far, bytes read and an estimated time left to a [`ProgressHandler`],
+//! and stops reading once its [`CancelToken`] is cancelled. The commands draw the updates as a
+//! progress bar with [`bar`] and cancel on Ctrl-C with [`interrupt_token`], then delete their
+//! unfinished outputs.
+
+use std::{
+    io::{self, IsTerminal as _, Write as _},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// Flag shared by the code that cancels a run and the workers that check it between blocks.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// State of a run, as given to a [`ProgressHandler`].
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressUpdate {
+    pub blocks_done: usize,
+    /// Blocks requested so far, which is all of them once the processing loop has queued them.
+    pub total_blocks: usize,
+    pub bytes_read: u64,
+    pub elapsed: Duration,
+    /// Time left at the average rate so far, once a block is done.
+    pub eta: Option<Duration>,
+    /// Whether this is the last update, sent when the reader is joined.
+    pub finished: bool,
+}
+
+impl ProgressUpdate {
+    #[must_use]
+    pub fn fraction(&self) -> f64 {
+        if self.total_blocks == 0 {
+            0.0
+        } else {
+            self.blocks_done as f64 / self.total_blocks as f64
+        }
+    }
+}
+
+/// Called from the worker threads as blocks complete, so it must return quickly.
+pub type ProgressHandler = Box<dyn Fn(&ProgressUpdate) + Send + Sync>;
+
+/// Counters of a run, updated by the workers.
+pub struct Progress {
+    total_blocks: AtomicUsize,
+    blocks_done: AtomicUsize,
+    bytes_read: AtomicU64,
+    start: Instant,
+    handler: ProgressHandler,
+}
+
+impl Progress {
+    #[must_use]
+    pub fn new(handler: ProgressHandler) -> Self {
+        Self {
+            total_blocks: AtomicUsize::new(0),
+            blocks_done: AtomicUsize::new(0),
+            bytes_read: AtomicU64::new(0),
+            start: Instant::now(),
+            handler,
+        }
+    }
+
+    pub fn block_requested(&self) {
+        self.total_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn block_done(&self) {
+        self.blocks_done.fetch_add(1, Ordering::Relaxed);
+        self.report(false);
+    }
+
+    pub fn finish(&self) {
+        self.report(true);
+    }
+
+    fn report(&self, finished: bool) {
+        let blocks_done = self.blocks_done.load(Ordering::Relaxed);
+        let total_blocks = self.total_blocks.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed();
+        let eta = (blocks_done > 0).then(|| {
+            elapsed.mul_f64(total_blocks.saturating_sub(blocks_done) as f64 / blocks_done as f64)
+        });
+        (self.handler)(&ProgressUpdate {
+            blocks_done,
+            total_blocks,
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            elapsed,
+            eta,
+            finished,
+        });
+    }
+}
+
+/// Shortest time between two redraws of the progress bar.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+const BAR_WIDTH: usize = 30;
+
+/// Handler drawing a progress bar on standard error, or doing nothing if that is not a terminal.
+#[must_use]
+pub fn bar() -> ProgressHandler {
+    if !io::stderr().is_terminal() {
+        return Box::new(|_| {});
+    }
+    let last_draw = Mutex::new(None::<Instant>);
+    Box::new(move |update| {
+        {
+            let mut last_draw = last_draw.lock();
+            if !update.finished
+                && last_draw.is_some_and(|instant| instant.elapsed() < REDRAW_INTERVAL)
+            {
+                return;
+            }
+            *last_draw = Some(Instant::now());
+        }
+
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r{}\x1b[K", status_line(update));
+        if update.finished {
+            let _ = writeln!(stderr);
+        }
+        let _ = stderr.flush();
+    })
+}
+
+/// Text of the progress bar for `update`: the bar, percentage, blocks, data read and the time
+/// left, or the total time once finished.
+fn status_line(update: &ProgressUpdate) -> String {
+    let fraction = update.fraction();
+    let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+    let eta = match update.eta {
+        Some(_) if update.finished => format!("in {}", format_duration(update.elapsed)),
+        Some(eta) => format!("ETA {}", format_duration(eta)),
+        None => "ETA --:--".to_owned(),
+    };
+    format!(
+        "[{}{}] {:3.0}% {}/{} blocks, {:.1} MiB read, {eta}",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        fraction * 100.0,
+        update.blocks_done,
+        update.total_blocks,
+        update.bytes_read as f64 / (1024.0 * 1024.0),
+    )
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Token cancelled by the first Ctrl-C.
+static INTERRUPT: OnceLock<CancelToken> = OnceLock::new();
+
+extern "C" fn interrupt(_signal: libc::c_int) {
+    if let Some(token) = INTERRUPT.get() {
+        token.cancel();
+    }
+    // A second Ctrl-C kills the process, in case a block takes long to finish.
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+}
+
+/// Token cancelled when the user presses Ctrl-C, which then stops reading blocks and lets the
+/// command clean up instead of killing it with its outputs half-written.
+pub fn interrupt_token() -> CancelToken {
+    INTERRUPT
+        .get_or_init(|| {
+            let handler: extern "C" fn(libc::c_int) = interrupt;
+            unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+            CancelToken::default()
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use gdal::{
+        raster::{Buffer, RasterCreationOptions},
+        DriverManager,
+    };
+
+    use super::*;
+    use crate::block_reader::ParallelBlockReader;
+
+    fn update(blocks_done: usize, total_blocks: usize, eta: Option<Duration>) -> ProgressUpdate {
+        ProgressUpdate {
+            blocks_done,
+            total_blocks,
+            bytes_read: 3 * 1024 * 1024 / 2,
+            elapsed: Duration::from_secs(90),
+            eta,
+            finished: false,
+        }
+    }
+
+    #[test]
+    fn fraction_of_blocks_done() {
+        assert_eq!(update(0, 0, None).fraction(), 0.0);
+        assert_eq!(update(0, 8, None).fraction(), 0.0);
+        assert_eq!(update(2, 8, None).fraction(), 0.25);
+        assert_eq!(update(8, 8, None).fraction(), 1.0);
+    }
+
+    #[test]
+    fn eta_follows_the_average_rate() {
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let progress = {
+            let updates = updates.clone();
+            Progress::new(Box::new(move |update| updates.lock().push(*update)))
+        };
+        for _ in 0..4 {
+            progress.block_requested();
+        }
+        progress.bytes_read(1024);
+        progress.block_done();
+        progress.block_done();
+        progress.block_done();
+        progress.finish();
+
+        let updates = updates.lock();
+        assert_eq!(updates.len(), 4);
+        // 1 of 4 blocks done: 3 more take 3 times as long.
+        assert_eq!(updates[0].eta, Some(updates[0].elapsed.mul_f64(3.0)));
+        // 3 of 4: 1 more takes a third as long.
+        assert_eq!(updates[2].eta, Some(updates[2].elapsed.mul_f64(1.0 / 3.0)));
+        assert_eq!(updates[2].fraction(), 0.75);
+        assert_eq!(updates[3].bytes_read, 1024);
+        assert!(updates[3].finished && !updates[2].finished);
+    }
+
+    #[test]
+    fn status_line_shows_the_eta_then_the_total_time() {
+        assert_eq!(
+            status_line(&update(0, 4, None)),
+            format!(
+                "[{}]   0% 0/4 blocks, 1.5 MiB read, ETA --:--",
+                " ".repeat(30)
+            )
+        );
+        let line = status_line(&update(1, 4, Some(Duration::from_secs(270))));
+        assert!(line.starts_with(&format!("[{}{}]  25%", "#".repeat(8), " ".repeat(22))));
+        assert!(line.ends_with("1/4 blocks, 1.5 MiB read, ETA 04:30"));
+        let finished = ProgressUpdate {
+            finished: true,
+            ..update(4, 4, Some(Duration::ZERO))
+        };
+        assert!(status_line(&finished).ends_with("100% 4/4 blocks, 1.5 MiB read, in 01:30"));
+    }
+
+    #[test]
+    fn durations_switch_to_hours_at_one_hour() {
+        assert_eq!(format_duration(Duration::ZERO), "00:00");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "00:59");
+        assert_eq!(format_duration(Duration::from_secs(60)), "01:00");
+        assert_eq!(format_duration(Duration::from_secs(3599)), "59:59");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "1:00:00");
+        assert_eq!(format_duration(Duration::from_secs(36_061)), "10:01:01");
+    }
+
+    #[test]
+    fn cancelling_stops_reading_blocks() {
+        // One row per block.
+        let path = "/vsimem/progress/cancel.tif";
+        let driver = DriverManager::get_driver_by_name("GTiff").unwrap();
+        let options = RasterCreationOptions::from_iter(["BLOCKYSIZE=1"]);
+        let dataset = driver
+            .create_with_band_type_with_options::<u16, _>(path, 4, 32, 1, &options)
+            .unwrap();
+        dataset
+            .rasterband(1)
+            .unwrap()
+            .write((0, 0), (4, 32), &mut Buffer::new((4, 32), vec![1u16; 128]))
+            .unwrap();
+        dataset.close().unwrap();
+
+        let cancel = CancelToken::default();
+        let last_update = Arc::new(Mutex::new(None));
+        let block_reader = {
+            let last_update = last_update.clone();
+            ParallelBlockReader::new(&[path.to_owned()], 1, None)
+                .unwrap()
+                .on_progress(Box::new(move |update| *last_update.lock() = Some(*update)))
+                .cancel_on(cancel.clone())
+        };
+        assert_eq!(block_reader.blocks.1.len(), 32);
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        for y in block_reader.blocks.1.clone() {
+            let (handled, cancel) = (handled.clone(), cancel.clone());
+            block_reader.run(
+                0,
+                y,
+                &[0],
+                Box::new(move |_, _, _| {
+                    handled.fetch_add(1, Ordering::Relaxed);
+                    cancel.cancel();
+                }),
+            );
+        }
+        block_reader.join();
+
+        // The single worker checks the token before each read, after the first handler ran.
+        assert_eq!(handled.load(Ordering::Relaxed), 1);
+        let last_update = last_update.lock().unwrap();
+        assert!(last_update.finished);
+        assert_eq!((last_update.blocks_done, last_update.total_blocks), (1, 32));
+        gdal::vsi::unlink_mem_file(path).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+//! Provenance of outputs: which build of the tool produced them, how, and from which inputs.
+//!
+//! Raster outputs carry it in the [`DOMAIN`] metadata domain, which [`Provenance::read`] reads
+//! back and `parallel-io provenance <file>` prints.
+
+use std::{
+    fmt,
+    io::Read as _,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, Result};
+use gdal::{Dataset, Metadata as _};
+use rayon::iter::{IntoParallelRefMutIterator as _, ParallelIterator as _};
+use sha2::{Digest as _, Sha256};
+
+use crate::{gdal_ext::VsiFile, sensor::Calibration};
+
+/// GDAL metadata domain holding the provenance of an output.
+pub const DOMAIN: &str = "PROVENANCE";
+
+/// Name of the tool, as recorded in provenance.
+pub const TOOL: &str = concat!(env!("CARGO_PKG_NAME"), " parallel-io");
+
+/// How an output was produced.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provenance {
+    pub tool: String,
+    pub version: String,
+    /// Commit the tool was built from, `-dirty` if the tree had local changes.
+    pub git_hash: String,
+    /// Command line, quoted so that it can be pasted into a shell.
+    pub command_line: String,
+    /// How output values derive from the inputs, e.g. `(nir - red) / (nir + red)`.
+    pub expression: String,
+    pub inputs: Vec<Input>,
+    /// Pixels masked out of the inputs, e.g. `SCL classes 0,1,3,8,9,10`.
+    pub mask: Option<String>,
+    /// Processing time, RFC 3339 in UTC.
+    pub timestamp: String,
+}
+
+/// Input file of an output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Input {
+    /// Path as given, before any warping to a common grid.
+    pub path: String,
+    /// SHA-256 of the file, as lowercase hexadecimal.
+    pub sha256: Option<String>,
+    /// Conversion of the digital numbers of the input to reflectance.
+    pub calibration: Option<Calibration>,
+}
+
+impl Provenance {
+    /// Provenance of an output computed now by this process from `inputs`.
+    #[must_use]
+    pub fn new(inputs: &[String], expression: String) -> Self {
+        Self {
+            tool: TOOL.to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            git_hash: option_env!("GIT_HASH").unwrap_or("unknown").to_owned(),
+            command_line: std::env::args()
+                .map(|arg| shell_quote(&arg))
+                .collect::<Vec<_>>()
+                .join(" "),
+            expression,
+            inputs: inputs
+                .iter()
+                .map(|path| Input {
+                    path: path.clone(),
+                    sha256: None,
+                    calibration: None,
+                })
+                .collect(),
+            mask: None,
+            timestamp: timestamp(SystemTime::now()),
+        }
+    }
+
+    /// Records the calibration of each input, in the order of the inputs.
+    #[must_use]
+    pub fn with_calibration(mut self, calibration: &[Calibration]) -> Self {
+        for (input, &calibration) in self.inputs.iter_mut().zip(calibration) {
+            input.calibration = Some(calibration);
+        }
+        self
+    }
+
+    #[must_use]
+    pub fn with_mask(mut self, mask: Option<String>) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    /// Computes the checksum of every input, reading them in parallel, if `args` asks for it.
+    pub fn with_checksums(mut self, args: &ChecksumArgs) -> Result<Self> {
+        if !args.checksums {
+            return Ok(self);
+        }
+        self.inputs.par_iter_mut().try_for_each(|input| {
+            input.sha256 = Some(
+                sha256_file(&input.path)
+                    .with_context(|| format!("computing the checksum of {}", input.path))?,
+            );
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(self)
+    }
+
+    /// Stores the provenance in the [`DOMAIN`] metadata domain of `dataset`.
+    pub fn write(&self, dataset: &mut Dataset) -> Result<()> {
+        let mut items = vec![
+            ("TOOL".to_owned(), self.tool.clone()),
+            ("VERSION".to_owned(), self.version.clone()),
+            ("GIT_HASH".to_owned(), self.git_hash.clone()),
+            ("COMMAND_LINE".to_owned(), self.command_line.clone()),
+            ("EXPRESSION".to_owned(), self.expression.clone()),
+            ("TIMESTAMP".to_owned(), self.timestamp.clone()),
+            ("INPUT_COUNT".to_owned(), self.inputs.len().to_string()),
+        ];
+        if let Some(mask) = &self.mask {
+            items.push(("MASK".to_owned(), mask.clone()));
+        }
+        for (i, input) in self.inputs.iter().enumerate() {
+            let key = |name: &str| format!("INPUT_{}_{name}", i + 1);
+            items.push((key("PATH"), input.path.clone()));
+            if let Some(sha256) = &input.sha256 {
+                items.push((key("SHA256"), sha256.clone()));
+            }
+            if let Some(calibration) = input.calibration {
+                items.push((key("SCALE"), calibration.scale.to_string()));
+                items.push((key("OFFSET"), calibration.offset.to_string()));
+                if let Some(nodata) = calibration.nodata {
+                    items.push((key("NODATA"), nodata.to_string()));
+                }
+            }
+        }
+        for (key, value) in items {
+            dataset.set_metadata_item(&key, &value, DOMAIN)?;
+        }
+        Ok(())
+    }
+
+    /// Provenance stored in `dataset`, if it has any.
+    pub fn read(dataset: &Dataset) -> Result<Option<Self>> {
+        let get = |key: &str| dataset.metadata_item(key, DOMAIN);
+        let Some(tool) = get("TOOL") else {
+            return Ok(None);
+        };
+
+        let count = get("INPUT_COUNT")
+            .unwrap_or_default()
+            .parse::<usize>()
+            .context("invalid INPUT_COUNT")?;
+        let mut inputs = Vec::with_capacity(count);
+        for i in 1..=count {
+            let get = |name: &str| get(&format!("INPUT_{i}_{name}"));
+            let number = |name: &str| {
+                get(name)
+                    .map(|value| {
+                        value
+                            .parse::<f64>()
+                            .with_context(|| format!("invalid INPUT_{i}_{name}"))
+                    })
+                    .transpose()
+            };
+            let calibration = match (number("SCALE")?, number("OFFSET")?) {
+                (Some(scale), Some(offset)) => Some(Calibration {
+                    scale,
+                    offset,
+                    nodata: number("NODATA")?,
+                }),
+                _ => None,
+            };
+            inputs.push(Input {
+                path: get("PATH").with_context(|| format!("INPUT_{i}_PATH missing"))?,
+                sha256: get("SHA256"),
+                calibration,
+            });
+        }
+
+        Ok(Some(Self {
+            tool,
+            version: get("VERSION").unwrap_or_default(),
+            git_hash: get("GIT_HASH").unwrap_or_default(),
+            command_line: get("COMMAND_LINE").unwrap_or_default(),
+            expression: get("EXPRESSION").unwrap_or_default(),
+            inputs,
+            mask: get("MASK"),
+            timestamp: get("TIMESTAMP").unwrap_or_default(),
+        }))
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "tool        {} {} ({})",
+            self.tool, self.version, self.git_hash
+        )?;
+        writeln!(f, "timestamp   {}", self.timestamp)?;
+        writeln!(f, "command     {}", self.command_line)?;
+        writeln!(f, "expression  {}", self.expression)?;
+        if let Some(mask) = &self.mask {
+            writeln!(f, "mask        {mask}")?;
+        }
+        for (i, input) in self.inputs.iter().enumerate() {
+            writeln!(f, "input {:<5} {}", i + 1, input.path)?;
+            if let Some(sha256) = &input.sha256 {
+                writeln!(f, "            sha256 {sha256}")?;
+            }
+            if let Some(calibration) = input.calibration {
+                write!(
+                    f,
+                    "            scale {}, offset {}",
+                    calibration.scale, calibration.offset
+                )?;
+                match calibration.nodata {
+                    Some(nodata) => writeln!(f, ", nodata {nodata}")?,
+                    None => writeln!(f)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Provenance options of the commands writing rasters.
+#[derive(clap::Args, Clone, Copy, Debug)]
+pub struct ChecksumArgs {
+    /// Record the SHA-256 of every input in the provenance; this reads each input in full once
+    /// more, downloading remote ones (`/vsis3/`, `/vsicurl/`) again
+    #[arg(long)]
+    checksums: bool,
+}
+
+/// Prints the provenance recorded in an output.
+#[derive(clap::Args)]
+pub struct ProvenanceArgs {
+    /// Raster written by parallel-io
+    path: String,
+}
+
+pub fn run(args: &ProvenanceArgs) -> Result<()> {
+    let dataset = Dataset::open(&args.path)?;
+    let provenance =
+        Provenance::read(&dataset)?.with_context(|| format!("{} has no provenance", args.path))?;
+    print!("{provenance}");
+    Ok(())
+}
+
+/// `arg` quoted for a POSIX shell if it has characters the shell would interpret.
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// RFC 3339 UTC timestamp of `time`, e.g. `2024-05-17T10:56:29Z`.
+#[must_use]
+pub fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs()) as i64;
+    let (days, time_of_day) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+
+    // Civil date from days since the epoch, in 400-year eras (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    )
+}
+
+/// SHA-256 of the file at `path`, which may be any path GDAL can open, as lowercase hexadecimal.
+fn sha256_file(path: &str) -> Result<String> {
+    let mut file = VsiFile::open(path)?;
+    let mut sha256 = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        sha256.update(&buffer[..read]);
+    }
+    Ok(sha256
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn at(seconds: u64) -> String {
+        timestamp(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    #[test]
+    fn timestamps_are_rfc3339_utc() {
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(1_715_943_389), "2024-05-17T10:56:29Z");
+        assert_eq!(at(1_709_251_199), "2024-02-29T23:59:59Z");
+        assert_eq!(at(951_868_800), "2000-03-01T00:00:00Z");
+        assert_eq!(at(4_133_937_600), "2100-12-31T12:00:00Z");
+        // Clocks set before the epoch.
+        assert_eq!(
+            timestamp(UNIX_EPOCH - Duration::from_secs(1)),
+            "1970-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn shell_quote_leaves_plain_arguments_alone() {
+        assert_eq!(shell_quote("--nir"), "--nir");
+        assert_eq!(
+            shell_quote("item=/vsis3/bucket/S2B_32TQM/B08.tif"),
+            "item=/vsis3/bucket/S2B_32TQM/B08.tif"
+        );
+        assert_eq!(shell_quote("-0.5,0.1"), "-0.5,0.1");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("$HOME/*.tif"), "'$HOME/*.tif'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn file_checksum_is_lowercase_hex() {
+        let path = std::env::temp_dir().join(format!("provenance-{}.txt", std::process::id()));
+        std::fs::write(&path, "abc").unwrap();
+        let sha256 = sha256_file(&path.to_string_lossy());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            sha256.unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn provenance_reads_back_from_the_written_file() {
+        let path = "/vsimem/provenance-test.tif";
+        let mut provenance = Provenance::new(
+            &["B08.jp2".to_owned(), "B04.jp2".to_owned()],
+            "(nir - red) / (nir + red)".to_owned(),
+        )
+        .with_calibration(&[
+            Calibration::SENTINEL2_L2A,
+            Calibration {
+                nodata: None,
+                ..Calibration::LANDSAT_C2_L2
+            },
+        ])
+        .with_mask(Some("SCL classes 0,1,3,8,9,10".to_owned()));
+        provenance.inputs[0].sha256 = Some("ba7816bf".to_owned());
+
+        let driver = gdal::DriverManager::get_driver_by_name("GTiff").unwrap();
+        let mut dataset = driver
+            .create_with_band_type::<u8, _>(path, 1, 1, 1)
+            .unwrap();
+        provenance.write(&mut dataset).unwrap();
+        dataset.close().unwrap();
+
+        let dataset = Dataset::open(path).unwrap();
+        assert_eq!(Provenance::read(&dataset).unwrap(), Some(provenance));
+
+        let plain = driver
+            .create_with_band_type::<u8, _>("/vsimem/plain.tif", 1, 1, 1)
+            .unwrap();
+        assert_eq!(Provenance::read(&plain).unwrap(), None);
+    }
+
+    #[test]
+    fn checksums_are_only_computed_on_request() {
+        let provenance = Provenance::new(&["/does/not/exist.tif".to_owned()], String::new());
+        let provenance = provenance
+            .with_checksums(&ChecksumArgs { checksums: false })
+            .unwrap();
+        assert_eq!(provenance.inputs[0].sha256, None);
+    }
+}
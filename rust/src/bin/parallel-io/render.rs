@@ -0,0 +1,422 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use gdal::{
+    raster::{
+        Buffer, ColorEntry, ColorInterpretation, ColorTable, PaletteInterpretation,
+        RasterCreationOptions,
+    },
+    Dataset, DriverManager, DriverType, GeoTransform,
+};
+
+use crate::{gdal_ext::delete_dataset, provenance::Provenance, vsi};
+
+/// ColorBrewer `RdYlGn`, from red (low) to green (high).
+const RDYLGN: [[u8; 3]; 11] = [
+    [0xa5, 0x00, 0x26],
+    [0xd7, 0x30, 0x27],
+    [0xf4, 0x6d, 0x43],
+    [0xfd, 0xae, 0x61],
+    [0xfe, 0xe0, 0x8b],
+    [0xff, 0xff, 0xbf],
+    [0xd9, 0xef, 0x8b],
+    [0xa6, 0xd9, 0x6a],
+    [0x66, 0xbd, 0x63],
+    [0x1a, 0x98, 0x50],
+    [0x00, 0x68, 0x37],
+];
+
+/// Matplotlib `viridis`, sampled at 10 evenly spaced points.
+const VIRIDIS: [[u8; 3]; 10] = [
+    [0x44, 0x01, 0x54],
+    [0x48, 0x28, 0x78],
+    [0x3e, 0x49, 0x89],
+    [0x31, 0x68, 0x8e],
+    [0x26, 0x82, 0x8e],
+    [0x1f, 0x9e, 0x89],
+    [0x35, 0xb7, 0x79],
+    [0x6e, 0xce, 0x58],
+    [0xb5, 0xde, 0x2b],
+    [0xfd, 0xe7, 0x25],
+];
+
+/// Piecewise linear mapping from index values to RGBA colours.
+#[derive(Clone, Debug)]
+pub struct ColorRamp {
+    stops: Vec<(f64, [u8; 4])>,
+}
+
+impl ColorRamp {
+    /// Parses a ramp name (`rdylgn`, `viridis`) or the path of a colour file.
+    ///
+    /// Named ramps are spread evenly over `range`. Colour files use the `gdaldem color-relief`
+    /// layout, one `value red green blue [alpha]` stop per line, where the value may also be a
+    /// percentage of `range`. `nv` lines are ignored, since nodata is always transparent.
+    pub fn parse(spec: &str, range: (f64, f64)) -> Result<Self> {
+        match spec.to_ascii_lowercase().as_str() {
+            "rdylgn" => Ok(Self::evenly_spaced(&RDYLGN, range)),
+            "viridis" => Ok(Self::evenly_spaced(&VIRIDIS, range)),
+            _ => Self::from_file(spec, range),
+        }
+    }
+
+    fn evenly_spaced(colors: &[[u8; 3]], range: (f64, f64)) -> Self {
+        let step = (range.1 - range.0) / (colors.len() - 1) as f64;
+        let stops = colors
+            .iter()
+            .enumerate()
+            .map(|(i, &[r, g, b])| (range.0 + i as f64 * step, [r, g, b, 255]))
+            .collect();
+        Self { stops }
+    }
+
+    fn from_file(path: &str, range: (f64, f64)) -> Result<Self> {
+        let text = vsi::read_to_string(path)
+            .with_context(|| format!("unknown colour ramp or unreadable file {path}"))?;
+        Self::from_text(&text, path, range)
+    }
+
+    /// Parses the colour file `text` read from `path`, which only appears in errors.
+    fn from_text(text: &str, path: &str, range: (f64, f64)) -> Result<Self> {
+        let mut stops = Vec::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|f| !f.is_empty())
+                .collect::<Vec<_>>();
+            // Lines of separators alone, e.g. `, ,`.
+            if fields.is_empty() || fields[0].eq_ignore_ascii_case("nv") {
+                continue;
+            }
+            if !(4..=5).contains(&fields.len()) {
+                bail!(
+                    "{path}:{}: expected `value red green blue [alpha]`",
+                    line_no + 1
+                );
+            }
+
+            let value = match fields[0].strip_suffix('%') {
+                Some(percent) => percent
+                    .parse::<f64>()
+                    .map(|percent| range.0 + percent / 100.0 * (range.1 - range.0)),
+                None => fields[0].parse::<f64>(),
+            }
+            .with_context(|| format!("{path}:{}", line_no + 1))?;
+            let mut color = [255; 4];
+            for (c, field) in color.iter_mut().zip(&fields[1..]) {
+                *c = field
+                    .parse()
+                    .with_context(|| format!("{path}:{}", line_no + 1))?;
+            }
+            stops.push((value, color));
+        }
+
+        if stops.is_empty() {
+            bail!("no colour stops in {path}");
+        }
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Self { stops })
+    }
+
+    /// Returns the colour of `value`, clamping values outside the ramp to its end colours.
+    #[must_use]
+    pub fn color(&self, value: f64) -> [u8; 4] {
+        let upper = self.stops.partition_point(|&(v, _)| v <= value);
+        if upper == 0 {
+            return self.stops[0].1;
+        }
+        if upper == self.stops.len() {
+            return self.stops[upper - 1].1;
+        }
+
+        let (v0, c0) = self.stops[upper - 1];
+        let (v1, c1) = self.stops[upper];
+        let t = (value - v0) / (v1 - v0);
+        let mut color = [0; 4];
+        for i in 0..4 {
+            color[i] = (c0[i] as f64 + t * (c1[i] as f64 - c0[i] as f64)).round() as u8;
+        }
+        color
+    }
+}
+
+/// Layout of the rendered image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RenderMode {
+    /// Four byte bands, with nodata in the alpha band
+    Rgba,
+    /// One byte band with a colour table, entry 0 being transparent nodata
+    Paletted,
+}
+
+/// Writes colour-ramped renderings of index blocks as they are computed.
+///
+/// GeoTIFF (and other drivers that support `Create`) are written block by block, to a staging
+/// file if the output only takes sequential writes. PNG output goes through an in-memory dataset
+/// that is copied out with a world file when rendering finishes, so it holds the whole image in
+/// memory: 4 bytes per pixel in RGBA mode, about 480 MB for a 10980 x 10980 tile. PNG keeps the
+/// provenance in its `.aux.xml` sidecar, as it has no place for other metadata domains.
+pub struct Renderer {
+    ramp: ColorRamp,
+    mode: RenderMode,
+    range: (f64, f64),
+    dataset: Dataset,
+    path: String,
+    is_png: bool,
+    provenance: Provenance,
+}
+
+impl Renderer {
+    /// Creates the rendering of values produced as recorded in `provenance`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        path: &str,
+        mode: RenderMode,
+        ramp: ColorRamp,
+        range: (f64, f64),
+        size: (usize, usize),
+        projection: &str,
+        geo_transform: &GeoTransform,
+        provenance: &Provenance,
+    ) -> Result<Self> {
+        let bands = match mode {
+            RenderMode::Rgba => 4,
+            RenderMode::Paletted => 1,
+        };
+
+        let is_png = Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+        let mut dataset = if is_png {
+            let driver = DriverManager::get_driver_by_name("MEM")?;
+            driver.create_with_band_type::<u8, _>("", size.0, size.1, bands)?
+        } else {
+            let driver =
+                DriverManager::get_output_driver_for_dataset_name(path, DriverType::Raster)
+                    .context("unknown render output format")?;
+            let mut options = RasterCreationOptions::from_iter(["COMPRESS=DEFLATE", "TILED=YES"]);
+            if mode == RenderMode::Rgba && driver.short_name() == "GTiff" {
+                options.set_name_value("PHOTOMETRIC", "RGB")?;
+                options.set_name_value("ALPHA", "YES")?;
+            }
+            driver.create_with_band_type_with_options::<u8, _>(
+                &vsi::writable_path(path),
+                size.0,
+                size.1,
+                bands,
+                &options,
+            )?
+        };
+
+        dataset.set_projection(projection)?;
+        dataset.set_geo_transform(geo_transform)?;
+        provenance.write(&mut dataset)?;
+
+        match mode {
+            RenderMode::Rgba => {
+                let interpretations = [
+                    ColorInterpretation::RedBand,
+                    ColorInterpretation::GreenBand,
+                    ColorInterpretation::BlueBand,
+                    ColorInterpretation::AlphaBand,
+                ];
+                for (i, interpretation) in interpretations.into_iter().enumerate() {
+                    dataset
+                        .rasterband(i + 1)?
+                        .set_color_interpretation(interpretation)?;
+                }
+            }
+            RenderMode::Paletted => {
+                let mut table = ColorTable::new(PaletteInterpretation::Rgba);
+                table.set_color_entry(0, &ColorEntry::rgba(0, 0, 0, 0));
+                for i in 1..=255u16 {
+                    let value = range.0 + (i - 1) as f64 / 254.0 * (range.1 - range.0);
+                    let [r, g, b, a] = ramp.color(value);
+                    table.set_color_entry(
+                        i,
+                        &ColorEntry::rgba(r as i16, g as i16, b as i16, a as i16),
+                    );
+                }
+                let mut band = dataset.rasterband(1)?;
+                band.set_color_interpretation(ColorInterpretation::PaletteIndex)?;
+                band.set_color_table(&table);
+                band.set_no_data_value(Some(0.0))?;
+            }
+        }
+
+        Ok(Self {
+            ramp,
+            mode,
+            range,
+            dataset,
+            path: path.to_owned(),
+            is_png,
+            provenance: provenance.clone(),
+        })
+    }
+
+    /// Renders a block of index values, where NaN marks nodata.
+    pub fn write_block(
+        &mut self,
+        offset: (isize, isize),
+        shape: (usize, usize),
+        values: &[f32],
+    ) -> Result<()> {
+        match self.mode {
+            RenderMode::Rgba => {
+                let mut channels = [(); 4].map(|_| Vec::with_capacity(values.len()));
+                for &v in values {
+                    let color = if v.is_nan() {
+                        [0; 4]
+                    } else {
+                        self.ramp.color(v as f64)
+                    };
+                    for (channel, c) in channels.iter_mut().zip(color) {
+                        channel.push(c);
+                    }
+                }
+                for (i, channel) in channels.into_iter().enumerate() {
+                    let mut buffer = Buffer::new(shape, channel);
+                    self.dataset
+                        .rasterband(i + 1)?
+                        .write(offset, shape, &mut buffer)?;
+                }
+            }
+            RenderMode::Paletted => {
+                let (min, max) = self.range;
+                let data = values
+                    .iter()
+                    .map(|&v| {
+                        if v.is_nan() {
+                            0
+                        } else {
+                            1 + ((v as f64 - min) / (max - min) * 254.0)
+                                .round()
+                                .clamp(0.0, 254.0) as u8
+                        }
+                    })
+                    .collect();
+                let mut buffer = Buffer::new(shape, data);
+                self.dataset
+                    .rasterband(1)?
+                    .write(offset, shape, &mut buffer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the rendering, copying it to PNG with a world file if needed.
+    pub fn finish(self) -> Result<()> {
+        if self.is_png {
+            let driver = DriverManager::get_driver_by_name("PNG")?;
+            // Standard output takes the image alone.
+            let options = if vsi::is_stdout(&self.path) {
+                RasterCreationOptions::new()
+            } else {
+                RasterCreationOptions::from_iter(["WORLDFILE=YES"])
+            };
+            let mut png = self.dataset.create_copy(&driver, &self.path, &options)?;
+            // Only the default metadata domain is copied to PNG.
+            if !vsi::is_stdout(&self.path) {
+                self.provenance.write(&mut png)?;
+            }
+        } else {
+            self.dataset.close()?;
+            vsi::commit(&vsi::writable_path(&self.path), &self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Closes the unfinished rendering and deletes it, if it was not kept in memory.
+    pub fn discard(self) -> Result<()> {
+        self.dataset.close()?;
+        if !self.is_png {
+            delete_dataset(&vsi::writable_path(&self.path))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANGE: (f64, f64) = (-1.0, 1.0);
+
+    #[test]
+    fn colour_files_take_values_percentages_and_optional_alpha() {
+        let ramp = ColorRamp::from_text(
+            "# comment\nnv 0 0 0 0\n100% 0 255 0\n0%,255,0,0,128\n\n, ,\n0 255 255 0\n",
+            "ramp.txt",
+            RANGE,
+        )
+        .unwrap();
+        assert_eq!(
+            ramp.stops,
+            [
+                (-1.0, [255, 0, 0, 128]),
+                (0.0, [255, 255, 0, 255]),
+                (1.0, [0, 255, 0, 255])
+            ]
+        );
+    }
+
+    #[test]
+    fn colours_are_interpolated_and_clamped_to_the_ends() {
+        let ramp = ColorRamp::from_text("0 0 0 0\n1 200 100 50 255\n", "ramp.txt", RANGE).unwrap();
+        assert_eq!(ramp.color(-5.0), [0, 0, 0, 255]);
+        assert_eq!(ramp.color(0.5), [100, 50, 25, 255]);
+        assert_eq!(ramp.color(7.0), [200, 100, 50, 255]);
+    }
+
+    #[test]
+    fn named_ramps_span_the_range() {
+        let ramp = ColorRamp::parse("RdYlGn", RANGE).unwrap();
+        assert_eq!(ramp.color(-1.0), [0xa5, 0x00, 0x26, 255]);
+        assert_eq!(ramp.color(1.0), [0x00, 0x68, 0x37, 255]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        for text in [
+            "0 0 0 0\nx 1 2 3\n",
+            "0 0 0 0\n5x% 1 2 3\n",
+            "0 0 0 0\n1 1 2 300\n",
+        ] {
+            let error = ColorRamp::from_text(text, "ramp.txt", RANGE).unwrap_err();
+            assert!(error.to_string().starts_with("ramp.txt:2"), "{error}");
+        }
+        assert!(ColorRamp::from_text("0 1 2\n", "ramp.txt", RANGE).is_err());
+        assert!(ColorRamp::from_text("nv 0 0 0\n", "ramp.txt", RANGE).is_err());
+    }
+
+    #[test]
+    fn renderings_carry_the_provenance() {
+        let path = "/vsimem/render-test.tif";
+        let provenance = Provenance::new(&["B08.jp2".to_owned()], "ndvi".to_owned());
+        let ramp = ColorRamp::parse("viridis", RANGE).unwrap();
+        let mut renderer = Renderer::create(
+            path,
+            RenderMode::Paletted,
+            ramp,
+            RANGE,
+            (2, 1),
+            "",
+            &[0.0, 1.0, 0.0, 0.0, 0.0, -1.0],
+            &provenance,
+        )
+        .unwrap();
+        renderer
+            .write_block((0, 0), (2, 1), &[0.5, f32::NAN])
+            .unwrap();
+        renderer.finish().unwrap();
+
+        let dataset = Dataset::open(path).unwrap();
+        assert_eq!(Provenance::read(&dataset).unwrap(), Some(provenance));
+    }
+}
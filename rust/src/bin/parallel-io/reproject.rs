@@ -0,0 +1,254 @@
+use std::fs;
+
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+use gdal::{
+    spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef},
+    Dataset,
+};
+
+use crate::gdal_ext;
+
+/// Resampling used when warping the inputs, named as in `gdalwarp -r`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Resampling {
+    Near,
+    Bilinear,
+    Cubic,
+    Cubicspline,
+    Lanczos,
+    Average,
+    Mode,
+    Min,
+    Max,
+    Med,
+}
+
+/// Target grid of the warped inputs.
+pub struct WarpOptions<'a> {
+    /// Target CRS, in any form accepted by `gdalwarp -t_srs`. `None` keeps the source CRS.
+    pub t_srs: Option<&'a str>,
+    /// Target pixel size `(x, y)` in units of the target CRS.
+    pub resolution: Option<(f64, f64)>,
+    pub resampling: Resampling,
+    /// Nodata value each band is read with, e.g. from its [`crate::sensor::Calibration`]. It
+    /// fills pixels outside the source footprint, and is excluded from resampling unless the
+    /// source band declares its own nodata value.
+    pub nodata: &'a [Option<f64>],
+}
+
+/// `gdalwarp` options excluding nodata from resampling and filling the pixels outside the
+/// footprint of `source`: its own nodata value if it has one, otherwise `nodata`.
+fn nodata_args(source: &Dataset, nodata: Option<f64>) -> Result<Vec<String>> {
+    let Some(dst_nodata) = nodata else {
+        return Ok(Vec::new());
+    };
+    let src_nodata = source.rasterband(1)?.no_data_value().unwrap_or(dst_nodata);
+    Ok(vec![
+        "-srcnodata".to_owned(),
+        src_nodata.to_string(),
+        "-dstnodata".to_owned(),
+        dst_nodata.to_string(),
+    ])
+}
+
+/// Path of `input` that stays valid when referenced from a VRT in `/vsimem`.
+///
+/// A warped VRT refers to its source by name, which must not be relative to `/vsimem`.
+fn source_path(input: &str) -> String {
+    fs::canonicalize(input).map_or_else(
+        |_| input.to_owned(),
+        |path| path.to_string_lossy().into_owned(),
+    )
+}
+
+/// Extent of `dataset` as `[min_x, min_y, max_x, max_y]` in its own CRS.
+fn extent(dataset: &Dataset) -> Result<[f64; 4]> {
+    let gt = dataset.geo_transform()?;
+    let (width, height) = dataset.raster_size();
+    let (x0, y0) = (gt[0], gt[3]);
+    let (x1, y1) = (x0 + width as f64 * gt[1], y0 + height as f64 * gt[5]);
+    Ok([x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1)])
+}
+
+/// Extent of `dataset` as `[min_x, min_y, max_x, max_y]` in `target`.
+fn bounds_in(dataset: &Dataset, target: &SpatialRef) -> Result<[f64; 4]> {
+    let bounds = extent(dataset)?;
+    let mut source = dataset.spatial_ref()?;
+    source.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    Ok(CoordTransform::new(&source, target)?.transform_bounds(&bounds, 21)?)
+}
+
+/// Warps the tiles of every band onto one common grid, returning for each band the paths of
+/// virtual warped datasets in `/vsimem` that can be opened (once per reader thread) instead of
+/// the tiles.
+///
+/// The grid is in `t_srs` (the CRS of the first tile by default) and covers all tiles of the first
+/// band. Its pixel size is `resolution`, or the one GDAL suggests for the first tile, and its
+/// edges are aligned to multiples of the pixel size, so that tiles sharing a source grid are not
+/// shifted.
+pub fn warp_inputs(bands: &[Vec<String>], options: &WarpOptions) -> Result<Vec<Vec<String>>> {
+    let mut args = vec![
+        "-of".to_owned(),
+        "VRT".to_owned(),
+        "-r".to_owned(),
+        options
+            .resampling
+            .to_possible_value()
+            .unwrap()
+            .get_name()
+            .to_owned(),
+        "-co".to_owned(),
+        "BLOCKXSIZE=1024".to_owned(),
+        "-co".to_owned(),
+        "BLOCKYSIZE=1024".to_owned(),
+    ];
+    if let Some(t_srs) = options.t_srs {
+        args.extend(["-t_srs".to_owned(), t_srs.to_owned()]);
+    }
+
+    let first = Dataset::open(source_path(&bands[0][0]))?;
+    let suggested = gdal_ext::warp(&[&first], "/vsimem/parallel-io/grid.vrt", &args)
+        .with_context(|| format!("failed to warp {}", bands[0][0]))?;
+    let mut target = suggested.spatial_ref()?;
+    target.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    let (x_res, y_res) = match options.resolution {
+        Some(resolution) => resolution,
+        None => {
+            let gt = suggested.geo_transform()?;
+            (gt[1].abs(), gt[5].abs())
+        }
+    };
+
+    let mut extent = [
+        f64::INFINITY,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        f64::NEG_INFINITY,
+    ];
+    for tile in &bands[0] {
+        let bounds = bounds_in(&Dataset::open(source_path(tile))?, &target)?;
+        extent = [
+            extent[0].min(bounds[0]),
+            extent[1].min(bounds[1]),
+            extent[2].max(bounds[2]),
+            extent[3].max(bounds[3]),
+        ];
+    }
+    let extent = [
+        (extent[0] / x_res).floor() * x_res,
+        (extent[1] / y_res).floor() * y_res,
+        (extent[2] / x_res).ceil() * x_res,
+        (extent[3] / y_res).ceil() * y_res,
+    ];
+
+    if options.t_srs.is_none() {
+        // Tiles in other UTM zones than the first one still need reprojecting.
+        args.extend(["-t_srs".to_owned(), target.to_wkt()?]);
+    }
+    args.push("-te".to_owned());
+    args.extend(extent.iter().map(f64::to_string));
+    args.extend(["-tr".to_owned(), x_res.to_string(), y_res.to_string()]);
+
+    let mut warped = Vec::with_capacity(bands.len());
+    for (band, tiles) in bands.iter().enumerate() {
+        let mut band_paths = Vec::with_capacity(tiles.len());
+        for (i, tile) in tiles.iter().enumerate() {
+            let source = Dataset::open(source_path(tile))?;
+            let dest = format!("/vsimem/parallel-io/warped_{band}_{i}.vrt");
+            let mut tile_args = args.clone();
+            tile_args.extend(nodata_args(&source, options.nodata[band])?);
+            gdal_ext::warp(&[&source], &dest, &tile_args)
+                .with_context(|| format!("failed to warp {tile}"))?;
+            band_paths.push(dest);
+        }
+        warped.push(band_paths);
+    }
+
+    Ok(warped)
+}
+
+/// Warps `input` onto the grid of `reference` with nearest neighbour resampling, e.g. to use a
+/// 20 m classification band with 10 m bands. Returns the path of a virtual warped dataset in
+/// `/vsimem`, named after `name`.
+pub fn warp_to_grid(input: &str, reference: &Dataset, name: &str) -> Result<String> {
+    let (width, height) = reference.raster_size();
+    let mut args = vec![
+        "-of".to_owned(),
+        "VRT".to_owned(),
+        "-r".to_owned(),
+        "near".to_owned(),
+        "-t_srs".to_owned(),
+        reference.spatial_ref()?.to_wkt()?,
+        "-te".to_owned(),
+    ];
+    args.extend(extent(reference)?.iter().map(f64::to_string));
+    args.extend(["-ts".to_owned(), width.to_string(), height.to_string()]);
+
+    let source = Dataset::open(source_path(input))?;
+    let dest = format!("/vsimem/parallel-io/{name}.vrt");
+    gdal_ext::warp(&[&source], &dest, &args).with_context(|| format!("failed to warp {input}"))?;
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use gdal::DriverManager;
+
+    use super::*;
+
+    /// 100 x 100 tile of 10 m pixels in UTM zone 33N with its upper left corner at `origin`.
+    fn tile(path: &str, origin: (f64, f64), nodata: Option<f64>) {
+        let driver = DriverManager::get_driver_by_name("GTiff").unwrap();
+        let mut dataset = driver
+            .create_with_band_type::<u16, _>(path, 100, 100, 1)
+            .unwrap();
+        dataset
+            .set_spatial_ref(&SpatialRef::from_epsg(32633).unwrap())
+            .unwrap();
+        dataset
+            .set_geo_transform(&[origin.0, 10.0, 0.0, origin.1, 0.0, -10.0])
+            .unwrap();
+        let mut band = dataset.rasterband(1).unwrap();
+        band.set_no_data_value(nodata).unwrap();
+    }
+
+    #[test]
+    fn tiles_are_warped_onto_one_aligned_grid() {
+        let tiles = vec![
+            "/vsimem/reproject-test/a.tif".to_owned(),
+            "/vsimem/reproject-test/b.tif".to_owned(),
+        ];
+        tile(&tiles[0], (500_005.0, 5_000_000.0), None);
+        tile(&tiles[1], (501_005.0, 5_000_000.0), Some(65535.0));
+
+        let warped = warp_inputs(
+            &[tiles],
+            &WarpOptions {
+                t_srs: Some("EPSG:32632"),
+                resolution: Some((20.0, 20.0)),
+                resampling: Resampling::Average,
+                nodata: &[Some(0.0)],
+            },
+        )
+        .unwrap();
+
+        assert_eq!(warped[0].len(), 2);
+        let grids = warped[0]
+            .iter()
+            .map(|path| {
+                let dataset = Dataset::open(path).unwrap();
+                assert_eq!(dataset.spatial_ref().unwrap().auth_code().unwrap(), 32632);
+                // The fill value the reader treats as nodata, whatever the source declares.
+                assert_eq!(dataset.rasterband(1).unwrap().no_data_value(), Some(0.0));
+                (dataset.geo_transform().unwrap(), dataset.raster_size())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(grids[0], grids[1]);
+        let (gt, size) = grids[0];
+        assert_eq!((gt[1], gt[5]), (20.0, -20.0));
+        assert_eq!((gt[0] % 20.0, gt[3] % 20.0), (0.0, 0.0));
+        // Both tiles, 2 km by 1 km, fit in the grid.
+        assert!(size.0 as f64 * 20.0 >= 2000.0 && size.1 as f64 * 20.0 >= 1000.0);
+    }
+}
@@ -0,0 +1,564 @@
+use std::{collections::HashMap, path::Path, time::Instant};
+
+use anyhow::{ensure, Context as _, Result};
+use gdal::{
+    spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef},
+    vector::{
+        Feature, FieldValue, LayerAccess as _, LayerOptions, OGRFieldType, OGRwkbGeometryType,
+    },
+    Dataset, DatasetOptions, DriverManager, DriverType, GdalOpenFlags, GeoTransformEx as _,
+};
+
+use crate::{
+    block_reader::ParallelBlockReader,
+    index::Index,
+    progress,
+    provenance::Provenance,
+    reproject,
+    sensor::{self, QualityMask, Scene, SensorArgs},
+    stac::{self, Item},
+    vsi,
+};
+
+/// Samples an index at points over a list of scenes, writing one row per point and scene.
+#[derive(clap::Args)]
+pub struct SampleArgs {
+    /// Point file: a CSV with `lon`/`lat` (or `x`/`y`) columns in WGS84, or any OGR point layer
+    #[arg(long)]
+    points: String,
+
+    /// Field holding the point identifier, the feature id by default
+    #[arg(long)]
+    id_field: Option<String>,
+
+    /// Index to sample
+    #[arg(long, value_enum, default_value_t = Index::Ndvi)]
+    index: Index,
+
+    /// First band of the index in each scene (B08 for NDVI)
+    #[arg(long, num_args = 1.., required_unless_present = "scenes")]
+    a: Vec<String>,
+
+    /// Second band of the index in each scene (B04 for NDVI), in the same order as `--a`
+    #[arg(long, num_args = 1.., required_unless_present = "scenes")]
+    b: Vec<String>,
+
+    /// Quality band of each scene, used to flag clouds and other invalid pixels: the scene
+    /// classification (SCL) of Sentinel-2 or the QA_PIXEL band of Landsat
+    #[arg(long, num_args = 1..)]
+    scl: Option<Vec<String>>,
+
+    /// Landsat 8/9 Collection 2 Level-2 scenes, as their `MTL.json` or `MTL.txt`, or
+    /// `item=<path>` for the items of a STAC Item, ItemCollection or Catalog, instead of `--a`,
+    /// `--b` and `--scl`: the bands of the index and the quality band are found from the metadata
+    #[arg(long, num_args = 1.., conflicts_with_all = ["a", "b", "scl"])]
+    scenes: Option<Vec<String>>,
+
+    /// SCL classes flagged as `masked`
+    #[arg(long, value_delimiter = ',', default_value = "0,1,3,8,9,10")]
+    scl_mask: Vec<u8>,
+
+    /// QA_PIXEL bits flagged as `masked`: fill, dilated cloud, cirrus, cloud and cloud shadow by
+    /// default
+    #[arg(long, value_delimiter = ',', default_value = "0,1,2,3,4")]
+    qa_mask: Vec<u8>,
+
+    /// Long-format table of `point_id, date, index, value, quality`, a CSV or (if GDAL has the
+    /// driver) Parquet file
+    #[arg(short, long)]
+    output: String,
+
+    #[command(flatten)]
+    sensor: SensorArgs,
+
+    /// Number of threads reading blocks
+    #[arg(long, default_value_t = 8)]
+    io_threads: usize,
+}
+
+impl SampleArgs {
+    /// Paths the command writes.
+    #[must_use]
+    pub fn outputs(&self) -> Vec<&str> {
+        vec![self.output.as_str()]
+    }
+}
+
+/// Quality of a sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Quality {
+    Ok,
+    /// Flagged by the scene classification.
+    Masked,
+    /// Inside the raster but without a valid index.
+    Nodata,
+    /// Outside the raster.
+    Outside,
+}
+
+impl Quality {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Masked => "masked",
+            Self::Nodata => "nodata",
+            Self::Outside => "outside",
+        }
+    }
+}
+
+struct Sample {
+    point: usize,
+    scene: usize,
+    value: f32,
+    quality: Quality,
+}
+
+/// Files and date of a scene.
+struct SceneFiles {
+    a: String,
+    b: String,
+    quality: Option<String>,
+    date: String,
+}
+
+/// Acquisition date of a band from its file name, or the file stem if there is none.
+fn scene_date(path: &str) -> String {
+    sensor::acquisition_date(path).unwrap_or_else(|| {
+        Path::new(path)
+            .file_stem()
+            .map_or_else(|| path.to_owned(), |s| s.to_string_lossy().into_owned())
+    })
+}
+
+/// Files of every scene, from the band lists or the scene metadata.
+fn scene_files(args: &SampleArgs) -> Result<Vec<SceneFiles>> {
+    if let Some(scenes) = &args.scenes {
+        let [a, b] = args.index.bands();
+        let mut files = Vec::new();
+        for path in scenes {
+            if let Some(path) = path.strip_prefix(stac::ITEM_PREFIX) {
+                for item in Item::read_all(path)? {
+                    let a = item.band(a)?.to_owned();
+                    files.push(SceneFiles {
+                        b: item.band(b)?.to_owned(),
+                        quality: item.quality().map(str::to_owned),
+                        date: match &item.datetime {
+                            Some(datetime) => datetime.chars().take(10).collect(),
+                            None => scene_date(&a),
+                        },
+                        a,
+                    });
+                }
+            } else {
+                let scene = Scene::from_mtl(path)?;
+                files.push(SceneFiles {
+                    a: scene.band(a).to_owned(),
+                    b: scene.band(b).to_owned(),
+                    quality: Some(scene.quality.clone()),
+                    date: scene.date,
+                });
+            }
+        }
+        return Ok(files);
+    }
+
+    let [band_a, band_b] = args.index.bands();
+    let a = stac::resolve_bands(&args.a, band_a)?;
+    let b = stac::resolve_bands(&args.b, band_b)?;
+    let scl = args.scl.as_deref().map(stac::resolve_quality).transpose()?;
+    ensure!(a.len() == b.len(), "--a and --b need one band per scene");
+    if let Some(scl) = &scl {
+        ensure!(scl.len() == a.len(), "--scl needs one band per scene");
+    }
+    Ok((0..a.len())
+        .map(|scene| SceneFiles {
+            a: a[scene].clone(),
+            b: b[scene].clone(),
+            quality: scl.as_ref().map(|scl| scl[scene].clone()),
+            date: scene_date(&a[scene]),
+        })
+        .collect())
+}
+
+/// Points as identifiers and coordinates, with the CRS of the coordinates.
+struct Points {
+    ids: Vec<String>,
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+    srs: SpatialRef,
+}
+
+fn read_points(path: &str, id_field: Option<&str>) -> Result<Points> {
+    let dataset = Dataset::open_ex(
+        path,
+        DatasetOptions {
+            open_flags: GdalOpenFlags::GDAL_OF_VECTOR,
+            open_options: Some(&[
+                "X_POSSIBLE_NAMES=lon,lng,long,longitude,x",
+                "Y_POSSIBLE_NAMES=lat,latitude,y",
+            ]),
+            ..Default::default()
+        },
+    )?;
+    let mut layer = dataset.layer(0)?;
+    let mut srs = match layer.spatial_ref() {
+        Some(srs) => srs,
+        None => SpatialRef::from_epsg(4326)?,
+    };
+    srs.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+
+    let (mut ids, mut xs, mut ys) = (Vec::new(), Vec::new(), Vec::new());
+    for feature in layer.features() {
+        let Some(geometry) = feature.geometry() else {
+            continue;
+        };
+        let (x, y, _) = geometry.get_point(0);
+        let id = match id_field {
+            Some(field) => match feature.field(field)? {
+                Some(FieldValue::StringValue(s)) => s,
+                Some(FieldValue::IntegerValue(i)) => i.to_string(),
+                Some(FieldValue::Integer64Value(i)) => i.to_string(),
+                Some(FieldValue::RealValue(r)) => r.to_string(),
+                _ => String::new(),
+            },
+            None => feature.fid().unwrap_or_default().to_string(),
+        };
+        ids.push(id);
+        xs.push(x);
+        ys.push(y);
+    }
+    ensure!(!ids.is_empty(), "no points in {path}");
+
+    Ok(Points { ids, xs, ys, srs })
+}
+
+/// Samples one scene, reading only the blocks that contain points.
+fn sample_scene(
+    args: &SampleArgs,
+    points: &Points,
+    scene: usize,
+    files: &SceneFiles,
+    samples: &mut Vec<Sample>,
+) -> Result<()> {
+    let reference = Dataset::open(&files.a)?;
+    let (width, height) = reference.raster_size();
+    let aligned = |path: &str, name: &str| -> Result<String> {
+        let dataset = Dataset::open(path)?;
+        if dataset.raster_size() == reference.raster_size()
+            && dataset.geo_transform()? == reference.geo_transform()?
+        {
+            Ok(path.to_owned())
+        } else {
+            reproject::warp_to_grid(path, &reference, name)
+        }
+    };
+    let mut inputs = vec![
+        files.a.clone(),
+        aligned(&files.b, &format!("sample_b_{scene}"))?,
+    ];
+    let mask = match &files.quality {
+        Some(quality) => {
+            inputs.push(aligned(quality, &format!("sample_scl_{scene}"))?);
+            Some(QualityMask::for_band(
+                quality,
+                &args.scl_mask,
+                &args.qa_mask,
+            ))
+        }
+        None => None,
+    };
+    let calibration = [
+        args.sensor.calibration(&files.a)?,
+        args.sensor.calibration(&files.b)?,
+    ];
+
+    let mut target = reference.spatial_ref()?;
+    target.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    let (mut xs, mut ys) = (points.xs.clone(), points.ys.clone());
+    CoordTransform::new(&points.srs, &target)?.transform_coords(&mut xs, &mut ys, &mut [])?;
+    let inverse = reference.geo_transform()?.invert()?;
+
+    let cancel = progress::interrupt_token();
+    let block_reader = ParallelBlockReader::new(&inputs, args.io_threads, None)?
+        .on_progress(progress::bar())
+        .cancel_on(cancel.clone());
+    let block_size = block_reader.region_size;
+
+    let mut by_block = HashMap::<(usize, usize), Vec<(usize, usize, usize)>>::new();
+    for (point, (&x, &y)) in xs.iter().zip(&ys).enumerate() {
+        let (px, py) = inverse.apply(x, y);
+        if !(px >= 0.0 && py >= 0.0 && px < width as f64 && py < height as f64) {
+            samples.push(Sample {
+                point,
+                scene,
+                value: f32::NAN,
+                quality: Quality::Outside,
+            });
+            continue;
+        }
+        let (px, py) = (px as usize, py as usize);
+        by_block
+            .entry((px / block_size.0, py / block_size.1))
+            .or_default()
+            .push((point, px, py));
+    }
+
+    // Bounded, so that readers wait for sampling instead of queueing up blocks in memory.
+    let (tx, rx) = flume::bounded(args.io_threads);
+    let dataset_indices = (0..inputs.len()).collect::<Vec<_>>();
+    for &(x, y) in by_block.keys() {
+        let tx = tx.clone();
+        block_reader.run(
+            x,
+            y,
+            &dataset_indices,
+            Box::new(move |x, y, blocks| {
+                tx.send((x, y, blocks)).unwrap();
+            }),
+        );
+    }
+    drop(tx);
+
+    let mut value = Vec::with_capacity(1);
+    let mut masked = Vec::new();
+    for (x, y, blocks) in rx {
+        let region = block_reader.region(x, y);
+        let a = blocks[&0].as_u16().unwrap().data();
+        let b = blocks[&1].as_u16().unwrap().data();
+        if let Some(mask) = &mask {
+            mask.apply(&blocks[&2], &mut masked);
+        }
+        for &(point, px, py) in &by_block[&(x, y)] {
+            let i = (py - region.offset.1) * region.size.0 + (px - region.offset.0);
+            args.index
+                .compute(&a[i..=i], &b[i..=i], calibration, &mut value);
+            let quality = if masked.get(i) == Some(&true) {
+                Quality::Masked
+            } else if value[0].is_nan() {
+                Quality::Nodata
+            } else {
+                Quality::Ok
+            };
+            samples.push(Sample {
+                point,
+                scene,
+                value: value[0],
+                quality,
+            });
+        }
+    }
+
+    block_reader.join();
+    ensure!(
+        !cancel.is_cancelled(),
+        "interrupted, {} not written",
+        args.output
+    );
+    Ok(())
+}
+
+/// Samples every scene and writes the long-format table.
+pub fn run(args: &SampleArgs) -> Result<()> {
+    let start = Instant::now();
+
+    let scenes = scene_files(args)?;
+    let points = read_points(&args.points, args.id_field.as_deref())?;
+    let mut samples = Vec::new();
+    for (scene, files) in scenes.iter().enumerate() {
+        sample_scene(args, &points, scene, files, &mut samples)
+            .with_context(|| format!("failed to sample {}", files.a))?;
+    }
+
+    let dates = scenes
+        .iter()
+        .map(|files| files.date.clone())
+        .collect::<Vec<_>>();
+    samples.sort_by(|s1, s2| {
+        (s1.point, &dates[s1.scene], s1.scene).cmp(&(s2.point, &dates[s2.scene], s2.scene))
+    });
+
+    let driver =
+        DriverManager::get_output_driver_for_dataset_name(&args.output, DriverType::Vector)
+            .context("unknown sample output format")?;
+    let mut output = driver.create_vector_only(vsi::writable_path(&args.output))?;
+    let layer = output.create_layer(LayerOptions {
+        name: "samples",
+        ty: OGRwkbGeometryType::wkbNone,
+        ..Default::default()
+    })?;
+    layer.create_defn_fields(&[
+        ("point_id", OGRFieldType::OFTString),
+        ("date", OGRFieldType::OFTString),
+        ("index", OGRFieldType::OFTString),
+        ("value", OGRFieldType::OFTReal),
+        ("quality", OGRFieldType::OFTString),
+    ])?;
+    for sample in &samples {
+        let mut feature = Feature::new(layer.defn())?;
+        feature.set_field_string("point_id", &points.ids[sample.point])?;
+        feature.set_field_string("date", &dates[sample.scene])?;
+        feature.set_field_string("index", args.index.name())?;
+        if !sample.value.is_nan() {
+            feature.set_field_double("value", sample.value as f64)?;
+        }
+        feature.set_field_string("quality", sample.quality.as_str())?;
+        feature.create(&layer)?;
+    }
+    let inputs = scenes
+        .iter()
+        .flat_map(|files| [files.a.clone(), files.b.clone()])
+        .collect::<Vec<_>>();
+    let provenance = Provenance::new(
+        &inputs,
+        format!("samples at points of {}", args.index.expression()),
+    );
+    let item = stac::vector_item(&output, &args.output, &provenance)?;
+    output.close()?;
+    vsi::commit(&vsi::writable_path(&args.output), &args.output)?;
+    item.write()?;
+
+    println!(
+        "{} samples of {} points in {} scenes written to {} in {:.3}s",
+        samples.len(),
+        points.ids.len(),
+        scenes.len(),
+        args.output,
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use gdal::raster::{Buffer, GdalType};
+
+    use super::*;
+    use crate::sensor::Calibration;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: SampleArgs,
+    }
+
+    fn parse_args(options: &[&str]) -> SampleArgs {
+        let cli = ["sample", "--points", "points.csv", "-o", "samples.csv"];
+        Cli::try_parse_from(cli.iter().chain(options)).unwrap().args
+    }
+
+    /// 4 x 4 raster of 10 m pixels in UTM zone 33N with its upper left corner at
+    /// (500000, 5000040).
+    fn raster<T: GdalType + Copy>(path: &str, data: Vec<T>) {
+        let driver = DriverManager::get_driver_by_name("GTiff").unwrap();
+        let mut dataset = driver.create_with_band_type::<T, _>(path, 4, 4, 1).unwrap();
+        dataset
+            .set_spatial_ref(&SpatialRef::from_epsg(32633).unwrap())
+            .unwrap();
+        dataset
+            .set_geo_transform(&[500_000.0, 10.0, 0.0, 5_000_040.0, 0.0, -10.0])
+            .unwrap();
+        let mut band = dataset.rasterband(1).unwrap();
+        band.write((0, 0), (4, 4), &mut Buffer::new((4, 4), data))
+            .unwrap();
+    }
+
+    #[test]
+    fn scene_dates_come_from_the_band_file_names() {
+        let args = parse_args(&[
+            "--a",
+            "T33TTG_20250305T100029_B08_10m.jp2",
+            "plots/nir.tif",
+            "--b",
+            "T33TTG_20250305T100029_B04_10m.jp2",
+            "plots/red.tif",
+        ]);
+        let files = scene_files(&args).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].date, "2025-03-05");
+        assert_eq!(files[1].b, "plots/red.tif");
+        // Without a date in the name, the file stem still tells scenes apart.
+        assert_eq!(files[1].date, "nir");
+        assert!(files.iter().all(|files| files.quality.is_none()));
+    }
+
+    #[test]
+    fn scenes_need_every_band() {
+        let args = parse_args(&["--a", "a1.tif", "a2.tif", "--b", "b1.tif"]);
+        assert!(scene_files(&args).is_err());
+        let args = parse_args(&["--a", "a.tif", "--b", "b.tif", "--scl", "s1.tif", "s2.tif"]);
+        assert!(scene_files(&args).is_err());
+    }
+
+    #[test]
+    fn samples_are_flagged_outside_masked_and_nodata() {
+        let (a, b, scl) = (
+            "/vsimem/sample-test/nir.tif",
+            "/vsimem/sample-test/red.tif",
+            "/vsimem/sample-test/scl.tif",
+        );
+        let mut nir = vec![5000u16; 16];
+        nir[5] = 0;
+        raster(a, nir);
+        raster(b, vec![2000u16; 16]);
+        let mut classes = vec![4u8; 16];
+        classes[10] = 9;
+        raster(scl, classes);
+
+        let mut srs = SpatialRef::from_epsg(32633).unwrap();
+        srs.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+        // Centres of pixels (0, 0), (1, 1) and (2, 2), and a point west of the raster.
+        let points = Points {
+            ids: ["ok", "nodata", "masked", "outside"]
+                .map(str::to_owned)
+                .to_vec(),
+            xs: vec![500_005.0, 500_015.0, 500_025.0, 400_000.0],
+            ys: vec![5_000_035.0, 5_000_025.0, 5_000_015.0, 5_000_035.0],
+            srs,
+        };
+        let files = SceneFiles {
+            a: a.to_owned(),
+            b: b.to_owned(),
+            quality: Some(scl.to_owned()),
+            date: "2025-03-05".to_owned(),
+        };
+        let mut samples = Vec::new();
+        sample_scene(
+            &parse_args(&["--a", a, "--b", b, "--io-threads", "2"]),
+            &points,
+            0,
+            &files,
+            &mut samples,
+        )
+        .unwrap();
+        samples.sort_by_key(|sample| sample.point);
+
+        let qualities = samples
+            .iter()
+            .map(|sample| sample.quality)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            qualities,
+            [
+                Quality::Ok,
+                Quality::Nodata,
+                Quality::Masked,
+                Quality::Outside
+            ]
+        );
+        let mut expected = Vec::new();
+        Index::Ndvi.compute(
+            &[5000],
+            &[2000],
+            [Calibration::SENTINEL2_L2A; 2],
+            &mut expected,
+        );
+        assert_eq!(samples[0].value, expected[0]);
+        // Masked samples keep their value, for filtering later.
+        assert_eq!(samples[2].value, expected[0]);
+        assert!(samples[1].value.is_nan() && samples[3].value.is_nan());
+        assert!(samples.iter().all(|sample| sample.scene == 0));
+    }
+}
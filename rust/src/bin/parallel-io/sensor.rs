@@ -0,0 +1,860 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+use clap::ValueEnum;
+use gdal::{Dataset, Metadata as _};
+use serde_json::Value;
+
+use crate::{
+    gdal_ext::{vsi_exists, TypedBuffer, XmlDocument},
+    vsi,
+};
+
+/// Spectral band, by its STAC `eo:common_name`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Band {
+    Blue,
+    Green,
+    Red,
+    /// Broad NIR (Sentinel-2 B08)
+    Nir,
+    /// Narrow NIR (Sentinel-2 B8A)
+    Nir08,
+    Swir16,
+    Swir22,
+}
+
+impl Band {
+    /// STAC `eo:common_name` of the band.
+    #[must_use]
+    pub fn common_name(self) -> &'static str {
+        match self {
+            Self::Blue => "blue",
+            Self::Green => "green",
+            Self::Red => "red",
+            Self::Nir => "nir",
+            Self::Nir08 => "nir08",
+            Self::Swir16 => "swir16",
+            Self::Swir22 => "swir22",
+        }
+    }
+}
+
+/// Products the crate knows how to calibrate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sensor {
+    /// Sentinel-2 MSI: Level-2A surface reflectance, or Level-1C top-of-atmosphere reflectance
+    /// in TOA mode.
+    Sentinel2,
+    /// Landsat 8/9 OLI Collection 2 Level-2 surface reflectance.
+    LandsatC2L2,
+}
+
+impl Sensor {
+    /// Sensor of a band file, from its name: Landsat Collection 2 files are named after their
+    /// product (`LC09_L2SP_190031_20230615_20230617_02_T1_SR_B4.TIF`), anything else is taken as
+    /// Sentinel-2.
+    #[must_use]
+    pub fn detect(path: &str) -> Self {
+        let name = file_name(path);
+        if landsat_product_id(&name).is_some() {
+            Self::LandsatC2L2
+        } else {
+            Self::Sentinel2
+        }
+    }
+
+    /// Name of `band` in the file names of the product, e.g. `B04` or `SR_B4` for red.
+    #[must_use]
+    pub fn band_name(self, band: Band) -> &'static str {
+        match (self, band) {
+            (Self::Sentinel2, Band::Blue) => "B02",
+            (Self::Sentinel2, Band::Green) => "B03",
+            (Self::Sentinel2, Band::Red) => "B04",
+            (Self::Sentinel2, Band::Nir) => "B08",
+            (Self::Sentinel2, Band::Nir08) => "B8A",
+            (Self::Sentinel2, Band::Swir16) => "B11",
+            (Self::Sentinel2, Band::Swir22) => "B12",
+            (Self::LandsatC2L2, Band::Blue) => "SR_B2",
+            (Self::LandsatC2L2, Band::Green) => "SR_B3",
+            (Self::LandsatC2L2, Band::Red) => "SR_B4",
+            // OLI has a single, narrow NIR band.
+            (Self::LandsatC2L2, Band::Nir | Band::Nir08) => "SR_B5",
+            (Self::LandsatC2L2, Band::Swir16) => "SR_B6",
+            (Self::LandsatC2L2, Band::Swir22) => "SR_B7",
+        }
+    }
+
+    /// Band of a file of this sensor, from its name.
+    #[must_use]
+    pub fn band_of(self, path: &str) -> Option<Band> {
+        let name = file_name(path);
+        Band::value_variants().iter().copied().find(|&band| {
+            let band_name = self.band_name(band);
+            name.contains(&format!("_{band_name}_")) || name.contains(&format!("_{band_name}."))
+        })
+    }
+}
+
+/// HLS bandpass adjustment of Sentinel-2 MSI to Landsat 8 OLI reflectance, as the slope and
+/// intercept of `oli = slope * msi + intercept` (HLS v1.4, Claverie et al., 2018). HLS uses B8A
+/// for NIR, so broad NIR has none.
+fn hls_bandpass(band: Band) -> Option<(f64, f64)> {
+    match band {
+        Band::Blue => Some((0.9778, -0.004)),
+        Band::Green => Some((1.0053, -0.0009)),
+        Band::Red => Some((0.9765, 0.0009)),
+        Band::Nir => None,
+        Band::Nir08 => Some((0.9983, -0.0001)),
+        Band::Swir16 => Some((0.9987, -0.0011)),
+        Band::Swir22 => Some((1.003, -0.0012)),
+    }
+}
+
+/// Linear conversion of digital numbers to reflectance, `dn * scale + offset`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    pub scale: f64,
+    pub offset: f64,
+    /// Digital number of pixels without data.
+    pub nodata: Option<f64>,
+}
+
+impl Calibration {
+    /// Sentinel-2 L2A from processing baseline 04.00: `(dn - 1000) / 10000`.
+    pub const SENTINEL2_L2A: Self = Self {
+        scale: 0.0001,
+        offset: -0.1,
+        nodata: Some(0.0),
+    };
+
+    /// Landsat Collection 2 Level-2 surface reflectance.
+    pub const LANDSAT_C2_L2: Self = Self {
+        scale: 0.0000275,
+        offset: -0.2,
+        nodata: Some(0.0),
+    };
+
+    /// Applies the linear adjustment `slope * reflectance + intercept` after this calibration.
+    #[must_use]
+    pub fn adjusted(self, slope: f64, intercept: f64) -> Self {
+        Self {
+            scale: self.scale * slope,
+            offset: self.offset * slope + intercept,
+            nodata: self.nodata,
+        }
+    }
+
+    /// Reflectance of `dn`, NaN for nodata.
+    #[must_use]
+    pub fn reflectance(&self, dn: f64) -> f64 {
+        if Some(dn) == self.nodata || dn.is_nan() {
+            f64::NAN
+        } else {
+            dn * self.scale + self.offset
+        }
+    }
+}
+
+/// Options of the conversion of digital numbers to reflectance, shared by every command.
+#[derive(clap::Args, Clone, Copy, Debug)]
+pub struct SensorArgs {
+    /// Adjust Sentinel-2 reflectance to Landsat 8/9 OLI with the HLS bandpass coefficients, so
+    /// that indices of both sensors are comparable (NIR must be B8A)
+    #[arg(long)]
+    harmonize: bool,
+
+    /// Read Sentinel-2 bands as Level-1C top-of-atmosphere reflectance, calibrated with the
+    /// quantification value and radiometric offsets of their SAFE product metadata
+    #[arg(long)]
+    toa: bool,
+}
+
+impl SensorArgs {
+    /// Calibration of a band file, to TOA reflectance in TOA mode and harmonised to Landsat OLI
+    /// if asked.
+    pub fn calibration(&self, path: &str) -> Result<Calibration> {
+        let sensor = Sensor::detect(path);
+        let band = || {
+            sensor
+                .band_of(path)
+                .with_context(|| format!("cannot tell the band of {path}"))
+        };
+        let calibration = if self.toa {
+            ensure!(
+                sensor == Sensor::Sentinel2,
+                "TOA mode needs Sentinel-2 L1C bands, not {path}"
+            );
+            L1cMetadata::find(path)?.calibration(band()?)
+        } else {
+            product_calibration(path)?
+        };
+        if !self.harmonize || sensor == Sensor::LandsatC2L2 {
+            return Ok(calibration);
+        }
+
+        let band = band()?;
+        let (slope, intercept) = hls_bandpass(band).with_context(|| {
+            format!("no HLS bandpass adjustment for {band:?} ({path}), use B8A for NIR")
+        })?;
+        Ok(calibration.adjusted(slope, intercept))
+    }
+
+    /// Records how the bands `inputs` were converted to reflectance in the metadata of
+    /// `dataset`: top-of-atmosphere or surface reflectance, the harmonisation, and for L1C the
+    /// sun and irradiance terms of the product.
+    pub fn set_metadata(&self, dataset: &mut Dataset, inputs: &[String]) -> Result<()> {
+        let reflectance = if self.toa { "TOA" } else { "SURFACE" };
+        dataset.set_metadata_item("REFLECTANCE", reflectance, "")?;
+        if self.harmonize {
+            dataset.set_metadata_item(
+                "HARMONIZATION",
+                "HLS v1.4 bandpass adjustment to Landsat 8 OLI",
+                "",
+            )?;
+        }
+        if self.toa {
+            let metadata = L1cMetadata::find(&inputs[0])?;
+            dataset.set_metadata_item(
+                "QUANTIFICATION_VALUE",
+                &metadata.quantification.to_string(),
+                "",
+            )?;
+            dataset.set_metadata_item("EARTH_SUN_DISTANCE_U", &metadata.u.to_string(), "")?;
+            dataset.set_metadata_item(
+                "MEAN_SUN_ZENITH_ANGLE",
+                &metadata.sun_zenith.to_string(),
+                "",
+            )?;
+            for path in inputs {
+                let Some(band) = Sensor::Sentinel2.band_of(path) else {
+                    continue;
+                };
+                if let Some(irradiance) = metadata.solar_irradiance.get(&l1c_band_id(band)) {
+                    dataset.set_metadata_item(
+                        &format!("SOLAR_IRRADIANCE_{}", Sensor::Sentinel2.band_name(band)),
+                        &irradiance.to_string(),
+                        "",
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Id of a band in the Sentinel-2 L1C metadata, counting B01 to B12 with B8A after B08.
+fn l1c_band_id(band: Band) -> usize {
+    match band {
+        Band::Blue => 1,
+        Band::Green => 2,
+        Band::Red => 3,
+        Band::Nir => 7,
+        Band::Nir08 => 8,
+        Band::Swir16 => 11,
+        Band::Swir22 => 12,
+    }
+}
+
+/// Radiometry of a Sentinel-2 L1C product, from the `MTD_MSIL1C.xml` and `MTD_TL.xml` of its
+/// SAFE directory.
+///
+/// L1C digital numbers already are TOA reflectance factors `pi L d^2 / (E0 cos(sun zenith))`,
+/// so the calibration only removes the quantification and radiometric offset; the earth–sun
+/// distance, solar irradiances and sun zenith are kept to document the product.
+pub struct L1cMetadata {
+    quantification: f64,
+    /// `RADIO_ADD_OFFSET` per band id, from processing baseline 04.00.
+    offsets: HashMap<usize, f64>,
+    /// Mean exo-atmospheric solar irradiance per band id, in W/m²/µm.
+    solar_irradiance: HashMap<usize, f64>,
+    /// Earth–sun distance correction factor `U`.
+    u: f64,
+    /// Mean sun zenith angle of the tile, in degrees.
+    sun_zenith: f64,
+}
+
+impl L1cMetadata {
+    /// Reads the metadata of the SAFE product that the band file `path` is in.
+    pub fn find(path: &str) -> Result<Self> {
+        let find = |name: &str| -> Result<String> {
+            Path::new(path)
+                .ancestors()
+                .skip(1)
+                .map(|dir| dir.join(name))
+                .find(|metadata| vsi_exists(&metadata.to_string_lossy()))
+                .map(|metadata| metadata.to_string_lossy().into_owned())
+                .with_context(|| format!("no {name} above {path}, L1C bands must be in their SAFE"))
+        };
+        let product_path = find("MTD_MSIL1C.xml")?;
+        let tile_path = find("MTD_TL.xml")?;
+        let product = XmlDocument::open(&product_path)?;
+        let tile = XmlDocument::open(&tile_path)?;
+
+        let number = |document: &XmlDocument, path: &str, name: &str| -> Result<f64> {
+            let value = document
+                .find_value(name)
+                .with_context(|| format!("no {name} in {path}"))?;
+            value
+                .parse()
+                .with_context(|| format!("invalid {name} {value} in {path}"))
+        };
+        let list = |name: &str, key: &str| -> Result<HashMap<usize, f64>> {
+            product
+                .list_values(name, key)
+                .into_iter()
+                .map(|(id, value)| {
+                    Ok((
+                        id.parse()
+                            .with_context(|| format!("invalid {key} in {product_path}"))?,
+                        value
+                            .parse()
+                            .with_context(|| format!("invalid {name} in {product_path}"))?,
+                    ))
+                })
+                .collect()
+        };
+
+        Ok(Self {
+            quantification: number(&product, &product_path, "QUANTIFICATION_VALUE")?,
+            offsets: list("Radiometric_Offset_List", "band_id")?,
+            solar_irradiance: list("Solar_Irradiance_List", "bandId")?,
+            u: number(&product, &product_path, "Reflectance_Conversion.U")?,
+            sun_zenith: number(&tile, &tile_path, "Mean_Sun_Angle.ZENITH_ANGLE")?,
+        })
+    }
+
+    /// Calibration of `band` to TOA reflectance, `(dn + RADIO_ADD_OFFSET) / QUANTIFICATION_VALUE`.
+    #[must_use]
+    pub fn calibration(&self, band: Band) -> Calibration {
+        let offset = self.offsets.get(&l1c_band_id(band)).copied().unwrap_or(0.0);
+        Calibration {
+            scale: 1.0 / self.quantification,
+            offset: offset / self.quantification,
+            nodata: Some(0.0),
+        }
+    }
+}
+
+/// Calibration of a band file: the scale and offset of its product metadata (`MTL.json` or
+/// `MTL.txt` next to the band) for Landsat, falling back to the Collection 2 constants, and the
+/// L2A constants for Sentinel-2.
+fn product_calibration(path: &str) -> Result<Calibration> {
+    match Sensor::detect(path) {
+        Sensor::Sentinel2 => Ok(Calibration::SENTINEL2_L2A),
+        Sensor::LandsatC2L2 => {
+            let name = file_name(path);
+            let Some(number) = name
+                .rsplit_once("_SR_B")
+                .and_then(|(_, band)| band.split('.').next())
+            else {
+                bail!("{path} is not a Landsat surface reflectance band");
+            };
+            match Mtl::find(path) {
+                Some(mtl) => {
+                    let mtl = Mtl::open(&mtl)?;
+                    let group = "LEVEL2_SURFACE_REFLECTANCE_PARAMETERS";
+                    Ok(Calibration {
+                        scale: mtl.number(group, &format!("REFLECTANCE_MULT_BAND_{number}"))?,
+                        offset: mtl.number(group, &format!("REFLECTANCE_ADD_BAND_{number}"))?,
+                        nodata: Some(0.0),
+                    })
+                }
+                None => Ok(Calibration::LANDSAT_C2_L2),
+            }
+        }
+    }
+}
+
+/// How a quality band flags pixels to mask.
+#[derive(Clone, Debug)]
+pub enum QualityMask {
+    /// Sentinel-2 scene classification (SCL) classes.
+    Classes(Vec<u8>),
+    /// Bits of the Landsat Collection 2 `QA_PIXEL` band, any of which masks the pixel.
+    Bits(u16),
+}
+
+impl QualityMask {
+    /// Mask of the quality band `path`: the `qa_bits` of a `QA_PIXEL` band, the `scl_classes`
+    /// otherwise.
+    #[must_use]
+    pub fn for_band(path: &str, scl_classes: &[u8], qa_bits: &[u8]) -> Self {
+        match Sensor::detect(path) {
+            Sensor::Sentinel2 => Self::Classes(scl_classes.to_vec()),
+            Sensor::LandsatC2L2 => Self::Bits(qa_bits.iter().fold(0, |bits, &bit| bits | 1 << bit)),
+        }
+    }
+
+    /// Returns `true` if the quality value `qa` is masked.
+    #[must_use]
+    pub fn is_masked(&self, qa: u16) -> bool {
+        match self {
+            Self::Classes(classes) => u8::try_from(qa).is_ok_and(|class| classes.contains(&class)),
+            Self::Bits(bits) => qa & bits != 0,
+        }
+    }
+
+    /// Masks the pixels of a quality block, as `true` in `masked`.
+    pub fn apply(&self, quality: &TypedBuffer, masked: &mut Vec<bool>) {
+        masked.clear();
+        match quality {
+            TypedBuffer::U8(buffer) => {
+                masked.extend(buffer.data().iter().map(|&qa| self.is_masked(qa as u16)));
+            }
+            TypedBuffer::U16(buffer) => {
+                masked.extend(buffer.data().iter().map(|&qa| self.is_masked(qa)));
+            }
+            other => masked.extend(
+                other
+                    .to_f64()
+                    .into_iter()
+                    .map(|qa| self.is_masked(qa as u16)),
+            ),
+        }
+    }
+}
+
+/// The masked values, e.g. `SCL classes 0,1,3` or `QA_PIXEL bits 0,1`.
+impl fmt::Display for QualityMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, values) = match self {
+            Self::Classes(classes) => ("SCL classes", classes.clone()),
+            Self::Bits(bits) => (
+                "QA_PIXEL bits",
+                (0..16).filter(|bit| bits & 1 << bit != 0).collect(),
+            ),
+        };
+        let values = values.iter().map(u8::to_string).collect::<Vec<_>>();
+        write!(f, "{name} {}", values.join(","))
+    }
+}
+
+/// Band files of a product, resolved from its metadata.
+pub struct Scene {
+    /// Acquisition date, as `YYYY-MM-DD`.
+    pub date: String,
+    bands: HashMap<Band, String>,
+    /// Quality band used for cloud masking.
+    pub quality: String,
+}
+
+impl Scene {
+    /// Opens a Landsat 8/9 Collection 2 Level-2 product from its `MTL.json` or `MTL.txt`.
+    pub fn from_mtl(path: &str) -> Result<Self> {
+        let mtl = Mtl::open(Path::new(path))?;
+        let spacecraft = mtl.get("IMAGE_ATTRIBUTES", "SPACECRAFT_ID").unwrap_or("");
+        ensure!(
+            matches!(spacecraft, "LANDSAT_8" | "LANDSAT_9"),
+            "{path} is not a Landsat 8/9 product"
+        );
+        ensure!(
+            mtl.get("PRODUCT_CONTENTS", "PROCESSING_LEVEL")
+                .is_some_and(|level| level.starts_with("L2")),
+            "{path} is not a Level-2 product"
+        );
+
+        let sensor = Sensor::LandsatC2L2;
+        let file = |key: &str| -> Result<String> {
+            let name = mtl
+                .get("PRODUCT_CONTENTS", key)
+                .with_context(|| format!("no {key} in {path}"))?;
+            Ok(mtl.dir.join(name).to_string_lossy().into_owned())
+        };
+        let mut bands = HashMap::new();
+        for band in Band::value_variants() {
+            let number = sensor.band_name(*band).trim_start_matches("SR_B");
+            bands.insert(*band, file(&format!("FILE_NAME_BAND_{number}"))?);
+        }
+
+        Ok(Self {
+            date: mtl
+                .get("IMAGE_ATTRIBUTES", "DATE_ACQUIRED")
+                .with_context(|| format!("no DATE_ACQUIRED in {path}"))?
+                .to_owned(),
+            bands,
+            quality: file("FILE_NAME_QUALITY_L1_PIXEL")?,
+        })
+    }
+
+    /// Path of `band`.
+    #[must_use]
+    pub fn band(&self, band: Band) -> &str {
+        &self.bands[&band]
+    }
+}
+
+/// Acquisition date (`YYYYMMDD`) and, for Sentinel-2, time (`HHMMSS`) of a band from its file
+/// name: the `20250305T100029` of a Sentinel-2 band, or the acquisition date field of a Landsat
+/// product id.
+fn acquisition(path: &str) -> Option<(String, Option<String>)> {
+    let name = file_name(path);
+    match landsat_product_id(&name) {
+        Some(id) => Some((id.split('_').nth(3)?.to_owned(), None)),
+        None => {
+            let i = name.as_bytes().windows(15).position(|w| {
+                w[..8].iter().all(u8::is_ascii_digit)
+                    && w[8] == b'T'
+                    && w[9..].iter().all(u8::is_ascii_digit)
+            })?;
+            Some((
+                name[i..i + 8].to_owned(),
+                Some(name[i + 9..i + 15].to_owned()),
+            ))
+        }
+    }
+}
+
+/// Acquisition date (`YYYY-MM-DD`) of a band from its file name.
+#[must_use]
+pub fn acquisition_date(path: &str) -> Option<String> {
+    let (date, _) = acquisition(path)?;
+    Some(format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..8]))
+}
+
+/// Acquisition time of a band from its file name as an RFC 3339 UTC timestamp, at midnight when
+/// the file name only has the date.
+#[must_use]
+pub fn acquisition_datetime(path: &str) -> Option<String> {
+    let (date, time) = acquisition(path)?;
+    let time = time.unwrap_or_else(|| "000000".to_owned());
+    Some(format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &date[..4],
+        &date[4..6],
+        &date[6..8],
+        &time[..2],
+        &time[2..4],
+        &time[4..6]
+    ))
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map_or_else(
+        || path.to_owned(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+/// Landsat 8/9 Collection 2 Level-2 product id at the start of a file name, e.g.
+/// `LC09_L2SP_190031_20230615_20230617_02_T1`.
+fn landsat_product_id(name: &str) -> Option<&str> {
+    let fields = name.splitn(8, '_').collect::<Vec<_>>();
+    let valid = fields.len() >= 7
+        && matches!(fields[0], "LC08" | "LC09")
+        && fields[1].starts_with("L2")
+        && fields[3].len() == 8
+        && fields[3].bytes().all(|b| b.is_ascii_digit())
+        && fields[5] == "02";
+    valid.then(|| &name[..fields[..7].iter().map(|f| f.len() + 1).sum::<usize>() - 1])
+}
+
+/// Landsat product metadata, as the values of the innermost group of each key.
+pub struct Mtl {
+    values: HashMap<(String, String), String>,
+    /// Directory of the metadata file, which the file names are relative to.
+    dir: PathBuf,
+}
+
+impl Mtl {
+    /// Finds the `MTL.json` or `MTL.txt` of the product that the band file `path` belongs to.
+    #[must_use]
+    pub fn find(path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        let name = path.file_name()?.to_string_lossy();
+        let id = landsat_product_id(&name)?;
+        ["json", "txt"]
+            .iter()
+            .map(|ext| path.with_file_name(format!("{id}_MTL.{ext}")))
+            .find(|mtl| vsi_exists(&mtl.to_string_lossy()))
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = vsi::read_to_string(&path.to_string_lossy())?;
+        let values = if text.trim_start().starts_with('{') {
+            parse_json(&text).with_context(|| format!("invalid JSON in {}", path.display()))?
+        } else {
+            parse_odl(&text)
+        };
+        Ok(Self {
+            values,
+            dir: path.parent().unwrap_or(Path::new("")).to_owned(),
+        })
+    }
+
+    #[must_use]
+    pub fn get(&self, group: &str, key: &str) -> Option<&str> {
+        self.values
+            .get(&(group.to_owned(), key.to_owned()))
+            .map(String::as_str)
+    }
+
+    fn number(&self, group: &str, key: &str) -> Result<f64> {
+        let value = self.get(group, key).with_context(|| format!("no {key}"))?;
+        value
+            .parse()
+            .with_context(|| format!("invalid {key} {value}"))
+    }
+}
+
+/// Parses the `GROUP = ...` / `KEY = VALUE` / `END_GROUP = ...` lines of an `MTL.txt`.
+fn parse_odl(text: &str) -> HashMap<(String, String), String> {
+    let mut values = HashMap::new();
+    let mut groups = Vec::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim().trim_matches('"'));
+        match key {
+            "GROUP" => groups.push(value.to_owned()),
+            "END_GROUP" => {
+                groups.pop();
+            }
+            _ => {
+                let group = groups.last().cloned().unwrap_or_default();
+                values.insert((group, key.to_owned()), value.to_owned());
+            }
+        }
+    }
+    values
+}
+
+/// Scalar values of the nested groups of an `MTL.json`, by group and key.
+fn parse_json(text: &str) -> Result<HashMap<(String, String), String>> {
+    fn flatten(group: &str, value: &Value, values: &mut HashMap<(String, String), String>) {
+        let Value::Object(members) = value else {
+            return;
+        };
+        for (key, value) in members {
+            let scalar = match value {
+                Value::Object(_) => {
+                    flatten(key, value, values);
+                    continue;
+                }
+                Value::String(s) => s.clone(),
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => b.to_string(),
+                Value::Null | Value::Array(_) => continue,
+            };
+            values.insert((group.to_owned(), key.clone()), scalar);
+        }
+    }
+
+    let mut values = HashMap::new();
+    flatten("", &serde_json::from_str(text)?, &mut values);
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use gdal::raster::Buffer;
+
+    use super::*;
+
+    const LANDSAT_RED: &str =
+        "/data/LC09_L2SP_190031_20230615_20230617_02_T1/LC09_L2SP_190031_20230615_20230617_02_T1_SR_B4.TIF";
+    const SENTINEL2_RED: &str = "/data/T32TQM_20250305T100029_B04_10m.jp2";
+
+    #[test]
+    fn sensor_and_band_from_file_names() {
+        assert_eq!(Sensor::detect(LANDSAT_RED), Sensor::LandsatC2L2);
+        assert_eq!(Sensor::detect(SENTINEL2_RED), Sensor::Sentinel2);
+        assert_eq!(Sensor::LandsatC2L2.band_of(LANDSAT_RED), Some(Band::Red));
+        assert_eq!(Sensor::Sentinel2.band_of(SENTINEL2_RED), Some(Band::Red));
+        assert_eq!(
+            Sensor::Sentinel2.band_of("T32TQM_20250305T100029_B8A_20m.jp2"),
+            Some(Band::Nir08)
+        );
+        assert_eq!(
+            Sensor::Sentinel2.band_of("T32TQM_20250305T100029_SCL_20m.jp2"),
+            None
+        );
+        // Collection 1 and Level-1 products are not calibrated as Collection 2 Level-2.
+        assert_eq!(
+            Sensor::detect("LC08_L1TP_190031_20230615_20230617_01_T1_B4.TIF"),
+            Sensor::Sentinel2
+        );
+    }
+
+    #[test]
+    fn acquisition_time_from_file_names() {
+        assert_eq!(acquisition_date(LANDSAT_RED).unwrap(), "2023-06-15");
+        assert_eq!(
+            acquisition_datetime(LANDSAT_RED).unwrap(),
+            "2023-06-15T00:00:00Z"
+        );
+        assert_eq!(acquisition_date(SENTINEL2_RED).unwrap(), "2025-03-05");
+        assert_eq!(
+            acquisition_datetime(SENTINEL2_RED).unwrap(),
+            "2025-03-05T10:00:29Z"
+        );
+        assert_eq!(acquisition_date("red.tif"), None);
+    }
+
+    #[test]
+    fn calibration_and_adjustment() {
+        let l2a = Calibration::SENTINEL2_L2A;
+        assert!((l2a.reflectance(3000.0) - 0.2).abs() < 1e-12);
+        assert!(l2a.reflectance(0.0).is_nan());
+        assert!(l2a.reflectance(f64::NAN).is_nan());
+
+        let (slope, intercept) = hls_bandpass(Band::Red).unwrap();
+        let adjusted = l2a.adjusted(slope, intercept);
+        let expected = slope * l2a.reflectance(3000.0) + intercept;
+        assert!((adjusted.reflectance(3000.0) - expected).abs() < 1e-12);
+        assert_eq!(adjusted.nodata, l2a.nodata);
+        assert_eq!(hls_bandpass(Band::Nir), None);
+    }
+
+    #[test]
+    fn mtl_txt_values_belong_to_their_innermost_group() {
+        let text = r#"GROUP = LANDSAT_METADATA_FILE
+  GROUP = PRODUCT_CONTENTS
+    PROCESSING_LEVEL = "L2SP"
+    FILE_NAME_BAND_4 = "LC09_L2SP_190031_20230615_20230617_02_T1_SR_B4.TIF"
+  END_GROUP = PRODUCT_CONTENTS
+  GROUP = LEVEL2_SURFACE_REFLECTANCE_PARAMETERS
+    REFLECTANCE_MULT_BAND_4 = 2.75E-05
+  END_GROUP = LEVEL2_SURFACE_REFLECTANCE_PARAMETERS
+  SPACECRAFT_ID = "LANDSAT_9"
+END_GROUP = LANDSAT_METADATA_FILE
+END
+"#;
+        let values = parse_odl(text);
+        let get = |group: &str, key: &str| values[&(group.to_owned(), key.to_owned())].as_str();
+        assert_eq!(get("PRODUCT_CONTENTS", "PROCESSING_LEVEL"), "L2SP");
+        assert_eq!(
+            get(
+                "LEVEL2_SURFACE_REFLECTANCE_PARAMETERS",
+                "REFLECTANCE_MULT_BAND_4"
+            ),
+            "2.75E-05"
+        );
+        assert_eq!(get("LANDSAT_METADATA_FILE", "SPACECRAFT_ID"), "LANDSAT_9");
+        assert_eq!(values.len(), 4);
+    }
+
+    #[test]
+    fn mtl_json_values_belong_to_their_innermost_group() {
+        let text = r#"{"LANDSAT_METADATA_FILE": {
+            "PRODUCT_CONTENTS": {"PROCESSING_LEVEL": "L2SP", "COLLECTION_NUMBER": "02"},
+            "LEVEL2_SURFACE_REFLECTANCE_PARAMETERS": {"REFLECTANCE_ADD_BAND_4": "-0.2"},
+            "IMAGE_ATTRIBUTES": {"SPACECRAFT_ID": "LANDSAT_9", "CLOUD_COVER": 12.5,
+                "ROLL_ANGLE": null, "LIST": [1, 2]}
+        }}"#;
+        let values = parse_json(text).unwrap();
+        let get = |group: &str, key: &str| values[&(group.to_owned(), key.to_owned())].as_str();
+        assert_eq!(get("PRODUCT_CONTENTS", "PROCESSING_LEVEL"), "L2SP");
+        assert_eq!(
+            get(
+                "LEVEL2_SURFACE_REFLECTANCE_PARAMETERS",
+                "REFLECTANCE_ADD_BAND_4"
+            ),
+            "-0.2"
+        );
+        assert_eq!(get("IMAGE_ATTRIBUTES", "CLOUD_COVER"), "12.5");
+        assert_eq!(values.len(), 5);
+        assert!(parse_json("{\"a\": ").is_err());
+    }
+
+    #[test]
+    fn l1c_calibration_from_the_safe_metadata() {
+        let safe = std::env::temp_dir().join(format!(
+            "S2B_MSIL1C_20250305T100029_N0511_R122_T32TQM_{}.SAFE",
+            std::process::id()
+        ));
+        let granule = safe.join("GRANULE/L1C_T32TQM/IMG_DATA");
+        std::fs::create_dir_all(&granule).unwrap();
+        std::fs::write(
+            safe.join("MTD_MSIL1C.xml"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<n1:Level-1C_User_Product xmlns:n1="https://psd-14.sentinel2.eo.esa.int/PSD/User_Product_Level-1C.xsd">
+  <n1:General_Info><Product_Image_Characteristics>
+    <QUANTIFICATION_VALUE unit="none">10000</QUANTIFICATION_VALUE>
+    <Reflectance_Conversion>
+      <U>1.0165</U>
+      <Solar_Irradiance_List>
+        <SOLAR_IRRADIANCE bandId="3" unit="W/m²/µm">1512.79</SOLAR_IRRADIANCE>
+      </Solar_Irradiance_List>
+    </Reflectance_Conversion>
+    <Radiometric_Offset_List>
+      <RADIO_ADD_OFFSET band_id="3">-1000</RADIO_ADD_OFFSET>
+    </Radiometric_Offset_List>
+  </Product_Image_Characteristics></n1:General_Info>
+</n1:Level-1C_User_Product>
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            safe.join("GRANULE/L1C_T32TQM/MTD_TL.xml"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<n1:Level-1C_Tile_ID xmlns:n1="https://psd-14.sentinel2.eo.esa.int/PSD/S2_PDI_Level-1C_Tile_Metadata.xsd">
+  <n1:Geometric_Info><Tile_Angles>
+    <Mean_Sun_Angle><ZENITH_ANGLE unit="deg">52.5</ZENITH_ANGLE></Mean_Sun_Angle>
+  </Tile_Angles></n1:Geometric_Info>
+</n1:Level-1C_Tile_ID>
+"#,
+        )
+        .unwrap();
+
+        let band = granule.join("T32TQM_20250305T100029_B04.jp2");
+        let metadata = L1cMetadata::find(&band.to_string_lossy());
+        std::fs::remove_dir_all(&safe).unwrap();
+        let metadata = metadata.unwrap();
+        assert_eq!(metadata.u, 1.0165);
+        assert_eq!(metadata.sun_zenith, 52.5);
+        assert_eq!(metadata.solar_irradiance[&3], 1512.79);
+        let red = metadata.calibration(Band::Red);
+        assert!((red.reflectance(3000.0) - 0.2).abs() < 1e-12);
+        // Bands without an offset, as before processing baseline 04.00.
+        assert_eq!(metadata.calibration(Band::Green).offset, 0.0);
+    }
+
+    #[test]
+    fn quality_mask_of_each_sensor() {
+        let scl = QualityMask::for_band("T32TQM_20250305T100029_SCL_20m.jp2", &[3, 8, 9], &[1]);
+        assert!(scl.is_masked(8));
+        assert!(!scl.is_masked(4));
+        // Not a class, e.g. a nodata value of a wider type.
+        assert!(!scl.is_masked(256 + 8));
+        assert_eq!(scl.to_string(), "SCL classes 3,8,9");
+
+        let qa = QualityMask::for_band(
+            "LC09_L2SP_190031_20230615_20230617_02_T1_QA_PIXEL.TIF",
+            &[3, 8, 9],
+            &[1, 3],
+        );
+        assert!(qa.is_masked(0b1000));
+        assert!(qa.is_masked(0b1_0101_0010));
+        assert!(!qa.is_masked(0b1_0101_0000));
+        assert_eq!(qa.to_string(), "QA_PIXEL bits 1,3");
+    }
+
+    #[test]
+    fn quality_mask_applies_to_any_buffer_type() {
+        let mask = QualityMask::Classes(vec![3, 9]);
+        let mut masked = Vec::new();
+        mask.apply(
+            &TypedBuffer::U8(Buffer::new((4, 1), vec![0, 3, 4, 9])),
+            &mut masked,
+        );
+        assert_eq!(masked, [false, true, false, true]);
+        mask.apply(
+            &TypedBuffer::I16(Buffer::new((2, 1), vec![9, 4])),
+            &mut masked,
+        );
+        assert_eq!(masked, [true, false]);
+
+        let mask = QualityMask::Bits(1 << 3);
+        mask.apply(
+            &TypedBuffer::U16(Buffer::new((2, 1), vec![21824, 21832])),
+            &mut masked,
+        );
+        assert_eq!(masked, [false, true]);
+    }
+}
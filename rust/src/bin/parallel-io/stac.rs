@@ -0,0 +1,766 @@
+//! STAC Items describing outputs, written next to each output as `<name>.stac.json` so that
+//! results can be loaded into catalogues and STAC-aware clients without further bookkeeping, and
+//! STAC Items selecting inputs, so that bands can be picked from a local mirror by name.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+use gdal::{
+    spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef},
+    vector::LayerAccess as _,
+    Dataset, Metadata as _,
+};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{
+    provenance::Provenance,
+    sensor::{self, Band, Sensor},
+    vsi,
+};
+
+/// Prefix of band arguments naming a STAC Item, ItemCollection or Catalog instead of a file,
+/// e.g. `--nir item=scene.json`.
+pub const ITEM_PREFIX: &str = "item=";
+
+const STAC_VERSION: &str = "1.0.0";
+
+const PROJECTION_EXTENSION: &str =
+    "https://stac-extensions.github.io/projection/v1.1.0/schema.json";
+const RASTER_EXTENSION: &str = "https://stac-extensions.github.io/raster/v1.1.0/schema.json";
+const EO_EXTENSION: &str = "https://stac-extensions.github.io/eo/v1.1.0/schema.json";
+const PROCESSING_EXTENSION: &str =
+    "https://stac-extensions.github.io/processing/v1.2.0/schema.json";
+
+/// `datetime` of the Items that the assets resolved by this process come from, by asset path, so
+/// that the Items of outputs take the time of their source products even when the asset file
+/// names carry no date.
+static ASSET_DATETIMES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// STAC Item read as an input, with its asset hrefs resolved to paths GDAL can open.
+pub struct Item {
+    pub id: String,
+    /// `datetime`, or `start_datetime` for items covering a range.
+    pub datetime: Option<String>,
+    assets: Vec<Asset>,
+}
+
+struct Asset {
+    key: String,
+    href: String,
+    /// `eo:common_name` of the bands of the asset.
+    common_names: Vec<String>,
+}
+
+impl Item {
+    /// Reads the items of a STAC Item, an ItemCollection, or a Catalog or Collection whose `item`
+    /// and `child` links are followed.
+    pub fn read_all(path: &str) -> Result<Vec<Self>> {
+        let mut items = Vec::new();
+        read_items(Path::new(path), &mut items)?;
+        ensure!(!items.is_empty(), "{path} has no STAC Items");
+        Ok(items)
+    }
+
+    fn from_json(json: &Value, base: &Path) -> Result<Self> {
+        let id = json
+            .get("id")
+            .and_then(Value::as_str)
+            .context("STAC Item without an id")?;
+        let properties = json.get("properties");
+        let datetime = ["datetime", "start_datetime"].iter().find_map(|key| {
+            properties
+                .and_then(|properties| properties.get(key))
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+        });
+
+        let mut assets = Vec::new();
+        if let Some(Value::Object(members)) = json.get("assets") {
+            for (key, asset) in members {
+                let Some(href) = asset.get("href").and_then(Value::as_str) else {
+                    continue;
+                };
+                // `eo:bands` up to STAC 1.0, `bands` since 1.1.
+                let common_names = ["eo:bands", "bands"]
+                    .iter()
+                    .flat_map(|list| elements(asset, list))
+                    .filter_map(|band| {
+                        ["common_name", "eo:common_name"]
+                            .iter()
+                            .find_map(|key| band.get(key).and_then(Value::as_str))
+                    })
+                    .map(str::to_owned)
+                    .collect();
+                assets.push(Asset {
+                    key: key.clone(),
+                    href: resolve_href(href, base),
+                    common_names,
+                });
+            }
+        }
+
+        Ok(Self {
+            id: id.to_owned(),
+            datetime,
+            assets,
+        })
+    }
+
+    /// Path of the asset of `band`: the asset keyed by its common name (`red`), listing it as its
+    /// `eo:common_name`, or keyed by its Sentinel-2 or Landsat band name (`B04`, `SR_B4`).
+    /// Landsat has a single NIR band, so `nir` falls back to `nir08`.
+    pub fn band(&self, band: Band) -> Result<&str> {
+        let find = |band: Band| {
+            let common_name = band.common_name();
+            let band_names = [Sensor::Sentinel2, Sensor::LandsatC2L2].map(|s| s.band_name(band));
+            self.asset(|asset| asset.key.eq_ignore_ascii_case(common_name))
+                .or_else(|| self.asset(|asset| asset.common_names.iter().any(|n| n == common_name)))
+                .or_else(|| {
+                    self.asset(|asset| {
+                        band_names
+                            .iter()
+                            .any(|name| asset.key.eq_ignore_ascii_case(name))
+                    })
+                })
+        };
+        let href = find(band)
+            .or_else(|| (band == Band::Nir).then(|| find(Band::Nir08)).flatten())
+            .with_context(|| format!("item {} has no {} asset", self.id, band.common_name()))?;
+        if let Some(datetime) = &self.datetime {
+            ASSET_DATETIMES
+                .lock()
+                .insert(href.to_owned(), datetime.clone());
+        }
+        Ok(href)
+    }
+
+    /// Path of the quality band: the scene classification (`scl`) of Sentinel-2 or `qa_pixel` of
+    /// Landsat.
+    #[must_use]
+    pub fn quality(&self) -> Option<&str> {
+        self.asset(|asset| {
+            ["scl", "qa_pixel"]
+                .iter()
+                .any(|key| asset.key.eq_ignore_ascii_case(key))
+        })
+    }
+
+    fn asset(&self, matches: impl Fn(&Asset) -> bool) -> Option<&str> {
+        self.assets
+            .iter()
+            .find(|asset| matches(asset))
+            .map(|asset| asset.href.as_str())
+    }
+}
+
+/// Items of the STAC document at `path`, appended to `items`.
+fn read_items(path: &Path, items: &mut Vec<Item>) -> Result<()> {
+    let text = vsi::read_to_string(&path.to_string_lossy())?;
+    let json = serde_json::from_str(&text)
+        .with_context(|| format!("invalid JSON in {}", path.display()))?;
+    add_items(&json, path, items)
+}
+
+/// Items of `json`, the STAC document at `path`, appended to `items`.
+fn add_items(json: &Value, path: &Path, items: &mut Vec<Item>) -> Result<()> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    match json.get("type").and_then(Value::as_str) {
+        Some("Feature") => items.push(Item::from_json(json, base)?),
+        Some("FeatureCollection") => {
+            for feature in elements(json, "features") {
+                items.push(Item::from_json(feature, base)?);
+            }
+        }
+        Some("Catalog" | "Collection") => {
+            for link in elements(json, "links") {
+                let rel = link.get("rel").and_then(Value::as_str);
+                if let (Some("item" | "child"), Some(href)) =
+                    (rel, link.get("href").and_then(Value::as_str))
+                {
+                    read_items(&PathBuf::from(resolve_href(href, base)), items)?;
+                }
+            }
+        }
+        _ => bail!(
+            "{} is not a STAC Item, ItemCollection or Catalog",
+            path.display()
+        ),
+    }
+    Ok(())
+}
+
+/// Items of the array member `key` of `json`, none if it is not an array.
+fn elements<'a>(json: &'a Value, key: &str) -> &'a [Value] {
+    json.get(key)
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+/// Path GDAL can open for an asset href: cloud URLs map to the matching virtual file system and
+/// relative hrefs are relative to the document.
+fn resolve_href(href: &str, base: &Path) -> String {
+    if let Some(path) = href.strip_prefix("s3://") {
+        format!("/vsis3/{path}")
+    } else if let Some(path) = href.strip_prefix("gs://") {
+        format!("/vsigs/{path}")
+    } else if href.starts_with("http://") || href.starts_with("https://") {
+        format!("/vsicurl/{href}")
+    } else if let Some(path) = href.strip_prefix("file://") {
+        path.to_owned()
+    } else if href.starts_with("/vsi") || Path::new(href).is_absolute() {
+        href.to_owned()
+    } else {
+        let href = href.strip_prefix("./").unwrap_or(href);
+        base.join(href).to_string_lossy().into_owned()
+    }
+}
+
+/// Expands `item=` band arguments to the asset of `band` in each of their items, keeping file
+/// arguments as they are.
+pub fn resolve_bands(args: &[String], band: Band) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.strip_prefix(ITEM_PREFIX) {
+            Some(path) => {
+                for item in Item::read_all(path)? {
+                    paths.push(item.band(band)?.to_owned());
+                }
+            }
+            None => paths.push(arg.clone()),
+        }
+    }
+    Ok(paths)
+}
+
+/// The single item of an `item=` argument, for arguments taking a single scene.
+pub fn read_item(arg: &str) -> Result<Item> {
+    let path = arg.strip_prefix(ITEM_PREFIX).unwrap_or(arg);
+    let mut items = Item::read_all(path)?;
+    ensure!(
+        items.len() == 1,
+        "{arg} has {} items, expected a single one",
+        items.len()
+    );
+    Ok(items.remove(0))
+}
+
+/// [`resolve_bands`] for an argument taking a single band, whose item must be a single scene.
+pub fn resolve_band(arg: &str, band: Band) -> Result<String> {
+    if arg.starts_with(ITEM_PREFIX) {
+        Ok(read_item(arg)?.band(band)?.to_owned())
+    } else {
+        Ok(arg.to_owned())
+    }
+}
+
+/// The two bands of an index, given as two band arguments or as a single `item=` argument
+/// holding both, which is read once.
+pub fn resolve_index_bands(args: &[String], bands: [Band; 2]) -> Result<[String; 2]> {
+    let item = match args {
+        [item] => Some(item),
+        [a, b] if a == b => Some(a),
+        _ => None,
+    };
+    if let Some(arg) = item.filter(|arg| arg.starts_with(ITEM_PREFIX)) {
+        let item = read_item(arg)?;
+        let [a, b] = bands.map(|band| item.band(band).map(str::to_owned));
+        return Ok([a?, b?]);
+    }
+    match args {
+        [a, b] => Ok([resolve_band(a, bands[0])?, resolve_band(b, bands[1])?]),
+        _ => bail!("expected the two bands of the index, or a single item=<path>"),
+    }
+}
+
+/// Expands `item=` quality band arguments to the quality asset of each of their items.
+pub fn resolve_quality(args: &[String]) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.strip_prefix(ITEM_PREFIX) {
+            Some(path) => {
+                for item in Item::read_all(path)? {
+                    let quality = item.quality();
+                    paths.push(
+                        quality
+                            .with_context(|| {
+                                format!("item {} has no scl or qa_pixel asset", item.id)
+                            })?
+                            .to_owned(),
+                    );
+                }
+            }
+            None => paths.push(arg.clone()),
+        }
+    }
+    Ok(paths)
+}
+
+/// Path of the item describing `output`, e.g. `ndvi.stac.json` for `ndvi.tif`.
+#[must_use]
+pub fn item_path(output: &str) -> String {
+    Path::new(output)
+        .with_extension("stac.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// STAC Item of an output, built from the open dataset and written next to the output once it
+/// is complete, so that no item refers to an output that failed to be written or uploaded.
+#[must_use]
+pub struct OutputItem {
+    /// Where the item goes, `None` for outputs streamed to standard output.
+    path: Option<String>,
+    item: Value,
+}
+
+impl OutputItem {
+    /// Writes the item to [`item_path`] of its output.
+    pub fn write(self) -> Result<()> {
+        let Some(path) = self.path else {
+            return Ok(());
+        };
+        let mut text = serde_json::to_string_pretty(&self.item)?;
+        text.push('\n');
+        vsi::write(&path, text.as_bytes())
+    }
+}
+
+/// Item of the raster `output`, with its footprint, projection and the data type, scaling,
+/// nodata and statistics of each band.
+pub fn raster_item(dataset: &Dataset, output: &str, provenance: &Provenance) -> Result<OutputItem> {
+    let gt = dataset.geo_transform()?;
+    let (width, height) = dataset.raster_size();
+    let (x0, y0) = (gt[0], gt[3]);
+    let (x1, y1) = (
+        x0 + width as f64 * gt[1] + height as f64 * gt[2],
+        y0 + width as f64 * gt[4] + height as f64 * gt[5],
+    );
+    let bounds = [x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1)];
+    let srs = dataset.spatial_ref().ok();
+
+    let mut bands = Vec::new();
+    for i in 1..=dataset.raster_count() {
+        let band = dataset.rasterband(i)?;
+        let mut info = json!({ "data_type": data_type(&band.band_type().name()) });
+        if let Some(nodata) = band.no_data_value() {
+            info["nodata"] = nodata_value(nodata);
+        }
+        if let Some(scale) = band.scale().filter(|&scale| scale != 1.0) {
+            info["scale"] = scale.into();
+        }
+        if let Some(offset) = band.offset().filter(|&offset| offset != 0.0) {
+            info["offset"] = offset.into();
+        }
+        let unit = band.unit();
+        if !unit.is_empty() {
+            info["unit"] = unit.into();
+        }
+        if let Some(stats) = band.get_statistics(false, false)? {
+            let mut statistics = json!({
+                "minimum": stats.min,
+                "maximum": stats.max,
+                "mean": stats.mean,
+                "stddev": stats.std_dev,
+            });
+            if let Some(valid) = band
+                .metadata_item("STATISTICS_VALID_PERCENT", "")
+                .and_then(|value| value.parse::<f64>().ok())
+            {
+                statistics["valid_percent"] = valid.into();
+            }
+            info["statistics"] = statistics;
+        }
+        let description = band.description()?;
+        if !description.is_empty() {
+            info["name"] = description.into();
+        }
+        bands.push(info);
+    }
+
+    let mut properties = json!({
+        "proj:epsg": srs.as_ref().and_then(|srs| srs.auth_code().ok()),
+        "proj:shape": [height, width],
+        "proj:transform": [gt[1], gt[2], gt[0], gt[4], gt[5], gt[3]],
+        "proj:bbox": bounds,
+    });
+    if let Some(wkt) = srs.as_ref().and_then(|srs| srs.to_wkt().ok()) {
+        properties["proj:wkt2"] = wkt.into();
+    }
+
+    let mut asset = asset(dataset, output);
+    asset["raster:bands"] = bands.into();
+    let footprint = match &srs {
+        Some(srs) => Some(to_lonlat(srs, bounds)?),
+        None => None,
+    };
+    output_item(
+        output,
+        footprint,
+        properties,
+        asset,
+        &[PROJECTION_EXTENSION, RASTER_EXTENSION],
+        provenance,
+    )
+}
+
+/// Item of the vector or table `output`, with the footprint of its features.
+pub fn vector_item(dataset: &Dataset, output: &str, provenance: &Provenance) -> Result<OutputItem> {
+    let mut footprint: Option<[f64; 4]> = None;
+    for layer in dataset.layers() {
+        let (Some(srs), Ok(extent)) = (layer.spatial_ref(), layer.get_extent()) else {
+            continue;
+        };
+        let bounds = to_lonlat(&srs, [extent.MinX, extent.MinY, extent.MaxX, extent.MaxY])?;
+        footprint = Some(match footprint {
+            Some(b) => [
+                b[0].min(bounds[0]),
+                b[1].min(bounds[1]),
+                b[2].max(bounds[2]),
+                b[3].max(bounds[3]),
+            ],
+            None => bounds,
+        });
+    }
+
+    output_item(
+        output,
+        footprint,
+        json!({}),
+        asset(dataset, output),
+        &[],
+        provenance,
+    )
+}
+
+/// Assembles the item around the asset of `output`.
+fn output_item(
+    output: &str,
+    footprint: Option<[f64; 4]>,
+    mut properties: Value,
+    asset: Value,
+    extensions: &[&str],
+    provenance: &Provenance,
+) -> Result<OutputItem> {
+    let created = &provenance.timestamp;
+    let paths = provenance
+        .inputs
+        .iter()
+        .map(|input| input.path.as_str())
+        .collect::<Vec<_>>();
+    let mut dates = paths
+        .iter()
+        .filter_map(|path| input_datetime(path))
+        .collect::<Vec<_>>();
+    dates.sort_unstable();
+    match (dates.first(), dates.last()) {
+        (Some(first), Some(last)) if first == last => {
+            properties["datetime"] = first.as_str().into();
+        }
+        (Some(first), Some(last)) => {
+            properties["datetime"] = Value::Null;
+            properties["start_datetime"] = first.as_str().into();
+            properties["end_datetime"] = last.as_str().into();
+        }
+        // Without acquisition dates the processing time is the only time known.
+        _ => properties["datetime"] = created.as_str().into(),
+    }
+    properties["created"] = created.as_str().into();
+
+    let mut eo_bands = Vec::new();
+    for path in &paths {
+        let sensor = Sensor::detect(path);
+        if let Some(band) = sensor.band_of(path) {
+            let entry = json!({
+                "name": sensor.band_name(band),
+                "common_name": band.common_name(),
+            });
+            if !eo_bands.contains(&entry) {
+                eo_bands.push(entry);
+            }
+        }
+    }
+    if !eo_bands.is_empty() {
+        properties["eo:bands"] = eo_bands.into();
+    }
+
+    properties["processing:software"] = json!({
+        provenance.tool.as_str(): format!("{} ({})", provenance.version, provenance.git_hash),
+    });
+    properties["processing:expression"] = json!({
+        "format": "text",
+        "expression": provenance.expression,
+    });
+    properties["processing:lineage"] = format!("Computed from {}", paths.join(", ")).into();
+    properties["parallel-io:command_line"] = provenance.command_line.as_str().into();
+
+    let mut stac_extensions = extensions.to_vec();
+    stac_extensions.extend([EO_EXTENSION, PROCESSING_EXTENSION]);
+    let mut item = json!({
+        "type": "Feature",
+        "stac_version": STAC_VERSION,
+        "stac_extensions": stac_extensions,
+        "id": file_stem(output),
+        "geometry": footprint.map(|[w, s, e, n]| json!({
+            "type": "Polygon",
+            "coordinates": [[[w, s], [e, s], [e, n], [w, n], [w, s]]],
+        })),
+    });
+    if let Some(bbox) = footprint {
+        item["bbox"] = bbox.to_vec().into();
+    }
+    item["properties"] = properties;
+    item["links"] = json!([]);
+    item["assets"] = json!({ "data": asset });
+
+    Ok(OutputItem {
+        path: (!vsi::is_stdout(output)).then(|| item_path(output)),
+        item,
+    })
+}
+
+/// Acquisition time of an input: the `datetime` of the STAC Item it was resolved from, or the
+/// one in its file name.
+pub fn input_datetime(path: &str) -> Option<String> {
+    ASSET_DATETIMES
+        .lock()
+        .get(path)
+        .cloned()
+        .or_else(|| sensor::acquisition_datetime(path))
+}
+
+/// The `data` asset, referring to `output` relative to the item next to it.
+fn asset(dataset: &Dataset, output: &str) -> Value {
+    let href = Path::new(output).file_name().map_or_else(
+        || output.to_owned(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    let mut asset = json!({ "href": href });
+    if let Some(media_type) = media_type(&dataset.driver().short_name()) {
+        asset["type"] = media_type.into();
+    }
+    asset["roles"] = json!(["data"]);
+    asset
+}
+
+/// Media type of files written by `driver`, as listed by the STAC best practices.
+fn media_type(driver: &str) -> Option<&'static str> {
+    Some(match driver {
+        "GTiff" => "image/tiff; application=geotiff",
+        "COG" => "image/tiff; application=geotiff; profile=cloud-optimized",
+        "PNG" => "image/png",
+        "JPEG" => "image/jpeg",
+        "netCDF" => "application/netcdf",
+        "Zarr" => "application/vnd+zarr",
+        "GPKG" => "application/geopackage+sqlite3",
+        "GeoJSON" => "application/geo+json",
+        "Parquet" => "application/vnd.apache.parquet",
+        "FlatGeobuf" => "application/vnd.flatgeobuf",
+        "CSV" => "text/csv",
+        _ => return None,
+    })
+}
+
+/// STAC `raster:bands` data type of a GDAL data type name, e.g. `uint8` for `Byte`.
+fn data_type(gdal: &str) -> String {
+    match gdal {
+        "Byte" => "uint8".to_owned(),
+        name => name.to_lowercase(),
+    }
+}
+
+/// Nodata as a number, or the strings STAC uses for the values JSON cannot hold.
+fn nodata_value(nodata: f64) -> Value {
+    if nodata.is_nan() {
+        "nan".into()
+    } else if nodata.is_infinite() {
+        if nodata > 0.0 { "inf" } else { "-inf" }.into()
+    } else {
+        nodata.into()
+    }
+}
+
+/// `[min_x, min_y, max_x, max_y]` bounds in `srs` as a WGS84 `west, south, east, north` box.
+fn to_lonlat(srs: &SpatialRef, bounds: [f64; 4]) -> Result<[f64; 4]> {
+    let mut source = srs.clone();
+    source.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    let mut wgs84 = SpatialRef::from_epsg(4326)?;
+    wgs84.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    Ok(CoordTransform::new(&source, &wgs84)?.transform_bounds(&bounds, 21)?)
+}
+
+fn file_stem(path: &str) -> String {
+    Path::new(path).file_stem().map_or_else(
+        || path.to_owned(),
+        |stem| stem.to_string_lossy().into_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/stac");
+
+    /// Items of a fixture, as if it were at `path`.
+    fn parse(text: &str, path: &str) -> Vec<Item> {
+        let mut items = Vec::new();
+        add_items(
+            &serde_json::from_str(text).unwrap(),
+            Path::new(path),
+            &mut items,
+        )
+        .unwrap();
+        items
+    }
+
+    fn sentinel2_item() -> Item {
+        parse(
+            include_str!("../../../tests/fixtures/stac/item.json"),
+            "/mirror/scene/item.json",
+        )
+        .remove(0)
+    }
+
+    #[test]
+    fn item_assets_by_key_common_name_and_band_name() {
+        let item = sentinel2_item();
+        assert_eq!(item.id, "S2B_32TQM_20250305_0_L2A");
+        let cogs =
+            "/vsis3/sentinel-cogs/sentinel-s2-l2a-cogs/32/T/QM/2025/3/S2B_32TQM_20250305_0_L2A";
+        assert_eq!(item.band(Band::Red).unwrap(), format!("{cogs}/B04.tif"));
+        assert_eq!(item.band(Band::Nir).unwrap(), format!("{cogs}/B08.tif"));
+        assert_eq!(item.band(Band::Nir08).unwrap(), format!("{cogs}/B8A.tif"));
+        // Keyed `band_3`, with green as its `eo:common_name`.
+        assert_eq!(
+            item.band(Band::Green).unwrap(),
+            "/mirror/scene/bands/green.tif"
+        );
+        // Keyed by the Sentinel-2 band name.
+        assert_eq!(
+            item.band(Band::Swir16).unwrap(),
+            "/vsicurl/https://example.com/S2B_32TQM_20250305_0_L2A/B11.tif"
+        );
+        assert_eq!(item.quality(), Some("/mirror/scene/SCL.tif"));
+        assert_eq!(
+            item.band(Band::Swir22).unwrap_err().to_string(),
+            "item S2B_32TQM_20250305_0_L2A has no swir22 asset"
+        );
+    }
+
+    #[test]
+    fn item_collection_items_and_nir_fallback() {
+        let items = parse(
+            include_str!("../../../tests/fixtures/stac/item-collection.json"),
+            "/mirror/items.json",
+        );
+        assert_eq!(items.len(), 2);
+
+        // Landsat has no broad NIR band, so `nir` takes the `nir08` asset.
+        let landsat9 = &items[0];
+        assert_eq!(
+            landsat9.band(Band::Nir).unwrap(),
+            "/data/landsat/LC09_L2SP_190031_20230615_20230617_02_T1_SR_B5.TIF"
+        );
+        assert_eq!(
+            landsat9.quality(),
+            Some("/data/landsat/LC09_L2SP_190031_20230615_20230617_02_T1_QA_PIXEL.TIF")
+        );
+        assert_eq!(
+            landsat9.datetime.as_deref(),
+            Some("2023-06-15T09:41:07.123Z")
+        );
+
+        let landsat8 = &items[1];
+        assert_eq!(landsat8.band(Band::Red).unwrap(), "/mirror/landsat/red.tif");
+        assert_eq!(landsat8.band(Band::Nir).unwrap(), "/mirror/landsat/nir.tif");
+        assert_eq!(landsat8.quality(), Some("/mirror/landsat/qa.tif"));
+        assert_eq!(landsat8.datetime.as_deref(), Some("2023-06-23T09:41:00Z"));
+    }
+
+    #[test]
+    fn catalog_links_are_followed() {
+        let items = Item::read_all(&format!("{FIXTURES}/catalog/catalog.json")).unwrap();
+        let ids = items
+            .iter()
+            .map(|item| item.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                "S2B_32TQM_20250305_0_L2A",
+                "LC09_L2SP_190031_20230615_02_T1"
+            ]
+        );
+        assert_eq!(
+            items[0].band(Band::Red).unwrap(),
+            format!("{FIXTURES}/catalog/S2B_32TQM_20250305_0_L2A/B04.tif")
+        );
+        assert_eq!(
+            items[1].band(Band::Nir).unwrap(),
+            format!(
+                "{FIXTURES}/catalog/landsat/LC09_L2SP_190031_20230615_20230617_02_T1_SR_B5.TIF"
+            )
+        );
+    }
+
+    #[test]
+    fn documents_that_are_not_stac_are_rejected() {
+        let mut items = Vec::new();
+        let error = add_items(
+            &json!({ "type": "Topology" }),
+            Path::new("topo.json"),
+            &mut items,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "topo.json is not a STAC Item, ItemCollection or Catalog"
+        );
+    }
+
+    #[test]
+    fn hrefs_map_to_gdal_paths() {
+        let base = Path::new("/mirror/scene");
+        assert_eq!(
+            resolve_href("s3://bucket/a/B04.tif", base),
+            "/vsis3/bucket/a/B04.tif"
+        );
+        assert_eq!(
+            resolve_href("gs://bucket/B04.tif", base),
+            "/vsigs/bucket/B04.tif"
+        );
+        assert_eq!(
+            resolve_href("https://host/B04.tif", base),
+            "/vsicurl/https://host/B04.tif"
+        );
+        assert_eq!(resolve_href("file:///data/B04.tif", base), "/data/B04.tif");
+        assert_eq!(
+            resolve_href("/vsizip/scene.zip/B04.tif", base),
+            "/vsizip/scene.zip/B04.tif"
+        );
+        assert_eq!(resolve_href("/data/B04.tif", base), "/data/B04.tif");
+        assert_eq!(resolve_href("B04.tif", base), "/mirror/scene/B04.tif");
+        assert_eq!(resolve_href("./B04.tif", base), "/mirror/scene/B04.tif");
+        assert_eq!(resolve_href("../B04.tif", base), "/mirror/scene/../B04.tif");
+    }
+
+    #[test]
+    fn outputs_take_the_datetime_of_the_source_item() {
+        let item = sentinel2_item();
+        let nir08 = item.band(Band::Nir08).unwrap();
+        assert_eq!(
+            input_datetime(nir08).as_deref(),
+            Some("2025-03-05T10:07:12.024000Z")
+        );
+        // Files not resolved from an item fall back to the date in their name.
+        assert_eq!(
+            input_datetime("/data/T32TQM_20250305T100029_B04_10m.jp2").as_deref(),
+            Some("2025-03-05T10:00:29Z")
+        );
+        assert_eq!(input_datetime("/data/red.tif"), None);
+    }
+}
@@ -0,0 +1,264 @@
+use std::fmt;
+
+use gdal::{
+    errors,
+    raster::{ColorInterpretation, RasterBand},
+    Metadata as _,
+};
+
+use crate::{gdal_ext::RasterBandExt as _, output_type::Encoding};
+
+/// Fixed-range histogram of index values.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    pub min: f64,
+    pub max: f64,
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    #[must_use]
+    pub fn new(range: (f64, f64), buckets: usize) -> Self {
+        Self {
+            min: range.0,
+            max: range.1,
+            counts: vec![0; buckets],
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        let buckets = self.counts.len();
+        let bucket = ((value - self.min) / (self.max - self.min) * buckets as f64) as isize;
+        self.counts[bucket.clamp(0, buckets as isize - 1) as usize] += 1;
+    }
+
+    /// Estimates the `q` quantile (0 to 1), interpolating linearly inside the bucket it falls in.
+    #[must_use]
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let total = self.counts.iter().sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+
+        let width = (self.max - self.min) / self.counts.len() as f64;
+        let target = q.clamp(0.0, 1.0) * total as f64;
+        let mut cumulative = 0.0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            let next = cumulative + count as f64;
+            if count > 0 && next >= target {
+                let t = (target - cumulative) / count as f64;
+                return Some(self.min + (i as f64 + t) * width);
+            }
+            cumulative = next;
+        }
+        Some(self.max)
+    }
+}
+
+/// Band statistics accumulated block by block while the output is written, so that finalising
+/// the output does not need another pass over the data.
+#[derive(Clone, Debug)]
+pub struct Statistics {
+    pub pixels: u64,
+    pub valid: u64,
+    pub min: f64,
+    pub max: f64,
+    sum: f64,
+    sum_sq: f64,
+    pub histogram: Option<Histogram>,
+}
+
+impl Statistics {
+    #[must_use]
+    pub fn new(histogram: Option<Histogram>) -> Self {
+        Self {
+            pixels: 0,
+            valid: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            sum_sq: 0.0,
+            histogram,
+        }
+    }
+
+    /// Adds a single index value, where NaN marks nodata.
+    pub fn add(&mut self, value: f32) {
+        self.pixels += 1;
+        if value.is_nan() {
+            return;
+        }
+
+        let value = value as f64;
+        self.valid += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.sum_sq += value * value;
+        if let Some(histogram) = &mut self.histogram {
+            histogram.add(value);
+        }
+    }
+
+    /// Adds a block of index values, where NaN marks nodata.
+    pub fn add_block(&mut self, values: &[f32]) {
+        self.pixels += values.len() as u64;
+
+        let (mut valid, mut min, mut max, mut sum, mut sum_sq) =
+            (0u64, f32::MAX, f32::MIN, 0.0, 0.0);
+        for &v in values.iter().filter(|v| !v.is_nan()) {
+            valid += 1;
+            min = min.min(v);
+            max = max.max(v);
+            sum += v as f64;
+            sum_sq += v as f64 * v as f64;
+            if let Some(histogram) = &mut self.histogram {
+                histogram.add(v as f64);
+            }
+        }
+
+        if valid > 0 {
+            self.valid += valid;
+            self.min = self.min.min(min as f64);
+            self.max = self.max.max(max as f64);
+            self.sum += sum;
+            self.sum_sq += sum_sq;
+        }
+    }
+
+    #[must_use]
+    pub fn mean(&self) -> f64 {
+        self.sum / self.valid as f64
+    }
+
+    #[must_use]
+    pub fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        (self.sum_sq / self.valid as f64 - mean * mean)
+            .max(0.0)
+            .sqrt()
+    }
+
+    #[must_use]
+    pub fn valid_percent(&self) -> f64 {
+        if self.pixels == 0 {
+            0.0
+        } else {
+            self.valid as f64 / self.pixels as f64 * 100.0
+        }
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min {:.4} max {:.4} mean {:.4} stddev {:.4}, {:.1}% valid",
+            self.min,
+            self.max,
+            self.mean(),
+            self.std_dev(),
+            self.valid_percent()
+        )
+    }
+}
+
+/// Descriptive metadata written to the output band alongside its statistics.
+pub struct BandInfo<'a> {
+    pub description: &'a str,
+    pub unit: &'a str,
+}
+
+/// Stores statistics, histogram, description, unit and colour interpretation on `band`.
+///
+/// GDAL keeps statistics in raw pixel values, so the accumulated index statistics are mapped back
+/// through the scale and offset of `encoding`.
+pub fn finalize_band(
+    band: &mut RasterBand,
+    stats: &Statistics,
+    encoding: &Encoding,
+    info: &BandInfo,
+) -> errors::Result<()> {
+    band.set_description(info.description)?;
+    band.set_unit_type(info.unit)?;
+    band.set_color_interpretation(ColorInterpretation::GrayIndex)?;
+
+    if stats.valid == 0 {
+        return Ok(());
+    }
+
+    let raw = |v: f64| (v - encoding.offset) / encoding.scale;
+    band.set_statistics(
+        raw(stats.min),
+        raw(stats.max),
+        raw(stats.mean()),
+        stats.std_dev() / encoding.scale,
+    )?;
+    band.set_metadata_item(
+        "STATISTICS_VALID_PERCENT",
+        &format!("{:.3}", stats.valid_percent()),
+        "",
+    )?;
+
+    if let Some(histogram) = &stats.histogram {
+        let mut counts = histogram.counts.clone();
+        band.set_default_histogram(raw(histogram.min), raw(histogram.max), &mut counts)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_clamps_values_outside_its_range_to_the_end_buckets() {
+        let mut histogram = Histogram::new((-1.0, 1.0), 4);
+        for value in [-2.0, -1.0, -0.1, 0.1, 0.9, 1.0, 3.0] {
+            histogram.add(value);
+        }
+        assert_eq!(histogram.counts, [2, 1, 1, 3]);
+    }
+
+    #[test]
+    fn quantiles_interpolate_inside_buckets() {
+        let mut histogram = Histogram::new((0.0, 10.0), 10);
+        for i in 0..100 {
+            histogram.add(i as f64 / 10.0 + 0.05);
+        }
+        assert!((histogram.quantile(0.5).unwrap() - 5.0).abs() < 1e-9);
+        assert!((histogram.quantile(0.25).unwrap() - 2.5).abs() < 1e-9);
+        assert_eq!(histogram.quantile(0.0), Some(0.0));
+        assert_eq!(histogram.quantile(1.0), Some(10.0));
+        assert_eq!(Histogram::new((0.0, 1.0), 3).quantile(0.5), None);
+    }
+
+    #[test]
+    fn statistics_skip_nodata() {
+        let mut stats = Statistics::new(None);
+        stats.add_block(&[1.0, f32::NAN, 3.0]);
+        stats.add(f32::NAN);
+        assert_eq!((stats.pixels, stats.valid), (4, 2));
+        assert_eq!((stats.min, stats.max), (1.0, 3.0));
+        assert_eq!(stats.mean(), 2.0);
+        assert_eq!(stats.std_dev(), 1.0);
+        assert_eq!(stats.valid_percent(), 50.0);
+    }
+
+    #[test]
+    fn blocks_and_single_values_accumulate_alike() {
+        let values = [0.25, -0.5, f32::NAN, 0.75, 0.0];
+        let mut by_block = Statistics::new(Some(Histogram::new((-1.0, 1.0), 8)));
+        by_block.add_block(&values);
+        let mut by_value = Statistics::new(Some(Histogram::new((-1.0, 1.0), 8)));
+        for value in values {
+            by_value.add(value);
+        }
+        assert_eq!(by_block.to_string(), by_value.to_string());
+        assert_eq!(
+            by_block.histogram.unwrap().counts,
+            by_value.histogram.unwrap().counts
+        );
+    }
+}
@@ -0,0 +1,434 @@
+use std::time::Instant;
+
+use anyhow::{bail, ensure, Result};
+use clap::ValueEnum;
+use gdal::{Dataset, Metadata as _};
+
+use crate::{
+    block_reader::ParallelBlockReader,
+    gdal_ext::RasterBandExt as _,
+    index::Index,
+    output_format::OutputFormatArgs,
+    output_type::{Encoding, OutputType},
+    progress,
+    provenance::{ChecksumArgs, Provenance},
+    reproject,
+    sensor::{Band, QualityMask, SensorArgs},
+    stac,
+    stats::{self, BandInfo, Statistics},
+    NDVI_RANGE,
+};
+
+/// Reduction applied along time to the valid observations of each pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Reducer {
+    Max,
+    Min,
+    Median,
+    Mean,
+    /// The `--percentile` percentile
+    Percentile,
+}
+
+/// Combines NDVI over a stack of aligned scenes into a composite.
+///
+/// The output has three bands: the composite, the number of valid observations, and for `max` and
+/// `min` the (0-based) index of the scene the value was taken from, resolved to its acquisition
+/// time by the `SCENE_<index>_DATETIME` metadata of that band.
+#[derive(clap::Args)]
+pub struct CompositeArgs {
+    /// NIR band (B08) of each scene, or `item=<path>` for the scenes of a STAC ItemCollection
+    #[arg(long, num_args = 1.., required = true)]
+    nir: Vec<String>,
+
+    /// Red band (B04) of each scene, in the same order as `--nir`, or `item=<path>`
+    #[arg(long, num_args = 1.., required = true)]
+    red: Vec<String>,
+
+    /// Quality band of each scene, used to mask clouds and other invalid pixels: the scene
+    /// classification (SCL) of Sentinel-2 or the QA_PIXEL band of Landsat, or `item=<path>`
+    #[arg(long, num_args = 1..)]
+    scl: Option<Vec<String>>,
+
+    /// SCL classes treated as invalid: no data, saturated, cloud shadow, cloud medium and high
+    /// probability and thin cirrus by default
+    #[arg(long, value_delimiter = ',', default_value = "0,1,3,8,9,10")]
+    scl_mask: Vec<u8>,
+
+    /// QA_PIXEL bits treated as invalid: fill, dilated cloud, cirrus, cloud and cloud shadow by
+    /// default
+    #[arg(long, value_delimiter = ',', default_value = "0,1,2,3,4")]
+    qa_mask: Vec<u8>,
+
+    /// Reduction applied along time
+    #[arg(long, value_enum, default_value_t = Reducer::Max)]
+    reducer: Reducer,
+
+    /// Percentile (0 to 100) computed by `--reducer percentile`
+    #[arg(long, required_if_eq("reducer", "percentile"))]
+    percentile: Option<f64>,
+
+    /// Output raster
+    #[arg(short, long)]
+    output: String,
+
+    /// Output data type
+    #[arg(long = "ot", value_enum, default_value_t = OutputType::Int16)]
+    output_type: OutputType,
+
+    #[command(flatten)]
+    format: OutputFormatArgs,
+
+    #[command(flatten)]
+    sensor: SensorArgs,
+
+    #[command(flatten)]
+    checksums: ChecksumArgs,
+
+    /// Number of threads reading blocks
+    #[arg(long, default_value_t = 8)]
+    io_threads: usize,
+}
+
+impl CompositeArgs {
+    /// Paths the command writes.
+    #[must_use]
+    pub fn outputs(&self) -> Vec<&str> {
+        vec![self.output.as_str()]
+    }
+}
+
+impl Reducer {
+    /// Reduces the valid `(scene, value)` observations of a pixel, returning the composite value
+    /// and, for `max` and `min`, the scene it was taken from.
+    fn reduce(self, percentile: f64, observations: &mut [(usize, f32)]) -> (f32, Option<usize>) {
+        if observations.is_empty() {
+            return (f32::NAN, None);
+        }
+
+        match self {
+            Self::Max => {
+                let (scene, value) = observations
+                    .iter()
+                    .copied()
+                    .reduce(|a, b| if b.1 > a.1 { b } else { a })
+                    .unwrap();
+                (value, Some(scene))
+            }
+            Self::Min => {
+                let (scene, value) = observations
+                    .iter()
+                    .copied()
+                    .reduce(|a, b| if b.1 < a.1 { b } else { a })
+                    .unwrap();
+                (value, Some(scene))
+            }
+            Self::Mean => {
+                let sum = observations.iter().map(|&(_, v)| v).sum::<f32>();
+                (sum / observations.len() as f32, None)
+            }
+            Self::Median => (quantile(observations, 0.5), None),
+            Self::Percentile => (quantile(observations, percentile / 100.0), None),
+        }
+    }
+}
+
+/// Linearly interpolated `q` quantile of the observed values.
+fn quantile(observations: &mut [(usize, f32)], q: f64) -> f32 {
+    observations.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+    let rank = q.clamp(0.0, 1.0) * (observations.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    let t = (rank - lo as f64) as f32;
+    observations[lo].1 + t * (observations[hi].1 - observations[lo].1)
+}
+
+/// Computes the composite block by block, holding one block of every scene at a time.
+pub fn run(args: &CompositeArgs) -> Result<()> {
+    let nir = stac::resolve_bands(&args.nir, Band::Nir)?;
+    let red = stac::resolve_bands(&args.red, Band::Red)?;
+    let scl = args.scl.as_deref().map(stac::resolve_quality).transpose()?;
+    let scenes = nir.len();
+    ensure!(
+        red.len() == scenes,
+        "--nir and --red need one band per scene"
+    );
+    if let Some(scl) = &scl {
+        ensure!(scl.len() == scenes, "--scl needs one band per scene");
+    }
+    // The observation count and scene index bands share the type of the composite.
+    ensure!(
+        scenes as f64 <= args.output_type.max_integer(),
+        "{scenes} scenes do not fit the count and scene index bands of --ot {}; use a wider type",
+        args.output_type.to_possible_value().unwrap().get_name()
+    );
+    let percentile = args.percentile.unwrap_or(50.0);
+    ensure!(
+        (0.0..=100.0).contains(&percentile),
+        "percentile must be between 0 and 100"
+    );
+    let start = Instant::now();
+
+    let reference = Dataset::open(&nir[0])?;
+    let geo_transform = reference.geo_transform()?;
+    for path in nir.iter().chain(&red) {
+        let dataset = Dataset::open(path)?;
+        ensure!(
+            dataset.raster_size() == reference.raster_size()
+                && dataset.geo_transform()? == geo_transform,
+            "{path} is not aligned with {}",
+            nir[0]
+        );
+    }
+
+    let calibration = nir
+        .iter()
+        .zip(&red)
+        .map(|(nir, red)| Ok([args.sensor.calibration(nir)?, args.sensor.calibration(red)?]))
+        .collect::<Result<Vec<_>>>()?;
+    let masks = scl
+        .iter()
+        .flatten()
+        .map(|path| QualityMask::for_band(path, &args.scl_mask, &args.qa_mask))
+        .collect::<Vec<_>>();
+
+    // NIR bands, then red bands, then quality bands warped to the NIR grid.
+    let mut inputs = nir.clone();
+    inputs.extend(red.iter().cloned());
+    if let Some(scl) = &scl {
+        for (i, path) in scl.iter().enumerate() {
+            inputs.push(reproject::warp_to_grid(
+                path,
+                &reference,
+                &format!("scl_{i}"),
+            )?);
+        }
+    }
+
+    let cancel = progress::interrupt_token();
+    let mut block_reader = ParallelBlockReader::new(&inputs, args.io_threads, None)?
+        .on_progress(progress::bar())
+        .cancel_on(cancel.clone());
+    if let Some(capacity) = args.format.ordered {
+        block_reader = block_reader.ordered(capacity);
+    }
+    let window = block_reader.window;
+
+    let encoding = Encoding::new(args.output_type, NDVI_RANGE, None, None)?;
+    let plain = Encoding {
+        output_type: args.output_type,
+        scale: 1.0,
+        offset: 0.0,
+    };
+
+    let mut output = args
+        .format
+        .create(encoding.output_type, &args.output, window.size, 3)?;
+    output.set_projection(&reference.projection())?;
+    output.set_geo_transform(&geo_transform)?;
+    output.set_metadata_item("COMPOSITE_SCENES", &nir.join(","), "")?;
+    args.sensor
+        .set_metadata(&mut output, &[nir.clone(), red.clone()].concat())?;
+    let mut mask = masks.iter().map(ToString::to_string).collect::<Vec<_>>();
+    mask.sort_unstable();
+    mask.dedup();
+    // NIR bands, then red bands, then quality bands.
+    let input_calibration = calibration
+        .iter()
+        .map(|[nir, _]| *nir)
+        .chain(calibration.iter().map(|[_, red]| *red))
+        .collect::<Vec<_>>();
+    let provenance = Provenance::new(
+        &[nir.clone(), red.clone(), scl.clone().unwrap_or_default()].concat(),
+        format!(
+            "{} over time of {}",
+            args.reducer.to_possible_value().unwrap().get_name(),
+            Index::Ndvi.expression()
+        ),
+    )
+    .with_calibration(&input_calibration)
+    .with_mask((!mask.is_empty()).then(|| mask.join("; ")))
+    .with_checksums(&args.checksums)?;
+    provenance.write(&mut output)?;
+    encoding.apply(&mut output.rasterband(1)?)?;
+    plain.apply(&mut output.rasterband(2)?)?;
+    plain.apply(&mut output.rasterband(3)?)?;
+
+    // Bounded, so that readers wait for the reduction instead of queueing up blocks of every
+    // scene in memory.
+    let (tx, rx) = flume::bounded(args.io_threads);
+    let dataset_indices = (0..inputs.len()).collect::<Vec<_>>();
+    for y in block_reader.blocks.1.clone() {
+        for x in block_reader.blocks.0.clone() {
+            let tx = tx.clone();
+            block_reader.run(
+                x,
+                y,
+                &dataset_indices,
+                Box::new(move |x, y, blocks| {
+                    tx.send((x, y, blocks)).unwrap();
+                }),
+            );
+        }
+    }
+    drop(tx);
+
+    let mut stats = Statistics::new(None);
+    let mut scene_ndvi = vec![Vec::new(); scenes];
+    let mut observations = Vec::with_capacity(scenes);
+    let mut masked = Vec::new();
+    for (x, y, mut blocks) in rx {
+        let blocks = dataset_indices
+            .iter()
+            .map(|i| blocks.remove(i).unwrap())
+            .collect::<Vec<_>>();
+        for (scene, ndvi) in scene_ndvi.iter_mut().enumerate() {
+            let nir = blocks[scene].as_u16().unwrap();
+            let red = blocks[scenes + scene].as_u16().unwrap();
+            Index::Ndvi.compute(nir.data(), red.data(), calibration[scene], ndvi);
+
+            if let Some(mask) = masks.get(scene) {
+                mask.apply(&blocks[2 * scenes + scene], &mut masked);
+                for (value, &masked) in ndvi.iter_mut().zip(&masked) {
+                    if masked {
+                        *value = f32::NAN;
+                    }
+                }
+            }
+        }
+
+        let pixels = scene_ndvi[0].len();
+        let mut composite = Vec::with_capacity(pixels);
+        let mut counts = Vec::with_capacity(pixels);
+        let mut selected = Vec::with_capacity(pixels);
+        for i in 0..pixels {
+            observations.clear();
+            observations.extend(
+                scene_ndvi
+                    .iter()
+                    .enumerate()
+                    .map(|(scene, ndvi)| (scene, ndvi[i]))
+                    .filter(|(_, v)| !v.is_nan()),
+            );
+            counts.push(observations.len() as f32);
+            let (value, scene) = args.reducer.reduce(percentile, &mut observations);
+            composite.push(value);
+            selected.push(scene.map_or(f32::NAN, |scene| scene as f32));
+        }
+
+        let region = block_reader.region(x, y);
+        let offset = window.relative_offset(&region);
+        stats.add_block(&composite);
+        output
+            .rasterband(1)?
+            .write_typed(offset, &mut encoding.encode(region.size, &composite))?;
+        output
+            .rasterband(2)?
+            .write_typed(offset, &mut plain.encode(region.size, &counts))?;
+        output
+            .rasterband(3)?
+            .write_typed(offset, &mut plain.encode(region.size, &selected))?;
+    }
+
+    block_reader.join();
+
+    if cancel.is_cancelled() {
+        args.format.discard(output, &args.output)?;
+        bail!("interrupted, {} not written", args.output);
+    }
+
+    stats::finalize_band(
+        &mut output.rasterband(1)?,
+        &stats,
+        &encoding,
+        &BandInfo {
+            description: "NDVI composite",
+            unit: "NDVI",
+        },
+    )?;
+    output
+        .rasterband(2)?
+        .set_description("valid observations")?;
+    let mut scene_band = output.rasterband(3)?;
+    scene_band.set_description("scene index (0-based, see SCENE_<index>_DATETIME)")?;
+    for (scene, path) in nir.iter().enumerate() {
+        if let Some(datetime) = stac::input_datetime(path) {
+            scene_band.set_metadata_item(&format!("SCENE_{scene}_DATETIME"), &datetime, "")?;
+        }
+    }
+    output.flush_cache()?;
+    let item = stac::raster_item(&output, &args.output, &provenance)?;
+    args.format.finish(output, &args.output, &provenance)?;
+    item.write()?;
+
+    println!(
+        "{:?} composite of {scenes} scenes written to {} in {:.3}s",
+        args.reducer,
+        args.output,
+        start.elapsed().as_secs_f64()
+    );
+    println!("NDVI {stats}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Valid observations of a pixel in scenes 0, 2, 3 and 5.
+    fn observations() -> Vec<(usize, f32)> {
+        vec![(0, 0.4), (2, 0.8), (3, 0.1), (5, 0.6)]
+    }
+
+    fn reduce(reducer: Reducer, percentile: f64) -> (f32, Option<usize>) {
+        reducer.reduce(percentile, &mut observations())
+    }
+
+    #[test]
+    fn max_and_min_pick_the_scene_of_the_value() {
+        assert_eq!(reduce(Reducer::Max, 50.0), (0.8, Some(2)));
+        assert_eq!(reduce(Reducer::Min, 50.0), (0.1, Some(3)));
+    }
+
+    #[test]
+    fn ties_keep_the_earliest_scene() {
+        let mut tied = vec![(1, 0.5), (4, 0.7), (6, 0.7), (7, 0.5)];
+        assert_eq!(Reducer::Max.reduce(50.0, &mut tied), (0.7, Some(4)));
+        assert_eq!(Reducer::Min.reduce(50.0, &mut tied), (0.5, Some(1)));
+    }
+
+    #[test]
+    fn median_and_mean_have_no_scene() {
+        assert_eq!(reduce(Reducer::Median, 0.0), (0.5, None));
+        let (mean, scene) = reduce(Reducer::Mean, 0.0);
+        assert!((mean - 0.475).abs() < 1e-6);
+        assert_eq!(scene, None);
+        assert_eq!(Reducer::Median.reduce(50.0, &mut [(3, 0.2)]), (0.2, None));
+    }
+
+    #[test]
+    fn percentiles_interpolate_between_observations() {
+        // Sorted values 0.1, 0.4, 0.6, 0.8 at ranks 0 to 3.
+        assert_eq!(reduce(Reducer::Percentile, 0.0).0, 0.1);
+        assert_eq!(reduce(Reducer::Percentile, 100.0).0, 0.8);
+        let (value, scene) = reduce(Reducer::Percentile, 25.0);
+        assert!((value - 0.325).abs() < 1e-6);
+        assert_eq!(scene, None);
+        assert!((quantile(&mut observations(), 0.9) - 0.74).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pixels_without_observations_are_nodata() {
+        for reducer in [
+            Reducer::Max,
+            Reducer::Min,
+            Reducer::Median,
+            Reducer::Mean,
+            Reducer::Percentile,
+        ] {
+            let (value, scene) = reducer.reduce(50.0, &mut []);
+            assert!(value.is_nan());
+            assert_eq!(scene, None);
+        }
+    }
+}
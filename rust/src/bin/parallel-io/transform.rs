@@ -0,0 +1,840 @@
+use std::time::Instant;
+
+use anyhow::{bail, ensure, Context as _, Result};
+use clap::ValueEnum;
+use gdal::{Dataset, Metadata as _};
+
+use crate::{
+    block_reader::ParallelBlockReader,
+    gdal_ext::RasterBandExt as _,
+    output_format::OutputFormatArgs,
+    output_type::{Encoding, OutputType},
+    progress,
+    provenance::{ChecksumArgs, Provenance},
+    reproject,
+    sensor::{Band, Calibration, SensorArgs},
+    stac,
+    stats::{self, BandInfo, Statistics},
+    vsi,
+};
+
+/// Range of surface reflectance assumed to size integer encodings of fixed transforms. Wider
+/// than 0 to 1: atmospheric correction leaves slightly negative values over dark water and
+/// shadows, and bright targets such as snow, clouds and specular reflections exceed 1.
+const REFLECTANCE_RANGE: (f64, f64) = (-0.2, 1.6);
+
+/// Bands taken from a STAC Item, in the order of the tasselled cap coefficients.
+const ITEM_BANDS: [Band; 6] = [
+    Band::Blue,
+    Band::Green,
+    Band::Red,
+    Band::Nir,
+    Band::Swir16,
+    Band::Swir22,
+];
+
+/// Published tasselled-cap coefficients for surface reflectance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TasselledCap {
+    /// Sentinel-2 MSI (Shi and Xu, 2019), from B02, B03, B04, B08, B11 and B12
+    #[value(name = "sentinel2")]
+    Sentinel2,
+    /// Landsat 8/9 OLI (Baig et al., 2014), from bands 2 to 7
+    #[value(name = "landsat-oli")]
+    LandsatOli,
+    /// Landsat 4/5 TM (Crist, 1985), from bands 1 to 5 and 7
+    #[value(name = "landsat-tm")]
+    LandsatTm,
+}
+
+impl TasselledCap {
+    /// Brightness, greenness and wetness coefficients.
+    fn coefficients(self) -> [[f64; 6]; 3] {
+        match self {
+            Self::Sentinel2 => [
+                [0.3510, 0.3813, 0.3437, 0.7196, 0.2396, 0.1949],
+                [-0.3599, -0.3533, -0.4734, 0.6633, 0.0087, -0.2856],
+                [0.2578, 0.2305, 0.0883, 0.1071, -0.7611, -0.5308],
+            ],
+            Self::LandsatOli => [
+                [0.3029, 0.2786, 0.4733, 0.5599, 0.5080, 0.1872],
+                [-0.2941, -0.2430, -0.5424, 0.7276, 0.0713, -0.1608],
+                [0.1511, 0.1973, 0.3283, 0.3407, -0.7117, -0.4559],
+            ],
+            Self::LandsatTm => [
+                [0.2043, 0.4158, 0.5524, 0.5741, 0.3124, 0.2303],
+                [-0.1603, -0.2819, -0.4934, 0.7940, -0.0002, -0.1446],
+                [0.0315, 0.2021, 0.3102, 0.1594, -0.6806, -0.6109],
+            ],
+        }
+    }
+}
+
+/// Applies a linear transform to a stack of bands: a tasselled cap, a custom coefficient matrix,
+/// or the principal components of the bands.
+#[derive(clap::Args)]
+#[command(group(
+    clap::ArgGroup::new("transform")
+        .required(true)
+        .args(["tasselled_cap", "matrix", "pca"])
+))]
+pub struct TransformArgs {
+    /// Bands, in the order of the coefficients (B02, B03, B04, B08, B11 and B12 for the
+    /// Sentinel-2 tasselled cap); bands on another grid are resampled to the first one.
+    /// `item=<path>` takes these six bands from a STAC Item
+    #[arg(long, num_args = 1.., required = true)]
+    bands: Vec<String>,
+
+    /// Tasselled cap brightness, greenness and wetness
+    #[arg(long, value_enum)]
+    tasselled_cap: Option<TasselledCap>,
+
+    /// CSV file of a custom transform, one output component per line as `name,c1,...,cN`
+    #[arg(long)]
+    matrix: Option<String>,
+
+    /// Principal components, from the covariance of the bands over their valid pixels
+    #[arg(long)]
+    pca: bool,
+
+    /// Number of components written, all by default
+    #[arg(long)]
+    components: Option<usize>,
+
+    /// Scale applied to the input digital numbers, instead of the one of the sensor (e.g. 1 for
+    /// bands that already hold reflectance)
+    #[arg(long)]
+    input_scale: Option<f64>,
+
+    /// Offset added to the scaled input digital numbers, instead of the one of the sensor
+    #[arg(long, allow_negative_numbers = true)]
+    input_offset: Option<f64>,
+
+    /// Input nodata digital number, instead of the one of the sensor; `nan` for none
+    #[arg(long, allow_negative_numbers = true)]
+    input_nodata: Option<f64>,
+
+    /// Output raster, with one band per component
+    #[arg(short, long)]
+    output: String,
+
+    /// Output data type
+    #[arg(long = "ot", value_enum, default_value_t = OutputType::Float32)]
+    output_type: OutputType,
+
+    #[command(flatten)]
+    format: OutputFormatArgs,
+
+    #[command(flatten)]
+    sensor: SensorArgs,
+
+    #[command(flatten)]
+    checksums: ChecksumArgs,
+
+    /// Number of threads reading blocks
+    #[arg(long, default_value_t = 8)]
+    io_threads: usize,
+}
+
+impl TransformArgs {
+    /// Paths the command writes.
+    #[must_use]
+    pub fn outputs(&self) -> Vec<&str> {
+        vec![self.output.as_str()]
+    }
+}
+
+/// Linear transform `y_k = sum_i c_ki (x_i - center_i)` of a band stack.
+struct LinearTransform {
+    names: Vec<String>,
+    /// One row of coefficients per component.
+    coefficients: Vec<Vec<f64>>,
+    center: Vec<f64>,
+}
+
+impl LinearTransform {
+    fn tasselled_cap(preset: TasselledCap) -> Self {
+        Self {
+            names: ["brightness", "greenness", "wetness"]
+                .map(str::to_owned)
+                .to_vec(),
+            coefficients: preset.coefficients().map(Vec::from).to_vec(),
+            center: vec![0.0; 6],
+        }
+    }
+
+    /// Reads a transform from a CSV file of `name,c1,...,cN` lines, skipping blank lines and
+    /// `#` comments.
+    fn from_csv(path: &str) -> Result<Self> {
+        Self::from_text(&vsi::read_to_string(path)?, path)
+    }
+
+    /// Parses the content of the CSV file `path`.
+    fn from_text(text: &str, path: &str) -> Result<Self> {
+        let mut names = Vec::new();
+        let mut coefficients: Vec<Vec<f64>> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(',').map(str::trim);
+            names.push(fields.next().unwrap().to_owned());
+            let row = fields
+                .map(str::parse)
+                .collect::<Result<Vec<f64>, _>>()
+                .with_context(|| format!("invalid coefficient on line {} of {path}", i + 1))?;
+            ensure!(
+                !row.is_empty(),
+                "line {} of {path} has no coefficients",
+                i + 1
+            );
+            if let Some(first) = coefficients.first() {
+                ensure!(
+                    row.len() == first.len(),
+                    "line {} of {path} has {} coefficients instead of {}",
+                    i + 1,
+                    row.len(),
+                    first.len()
+                );
+            }
+            coefficients.push(row);
+        }
+        ensure!(!names.is_empty(), "no components in {path}");
+
+        let bands = coefficients[0].len();
+        Ok(Self {
+            names,
+            coefficients,
+            center: vec![0.0; bands],
+        })
+    }
+
+    /// Principal components of `covariance`, by decreasing variance, with their variances.
+    fn pca(covariance: &Covariance) -> (Self, Vec<f64>) {
+        let n = covariance.mean.len();
+        let (variances, vectors) = symmetric_eigen(covariance.matrix(), n);
+        let transform = Self {
+            names: (1..=n).map(|i| format!("PC{i}")).collect(),
+            coefficients: vectors,
+            center: covariance.mean.clone(),
+        };
+        (transform, variances)
+    }
+
+    fn bands(&self) -> usize {
+        self.center.len()
+    }
+
+    fn truncate(&mut self, components: usize) {
+        self.names.truncate(components);
+        self.coefficients.truncate(components);
+    }
+
+    /// Range of each component for band values within `ranges`.
+    fn ranges(&self, ranges: &[(f64, f64)]) -> Vec<(f64, f64)> {
+        self.coefficients
+            .iter()
+            .map(|row| {
+                row.iter().zip(ranges).zip(&self.center).fold(
+                    (0.0, 0.0),
+                    |(lo, hi), ((&c, &(min, max)), &center)| {
+                        let (a, b) = (c * (min - center), c * (max - center));
+                        (lo + a.min(b), hi + a.max(b))
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Transforms a block of band values into `components`. Pixels with a NaN band are NaN in
+    /// every component.
+    fn apply(&self, bands: &[Vec<f32>], components: &mut [Vec<f32>]) {
+        let pixels = bands[0].len();
+        for component in components.iter_mut() {
+            component.clear();
+            component.reserve(pixels);
+        }
+
+        let mut pixel = vec![0.0; bands.len()];
+        for i in 0..pixels {
+            for ((value, band), center) in pixel.iter_mut().zip(bands).zip(&self.center) {
+                *value = band[i] as f64 - center;
+            }
+            let valid = pixel.iter().all(|v| !v.is_nan());
+            for (component, row) in components.iter_mut().zip(&self.coefficients) {
+                component.push(if valid {
+                    row.iter().zip(&pixel).map(|(c, v)| c * v).sum::<f64>() as f32
+                } else {
+                    f32::NAN
+                });
+            }
+        }
+    }
+}
+
+/// Mean and covariance of a band stack, accumulated block by block over the pixels valid in
+/// every band.
+struct Covariance {
+    count: f64,
+    mean: Vec<f64>,
+    /// Sums of products of deviations from the mean, row-major.
+    comoment: Vec<f64>,
+    min: Vec<f64>,
+    max: Vec<f64>,
+}
+
+impl Covariance {
+    fn new(bands: usize) -> Self {
+        Self {
+            count: 0.0,
+            mean: vec![0.0; bands],
+            comoment: vec![0.0; bands * bands],
+            min: vec![f64::INFINITY; bands],
+            max: vec![f64::NEG_INFINITY; bands],
+        }
+    }
+
+    /// Adds a block, merging its own mean and co-moments (Chan et al.) to stay accurate over
+    /// many pixels.
+    fn add_block(&mut self, bands: &[Vec<f32>]) {
+        let n = bands.len();
+        let valid = (0..bands[0].len())
+            .filter(|&i| bands.iter().all(|band| !band[i].is_nan()))
+            .collect::<Vec<_>>();
+        if valid.is_empty() {
+            return;
+        }
+
+        let count = valid.len() as f64;
+        let mean = bands
+            .iter()
+            .map(|band| valid.iter().map(|&i| band[i] as f64).sum::<f64>() / count)
+            .collect::<Vec<_>>();
+        let mut comoment = vec![0.0; n * n];
+        let mut deviation = vec![0.0; n];
+        for &i in &valid {
+            for (b, band) in bands.iter().enumerate() {
+                let value = band[i] as f64;
+                deviation[b] = value - mean[b];
+                self.min[b] = self.min[b].min(value);
+                self.max[b] = self.max[b].max(value);
+            }
+            for p in 0..n {
+                for q in p..n {
+                    comoment[p * n + q] += deviation[p] * deviation[q];
+                }
+            }
+        }
+
+        let total = self.count + count;
+        let delta = mean
+            .iter()
+            .zip(&self.mean)
+            .map(|(b, a)| b - a)
+            .collect::<Vec<_>>();
+        for p in 0..n {
+            for q in p..n {
+                self.comoment[p * n + q] +=
+                    comoment[p * n + q] + delta[p] * delta[q] * self.count * count / total;
+                self.comoment[q * n + p] = self.comoment[p * n + q];
+            }
+            self.mean[p] += delta[p] * count / total;
+        }
+        self.count = total;
+    }
+
+    /// Sample covariance matrix, row-major.
+    fn matrix(&self) -> Vec<f64> {
+        self.comoment
+            .iter()
+            .map(|c| c / (self.count - 1.0))
+            .collect()
+    }
+}
+
+/// Eigenvalues and unit eigenvectors of the symmetric `n`x`n` matrix `a` by cyclic Jacobi
+/// rotations, sorted by decreasing eigenvalue. Each eigenvector has its largest loading positive.
+fn symmetric_eigen(mut a: Vec<f64>, n: usize) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let mut v = vec![0.0; n * n];
+    for i in 0..n {
+        v[i * n + i] = 1.0;
+    }
+
+    let norm = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    for _sweep in 0..64 {
+        let off_diagonal = (0..n)
+            .flat_map(|p| (p + 1..n).map(move |q| (p, q)))
+            .map(|(p, q)| a[p * n + q] * a[p * n + q])
+            .sum::<f64>()
+            .sqrt();
+        if off_diagonal <= f64::EPSILON * norm {
+            break;
+        }
+
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[p * n + q];
+                if apq == 0.0 {
+                    continue;
+                }
+                let theta = (a[q * n + q] - a[p * n + p]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[k * n + p], a[k * n + q]);
+                    a[k * n + p] = c * akp - s * akq;
+                    a[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p * n + k], a[q * n + k]);
+                    a[p * n + k] = c * apk - s * aqk;
+                    a[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k * n + p], v[k * n + q]);
+                    v[k * n + p] = c * vkp - s * vkq;
+                    v[k * n + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    let mut order = (0..n).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| a[j * n + j].total_cmp(&a[i * n + i]));
+    let values = order.iter().map(|&i| a[i * n + i]).collect();
+    let vectors = order
+        .iter()
+        .map(|&i| {
+            let vector = (0..n).map(|k| v[k * n + i]).collect::<Vec<_>>();
+            let largest = vector
+                .iter()
+                .copied()
+                .max_by(|x, y| x.abs().total_cmp(&y.abs()))
+                .unwrap();
+            vector.into_iter().map(|x| x * largest.signum()).collect()
+        })
+        .collect();
+    (values, vectors)
+}
+
+/// Reads every block of `inputs` and passes its offset, its size and the calibrated band values,
+/// with NaN marking nodata, to `f`. Fails once all blocks are read if the run was interrupted.
+fn for_each_block(
+    args: &TransformArgs,
+    inputs: &[String],
+    calibration: &[Calibration],
+    mut f: impl FnMut((isize, isize), (usize, usize), &[Vec<f32>]) -> Result<()>,
+) -> Result<()> {
+    let cancel = progress::interrupt_token();
+    let mut block_reader = ParallelBlockReader::new(inputs, args.io_threads, None)?
+        .on_progress(progress::bar())
+        .cancel_on(cancel.clone());
+    if let Some(capacity) = args.format.ordered {
+        block_reader = block_reader.ordered(capacity);
+    }
+
+    let (tx, rx) = flume::bounded(args.io_threads);
+    let dataset_indices = (0..inputs.len()).collect::<Vec<_>>();
+    for y in block_reader.blocks.1.clone() {
+        for x in block_reader.blocks.0.clone() {
+            let tx = tx.clone();
+            block_reader.run(
+                x,
+                y,
+                &dataset_indices,
+                Box::new(move |x, y, blocks| {
+                    tx.send((x, y, blocks)).unwrap();
+                }),
+            );
+        }
+    }
+    drop(tx);
+
+    let mut bands = vec![Vec::new(); inputs.len()];
+    for (x, y, mut blocks) in rx {
+        for (i, (values, calibration)) in bands.iter_mut().zip(calibration).enumerate() {
+            values.clear();
+            values.extend(
+                blocks
+                    .remove(&i)
+                    .unwrap()
+                    .to_f64()
+                    .into_iter()
+                    .map(|dn| calibration.reflectance(dn) as f32),
+            );
+        }
+        let region = block_reader.region(x, y);
+        f(
+            block_reader.window.relative_offset(&region),
+            region.size,
+            &bands,
+        )?;
+    }
+
+    block_reader.join();
+    ensure!(
+        !cancel.is_cancelled(),
+        "interrupted, {} not written",
+        args.output
+    );
+    Ok(())
+}
+
+/// Computes the transform (after a first pass for the covariance, for PCA) block by block.
+pub fn run(args: &TransformArgs) -> Result<()> {
+    let start = Instant::now();
+    let bands = match args.bands.as_slice() {
+        [item] if item.starts_with(stac::ITEM_PREFIX) => {
+            let item = stac::read_item(item)?;
+            ITEM_BANDS
+                .iter()
+                .map(|&band| Ok(item.band(band)?.to_owned()))
+                .collect::<Result<Vec<_>>>()?
+        }
+        bands => bands.to_vec(),
+    };
+
+    let calibration = bands
+        .iter()
+        .map(|path| {
+            let calibration = args.sensor.calibration(path)?;
+            Ok(Calibration {
+                scale: args.input_scale.unwrap_or(calibration.scale),
+                offset: args.input_offset.unwrap_or(calibration.offset),
+                nodata: args.input_nodata.or(calibration.nodata),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let reference = Dataset::open(&bands[0])?;
+    let mut inputs = vec![bands[0].clone()];
+    for (i, path) in bands.iter().enumerate().skip(1) {
+        let dataset = Dataset::open(path)?;
+        if dataset.raster_size() == reference.raster_size()
+            && dataset.geo_transform()? == reference.geo_transform()?
+        {
+            inputs.push(path.clone());
+        } else {
+            // E.g. the 20 m B11 and B12 of the tasselled cap with the 10 m bands.
+            inputs.push(reproject::warp_to_grid(
+                path,
+                &reference,
+                &format!("transform_{i}"),
+            )?);
+        }
+    }
+
+    let (mut transform, variances, ranges) = if let Some(preset) = args.tasselled_cap {
+        let transform = LinearTransform::tasselled_cap(preset);
+        let ranges = vec![REFLECTANCE_RANGE; transform.bands()];
+        (transform, None, ranges)
+    } else if let Some(path) = &args.matrix {
+        let transform = LinearTransform::from_csv(path)?;
+        let ranges = vec![REFLECTANCE_RANGE; transform.bands()];
+        (transform, None, ranges)
+    } else {
+        let mut covariance = Covariance::new(inputs.len());
+        for_each_block(args, &inputs, &calibration, |_, _, bands| {
+            covariance.add_block(bands);
+            Ok(())
+        })?;
+        ensure!(
+            covariance.count > inputs.len() as f64,
+            "not enough valid pixels for PCA"
+        );
+        let (transform, variances) = LinearTransform::pca(&covariance);
+        let ranges = covariance
+            .min
+            .iter()
+            .copied()
+            .zip(covariance.max.iter().copied())
+            .collect::<Vec<_>>();
+        (transform, Some(variances), ranges)
+    };
+    ensure!(
+        transform.bands() == inputs.len(),
+        "the transform needs {} bands, got {}",
+        transform.bands(),
+        inputs.len()
+    );
+    if let Some(components) = args.components {
+        if components == 0 || components > transform.names.len() {
+            bail!(
+                "--components must be between 1 and {}",
+                transform.names.len()
+            );
+        }
+        transform.truncate(components);
+    }
+    let components = transform.names.len();
+
+    let encodings = transform
+        .ranges(&ranges)
+        .into_iter()
+        .map(|range| Encoding::new(args.output_type, range, None, None))
+        .collect::<Result<Vec<_>>>()?;
+    let mut output = args.format.create(
+        args.output_type,
+        &args.output,
+        reference.raster_size(),
+        components,
+    )?;
+    output.set_projection(&reference.projection())?;
+    output.set_geo_transform(&reference.geo_transform()?)?;
+    output.set_metadata_item("TRANSFORM_INPUTS", &bands.join(","), "")?;
+    args.sensor.set_metadata(&mut output, &bands)?;
+    let expression = if let Some(preset) = args.tasselled_cap {
+        format!(
+            "{} tasselled cap of reflectance",
+            preset.to_possible_value().unwrap().get_name()
+        )
+    } else if let Some(path) = &args.matrix {
+        format!("linear transform of reflectance with the coefficients of {path}")
+    } else {
+        "principal components of reflectance".to_owned()
+    };
+    let provenance = Provenance::new(&bands, expression)
+        .with_calibration(&calibration)
+        .with_checksums(&args.checksums)?;
+    provenance.write(&mut output)?;
+    if variances.is_some() {
+        let means = transform
+            .center
+            .iter()
+            .map(f64::to_string)
+            .collect::<Vec<_>>();
+        output.set_metadata_item("PCA_MEANS", &means.join(","), "")?;
+    }
+    for (i, encoding) in encodings.iter().enumerate() {
+        let mut band = output.rasterband(i + 1)?;
+        encoding.apply(&mut band)?;
+        let loadings = transform.coefficients[i]
+            .iter()
+            .map(f64::to_string)
+            .collect::<Vec<_>>();
+        band.set_metadata_item("COEFFICIENTS", &loadings.join(","), "")?;
+    }
+
+    let mut stats = vec![Statistics::new(None); components];
+    let mut values = vec![Vec::new(); components];
+    let result = for_each_block(args, &inputs, &calibration, |offset, size, bands| {
+        transform.apply(bands, &mut values);
+        for (i, (component, encoding)) in values.iter().zip(&encodings).enumerate() {
+            stats[i].add_block(component);
+            output
+                .rasterband(i + 1)?
+                .write_typed(offset, &mut encoding.encode(size, component))?;
+        }
+        Ok(())
+    });
+    if let Err(error) = result {
+        if progress::interrupt_token().is_cancelled() {
+            args.format.discard(output, &args.output)?;
+        }
+        return Err(error);
+    }
+
+    for (i, name) in transform.names.iter().enumerate() {
+        stats::finalize_band(
+            &mut output.rasterband(i + 1)?,
+            &stats[i],
+            &encodings[i],
+            &BandInfo {
+                description: name,
+                unit: "",
+            },
+        )?;
+    }
+    if let Some(variances) = &variances {
+        let total = variances.iter().sum::<f64>();
+        for (i, variance) in variances.iter().take(components).enumerate() {
+            let mut band = output.rasterband(i + 1)?;
+            band.set_metadata_item("EIGENVALUE", &variance.to_string(), "")?;
+            band.set_metadata_item(
+                "EXPLAINED_VARIANCE",
+                &format!("{:.6}", variance / total),
+                "",
+            )?;
+        }
+    }
+    output.flush_cache()?;
+    let item = stac::raster_item(&output, &args.output, &provenance)?;
+    args.format.finish(output, &args.output, &provenance)?;
+    item.write()?;
+
+    println!(
+        "{components} components written to {} in {:.3}s",
+        args.output,
+        start.elapsed().as_secs_f64()
+    );
+    for (i, name) in transform.names.iter().enumerate() {
+        match &variances {
+            Some(variances) => println!(
+                "{name} {:.1}% of variance, {}",
+                variances[i] / variances.iter().sum::<f64>() * 100.0,
+                stats[i]
+            ),
+            None => println!("{name} {}", stats[i]),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn matrix_csv_skips_comments_and_blank_lines() {
+        let text = "# name,blue,red\n\nndvi_like, -0.5, 0.5\n sum,1,1\n";
+        let transform = LinearTransform::from_text(text, "m.csv").unwrap();
+        assert_eq!(transform.names, ["ndvi_like", "sum"]);
+        assert_eq!(transform.coefficients, [[-0.5, 0.5], [1.0, 1.0]]);
+        assert_eq!(transform.bands(), 2);
+    }
+
+    #[test]
+    fn matrix_csv_rejects_malformed_lines() {
+        let error = |text| {
+            LinearTransform::from_text(text, "m.csv")
+                .err()
+                .map(|e| e.to_string())
+                .unwrap()
+        };
+        assert_eq!(error("a,1,2\nb\n"), "line 2 of m.csv has no coefficients");
+        assert_eq!(error("a\n"), "line 1 of m.csv has no coefficients");
+        assert_eq!(
+            error("a,1,2\nb,1\n"),
+            "line 2 of m.csv has 1 coefficients instead of 2"
+        );
+        assert_eq!(error("a,1,x\n"), "invalid coefficient on line 1 of m.csv");
+        assert_eq!(error("# only a comment\n"), "no components in m.csv");
+    }
+
+    #[test]
+    fn ranges_cover_every_band_combination() {
+        let transform = LinearTransform::from_text("a,1,-2", "m.csv").unwrap();
+        assert_eq!(transform.ranges(&[(0.0, 1.0), (0.0, 1.0)]), [(-2.0, 1.0)]);
+    }
+
+    #[test]
+    fn nodata_in_any_band_gives_nodata_components() {
+        let transform = LinearTransform::from_text("a,1,2\nb,1,-1", "m.csv").unwrap();
+        let mut components = vec![Vec::new(); 2];
+        transform.apply(&[vec![1.0, f32::NAN], vec![2.0, 3.0]], &mut components);
+        assert_eq!(components[0][0], 5.0);
+        assert_eq!(components[1][0], -1.0);
+        assert!(components[0][1].is_nan() && components[1][1].is_nan());
+    }
+
+    #[test]
+    fn eigen_decomposition_of_a_known_matrix() {
+        let (values, vectors) = symmetric_eigen(vec![2.0, 1.0, 1.0, 2.0], 2);
+        assert_close(values[0], 3.0);
+        assert_close(values[1], 1.0);
+        let h = 0.5f64.sqrt();
+        assert_close(vectors[0][0], h);
+        assert_close(vectors[0][1], h);
+        // Largest loading positive, whichever one it is for equal magnitudes.
+        assert_close(vectors[1][0].abs(), h);
+        assert_close(vectors[1][0], -vectors[1][1]);
+    }
+
+    #[test]
+    fn eigenvectors_are_orthonormal_and_sorted() {
+        #[rustfmt::skip]
+        let a = vec![
+            4.0, 1.0, -2.0, 2.0,
+            1.0, 2.0, 0.0, 1.0,
+            -2.0, 0.0, 3.0, -2.0,
+            2.0, 1.0, -2.0, -1.0,
+        ];
+        let n = 4;
+        let (values, vectors) = symmetric_eigen(a.clone(), n);
+        assert!(values.windows(2).all(|w| w[0] >= w[1]));
+        assert_close(values.iter().sum(), 4.0 + 2.0 + 3.0 - 1.0);
+        for (value, vector) in values.iter().zip(&vectors) {
+            for row in 0..n {
+                let av = (0..n).map(|k| a[row * n + k] * vector[k]).sum::<f64>();
+                assert_close(av, value * vector[row]);
+            }
+            let largest = vector
+                .iter()
+                .copied()
+                .max_by(|x, y| x.abs().total_cmp(&y.abs()))
+                .unwrap();
+            assert!(largest > 0.0);
+        }
+        for (i, u) in vectors.iter().enumerate() {
+            for (j, v) in vectors.iter().enumerate() {
+                let dot = u.iter().zip(v).map(|(a, b)| a * b).sum::<f64>();
+                assert_close(dot, if i == j { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    #[test]
+    fn covariance_merged_over_blocks_matches_a_single_pass() {
+        let x = [1.0, 2.0, 4.0, 7.0, 11.0, 3.0, 5.0];
+        let y = [2.0, 1.0, 5.0, 8.0, 9.0, 4.0, 4.0];
+
+        let mut single = Covariance::new(2);
+        single.add_block(&[x.to_vec(), y.to_vec()]);
+        let mut blocks = Covariance::new(2);
+        blocks.add_block(&[x[..2].to_vec(), y[..2].to_vec()]);
+        // A block without valid pixels is skipped.
+        blocks.add_block(&[vec![f32::NAN], vec![1.0]]);
+        blocks.add_block(&[x[2..].to_vec(), y[2..].to_vec()]);
+
+        let n = x.len() as f64;
+        let mean_x = x.iter().map(|&v| v as f64).sum::<f64>() / n;
+        let mean_y = y.iter().map(|&v| v as f64).sum::<f64>() / n;
+        let cov = |a: &[f32], ma: f64, b: &[f32], mb: f64| {
+            a.iter()
+                .zip(b)
+                .map(|(&a, &b)| (a as f64 - ma) * (b as f64 - mb))
+                .sum::<f64>()
+                / (n - 1.0)
+        };
+        let expected = [
+            cov(&x, mean_x, &x, mean_x),
+            cov(&x, mean_x, &y, mean_y),
+            cov(&y, mean_y, &x, mean_x),
+            cov(&y, mean_y, &y, mean_y),
+        ];
+
+        for covariance in [&single, &blocks] {
+            assert_eq!(covariance.count, n);
+            assert_close(covariance.mean[0], mean_x);
+            assert_close(covariance.mean[1], mean_y);
+            for (a, b) in covariance.matrix().iter().zip(expected) {
+                assert_close(*a, b);
+            }
+            assert_eq!((covariance.min[0], covariance.max[0]), (1.0, 11.0));
+            assert_eq!((covariance.min[1], covariance.max[1]), (1.0, 9.0));
+        }
+    }
+
+    #[test]
+    fn principal_components_are_centred_on_the_mean() {
+        let mut covariance = Covariance::new(2);
+        covariance.add_block(&[vec![0.0, 2.0, 4.0], vec![1.0, 3.0, 5.0]]);
+        let (transform, variances) = LinearTransform::pca(&covariance);
+        assert_eq!(transform.names, ["PC1", "PC2"]);
+        assert_eq!(transform.center, [2.0, 3.0]);
+        assert_close(variances[0], 8.0);
+        assert_close(variances[1], 0.0);
+
+        let mut components = vec![Vec::new(); 2];
+        transform.apply(&[vec![4.0], vec![5.0]], &mut components);
+        assert!((components[0][0] - 8.0f32.sqrt()).abs() < 1e-6);
+        assert!(components[1][0].abs() < 1e-6);
+    }
+}
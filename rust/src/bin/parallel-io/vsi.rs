@@ -0,0 +1,259 @@
+//! Inputs and outputs on GDAL virtual file systems: `/vsimem/`, `/vsizip/`, `/vsistdout/`, and
+//! `/vsis3/` and the other object stores.
+//!
+//! Drivers update most formats in place while blocks arrive in any order, which only local files
+//! and `/vsimem/` allow; the other virtual file systems take one sequential write. Outputs bound
+//! there are written to a local staging file from [`writable_path`], then streamed to their
+//! destination once complete with [`commit`].
+//!
+//! Staging is used even with `--ordered`, whose blocks arrive in raster order: the commands set
+//! statistics, histograms, scaling and provenance once every block is written, which GDAL stores
+//! in the GeoTIFF header or in files of their own, so no format is complete after one sequential
+//! pass. The cost is local disk space for the whole output and an upload that only starts once
+//! processing is done. Staging files of failed runs are removed by [`remove_staged`].
+
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    hash::{DefaultHasher, Hash as _, Hasher as _},
+    io,
+    path::Path,
+    process,
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use anyhow::{ensure, Context as _, Result};
+use libc::c_void;
+use parking_lot::Mutex;
+
+use crate::gdal_ext::VsiFile;
+
+/// Prefix of standard output paths; `/vsistdout/ndvi.tif` picks the format by extension.
+const STDOUT: &str = "/vsistdout";
+
+/// Whether GDAL drivers can seek back and update `path` while writing it.
+#[must_use]
+pub fn is_random_access(path: &str) -> bool {
+    !path.starts_with("/vsi") || path.starts_with("/vsimem/")
+}
+
+/// Whether `path` is standard output, which takes the output alone, without sidecar files.
+#[must_use]
+pub fn is_stdout(path: &str) -> bool {
+    path.starts_with(STDOUT)
+}
+
+/// Staging files handed out by [`staging_path`] and not committed yet.
+static STAGED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Local file to write an output bound for `destination` to, keeping its file name so that
+/// drivers pick the same format.
+///
+/// The name also holds a hash of the whole destination, so that outputs with the same file name
+/// in different directories or buckets get staging files of their own, while the same destination
+/// always maps to the same file.
+#[must_use]
+pub fn staging_path(destination: &str) -> String {
+    let destination = destination.trim_end_matches('/');
+    let name = Path::new(destination)
+        .file_name()
+        .map_or_else(|| "output".into(), |name| name.to_string_lossy());
+    let mut hasher = DefaultHasher::new();
+    destination.hash(&mut hasher);
+    let path = env::temp_dir()
+        .join(format!(
+            "parallel-io-{}-{:016x}-{name}",
+            process::id(),
+            hasher.finish()
+        ))
+        .to_string_lossy()
+        .into_owned();
+    STAGED.lock().insert(path.clone());
+    path
+}
+
+/// Removes the staging files of outputs that were not committed, after a command failed.
+pub fn remove_staged() {
+    for path in std::mem::take(&mut *STAGED.lock()) {
+        let path = Path::new(&path);
+        // Already gone if the command discarded its output itself.
+        let _ = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+    }
+}
+
+/// Path for drivers to write the output bound for `destination` to: the destination itself if
+/// it is random access, a staging file otherwise.
+#[must_use]
+pub fn writable_path(destination: &str) -> String {
+    if is_random_access(destination) {
+        destination.to_owned()
+    } else {
+        staging_path(destination)
+    }
+}
+
+/// Streams the complete output written at `path` to `destination` and removes it, if `path` is
+/// a staging file. The dataset at `path` must be closed.
+pub fn commit(path: &str, destination: &str) -> Result<()> {
+    if path == destination {
+        return Ok(());
+    }
+    let mut staged = fs::File::open(path).with_context(|| format!("reading {path}"))?;
+    let mut file = VsiFile::create(destination)?;
+    io::copy(&mut staged, &mut file).with_context(|| format!("writing {destination}"))?;
+    file.close()
+        .with_context(|| format!("writing {destination}"))?;
+    fs::remove_file(path).with_context(|| format!("removing {path}"))?;
+    STAGED.lock().remove(path);
+    Ok(())
+}
+
+/// Contents of the text file at `path`, local or on a GDAL virtual file system.
+pub fn read_to_string(path: &str) -> Result<String> {
+    let mut text = String::new();
+    io::Read::read_to_string(&mut VsiFile::open(path)?, &mut text)
+        .with_context(|| format!("reading {path}"))?;
+    Ok(text)
+}
+
+/// Writes `contents` to `path`, local or on a GDAL virtual file system.
+pub fn write(path: &str, contents: &[u8]) -> Result<()> {
+    let mut file = VsiFile::create(path)?;
+    io::Write::write_all(&mut file, contents).with_context(|| format!("writing {path}"))?;
+    Ok(file.close()?)
+}
+
+/// Descriptor of the original standard output, once [`reserve_stdout`] has moved it.
+static STDOUT_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// `VSIStdoutSetRedirection` callback writing to [`STDOUT_FD`].
+unsafe extern "C" fn write_stdout(
+    data: *const c_void,
+    size: usize,
+    count: usize,
+    _stream: *mut gdal_sys::FILE,
+) -> usize {
+    let fd = STDOUT_FD.load(Ordering::Relaxed);
+    let mut data = data.cast::<u8>();
+    let mut remaining = size * count;
+    while remaining > 0 {
+        let written = unsafe { libc::write(fd, data.cast(), remaining as _) };
+        if written <= 0 {
+            return (size * count - remaining) / size.max(1);
+        }
+        remaining -= written as usize;
+        data = unsafe { data.add(written as usize) };
+    }
+    count
+}
+
+/// Keeps standard output for `/vsistdout/` alone, sending what the commands print to standard
+/// error instead, so that messages do not end up in the middle of a piped raster.
+pub fn reserve_stdout() -> Result<()> {
+    let fd = unsafe { libc::dup(1) };
+    ensure!(
+        fd >= 0,
+        "duplicating stdout: {}",
+        io::Error::last_os_error()
+    );
+    STDOUT_FD.store(fd, Ordering::Relaxed);
+    unsafe { gdal_sys::VSIStdoutSetRedirection(Some(write_stdout), std::ptr::null_mut()) };
+    ensure!(
+        unsafe { libc::dup2(2, 1) } >= 0,
+        "redirecting stdout: {}",
+        io::Error::last_os_error()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Held by the tests writing staging files, which [`remove_staged`] would delete under the
+    /// feet of another test.
+    static STAGING: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn only_local_files_and_memory_are_random_access() {
+        assert!(is_random_access("ndvi.tif"));
+        assert!(is_random_access("/data/ndvi.tif"));
+        assert!(is_random_access("/vsimem/ndvi.tif"));
+        assert!(!is_random_access("/vsis3/bucket/ndvi.tif"));
+        assert!(!is_random_access("/vsizip/out.zip/ndvi.tif"));
+        assert!(!is_random_access("/vsistdout/ndvi.tif"));
+        assert!(is_stdout("/vsistdout/"));
+        assert!(!is_stdout("/data/vsistdout/ndvi.tif"));
+    }
+
+    #[test]
+    fn staging_files_keep_the_file_name() {
+        assert_eq!(writable_path("/data/ndvi.tif"), "/data/ndvi.tif");
+        let staged = writable_path("/vsis3/bucket/out/ndvi.tif");
+        let name = Path::new(&staged).file_name().unwrap().to_string_lossy();
+        assert!(name.starts_with(&format!("parallel-io-{}-", process::id())));
+        assert!(name.ends_with("-ndvi.tif"));
+        assert!(Path::new(&staged).starts_with(env::temp_dir()));
+        assert!(staging_path("/vsis3/bucket/out/ndvi.zarr/").ends_with("-ndvi.zarr"));
+    }
+
+    #[test]
+    fn destinations_with_the_same_file_name_are_staged_apart() {
+        let a = staging_path("/vsis3/a/ndvi.tif");
+        let b = staging_path("/vsis3/b/ndvi.tif");
+        assert_ne!(a, b);
+        assert_eq!(staging_path("/vsis3/a/ndvi.tif"), a);
+        assert_eq!(
+            staging_path("/vsis3/a/ndvi.zarr/"),
+            staging_path("/vsis3/a/ndvi.zarr")
+        );
+    }
+
+    #[test]
+    fn uncommitted_staging_files_are_removed() {
+        let _staging = STAGING.lock();
+        let file = staging_path("/vsis3/bucket/failed.tif");
+        let store = staging_path("/vsis3/bucket/failed.zarr");
+        fs::write(&file, b"partial").unwrap();
+        fs::create_dir_all(Path::new(&store).join("ndvi")).unwrap();
+        fs::write(Path::new(&store).join("ndvi/0.0"), b"chunk").unwrap();
+
+        remove_staged();
+        assert!(!Path::new(&file).exists());
+        assert!(!Path::new(&store).exists());
+    }
+
+    #[test]
+    fn commit_streams_the_staged_output_and_removes_it() {
+        let _staging = STAGING.lock();
+        let destination = "/vsimem/committed.tif";
+        let staged = staging_path(destination);
+        fs::write(&staged, b"complete").unwrap();
+        commit(&staged, destination).unwrap();
+        assert_eq!(read_to_string(destination).unwrap(), "complete");
+        assert!(!Path::new(&staged).exists());
+        assert!(!STAGED.lock().contains(&staged));
+    }
+
+    /// Round trip through an S3-compatible store, e.g. a local MinIO with GDAL pointed at it:
+    /// `AWS_S3_ENDPOINT=localhost:9000 AWS_HTTPS=NO AWS_VIRTUAL_HOSTING=FALSE`, its credentials
+    /// in `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, and `PARALLEL_IO_TEST_S3` naming a
+    /// writable `/vsis3/bucket/prefix`.
+    #[test]
+    #[ignore = "needs an S3-compatible store, see the comment"]
+    fn commit_uploads_to_an_object_store() {
+        let _staging = STAGING.lock();
+        let prefix = env::var("PARALLEL_IO_TEST_S3").expect("PARALLEL_IO_TEST_S3 is not set");
+        let destination = format!("{}/parallel-io-{}.txt", prefix, process::id());
+        let staged = writable_path(&destination);
+        assert_ne!(staged, destination);
+        fs::write(&staged, b"uploaded").unwrap();
+        commit(&staged, &destination).unwrap();
+        assert_eq!(read_to_string(&destination).unwrap(), "uploaded");
+        assert!(!Path::new(&staged).exists());
+    }
+}
@@ -0,0 +1,267 @@
+use std::ops::Range;
+
+use anyhow::{ensure, Result};
+use gdal::{
+    spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef},
+    Dataset, GeoTransform, GeoTransformEx as _,
+};
+
+/// Rectangle of raster pixels, as `(x, y)` offset and `(width, height)` size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelWindow {
+    pub offset: (usize, usize),
+    pub size: (usize, usize),
+}
+
+impl PixelWindow {
+    /// The whole raster.
+    #[must_use]
+    pub fn full(raster_size: (usize, usize)) -> Self {
+        Self {
+            offset: (0, 0),
+            size: raster_size,
+        }
+    }
+
+    /// Window from `gdal_translate -srcwin` style `xoff yoff xsize ysize`, clipped to the raster.
+    pub fn from_srcwin(srcwin: [isize; 4], raster_size: (usize, usize)) -> Result<Self> {
+        let [xoff, yoff, xsize, ysize] = srcwin;
+        Self::clipped(
+            (xoff, yoff),
+            (xoff.saturating_add(xsize), yoff.saturating_add(ysize)),
+            raster_size,
+        )
+    }
+
+    /// Window covering `[min_x, min_y, max_x, max_y]` in the raster CRS, clipped to the raster.
+    pub fn from_bounds(
+        geo_transform: &GeoTransform,
+        bounds: [f64; 4],
+        raster_size: (usize, usize),
+    ) -> Result<Self> {
+        let inverse = geo_transform.invert()?;
+        let [min_x, min_y, max_x, max_y] = bounds;
+        let corners = [
+            inverse.apply(min_x, min_y),
+            inverse.apply(min_x, max_y),
+            inverse.apply(max_x, min_y),
+            inverse.apply(max_x, max_y),
+        ];
+
+        let (mut min_px, mut min_py) = (f64::INFINITY, f64::INFINITY);
+        let (mut max_px, mut max_py) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for (px, py) in corners {
+            min_px = min_px.min(px);
+            min_py = min_py.min(py);
+            max_px = max_px.max(px);
+            max_py = max_py.max(py);
+        }
+
+        Self::clipped(
+            (min_px.floor() as isize, min_py.floor() as isize),
+            (max_px.ceil() as isize, max_py.ceil() as isize),
+            raster_size,
+        )
+    }
+
+    /// Window from `gdal_translate -projwin` style `ulx uly lrx lry` in the raster CRS.
+    pub fn from_projwin(
+        geo_transform: &GeoTransform,
+        projwin: [f64; 4],
+        raster_size: (usize, usize),
+    ) -> Result<Self> {
+        let [ulx, uly, lrx, lry] = projwin;
+        Self::from_bounds(
+            geo_transform,
+            [ulx.min(lrx), uly.min(lry), ulx.max(lrx), uly.max(lry)],
+            raster_size,
+        )
+    }
+
+    /// Window covering a WGS84 `west south east north` box, transformed to the CRS of `dataset`.
+    pub fn from_lonlat_bbox(dataset: &Dataset, bbox: [f64; 4]) -> Result<Self> {
+        let mut wgs84 = SpatialRef::from_epsg(4326)?;
+        wgs84.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+        let mut target = dataset.spatial_ref()?;
+        target.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+
+        let bounds = CoordTransform::new(&wgs84, &target)?.transform_bounds(&bbox, 21)?;
+        Self::from_bounds(&dataset.geo_transform()?, bounds, dataset.raster_size())
+    }
+
+    fn clipped(
+        min: (isize, isize),
+        max: (isize, isize),
+        raster_size: (usize, usize),
+    ) -> Result<Self> {
+        let clip = |v: isize, size: usize| v.clamp(0, size as isize) as usize;
+        let (x0, y0) = (clip(min.0, raster_size.0), clip(min.1, raster_size.1));
+        let (x1, y1) = (clip(max.0, raster_size.0), clip(max.1, raster_size.1));
+        ensure!(x1 > x0 && y1 > y0, "window does not intersect the raster");
+        Ok(Self {
+            offset: (x0, y0),
+            size: (x1 - x0, y1 - y0),
+        })
+    }
+
+    /// Pixels shared by both windows.
+    #[must_use]
+    pub fn intersection(&self, other: &Self) -> Option<Self> {
+        let x0 = self.offset.0.max(other.offset.0);
+        let y0 = self.offset.1.max(other.offset.1);
+        let x1 = (self.offset.0 + self.size.0).min(other.offset.0 + other.size.0);
+        let y1 = (self.offset.1 + self.size.1).min(other.offset.1 + other.size.1);
+        (x1 > x0 && y1 > y0).then_some(Self {
+            offset: (x0, y0),
+            size: (x1 - x0, y1 - y0),
+        })
+    }
+
+    /// Geotransform of a raster covering just this window.
+    #[must_use]
+    pub fn geo_transform(&self, geo_transform: &GeoTransform) -> GeoTransform {
+        let (x, y) = geo_transform.apply(self.offset.0 as f64, self.offset.1 as f64);
+        let mut window_transform = *geo_transform;
+        window_transform[0] = x;
+        window_transform[3] = y;
+        window_transform
+    }
+
+    /// Range of `block_size` blocks of the source raster that intersect this window.
+    #[must_use]
+    pub fn blocks(&self, block_size: (usize, usize)) -> (Range<usize>, Range<usize>) {
+        (
+            self.offset.0 / block_size.0..(self.offset.0 + self.size.0).div_ceil(block_size.0),
+            self.offset.1 / block_size.1..(self.offset.1 + self.size.1).div_ceil(block_size.1),
+        )
+    }
+
+    /// Part of source block `(x, y)` that lies inside this window.
+    #[must_use]
+    pub fn block(&self, block_size: (usize, usize), block: (usize, usize)) -> Self {
+        let x0 = (block.0 * block_size.0).max(self.offset.0);
+        let y0 = (block.1 * block_size.1).max(self.offset.1);
+        let x1 = ((block.0 + 1) * block_size.0).min(self.offset.0 + self.size.0);
+        let y1 = ((block.1 + 1) * block_size.1).min(self.offset.1 + self.size.1);
+        Self {
+            offset: (x0, y0),
+            size: (x1 - x0, y1 - y0),
+        }
+    }
+
+    /// This window grown by `margin` pixels on each side, clipped to the raster.
+    #[must_use]
+    pub fn grow(&self, margin: usize, raster_size: (usize, usize)) -> Self {
+        let x0 = self.offset.0.saturating_sub(margin);
+        let y0 = self.offset.1.saturating_sub(margin);
+        let x1 = (self.offset.0 + self.size.0 + margin).min(raster_size.0);
+        let y1 = (self.offset.1 + self.size.1 + margin).min(raster_size.1);
+        Self {
+            offset: (x0, y0),
+            size: (x1 - x0, y1 - y0),
+        }
+    }
+
+    /// Offset of `inner` relative to this window, e.g. for writing it to a windowed output.
+    #[must_use]
+    pub fn relative_offset(&self, inner: &Self) -> (isize, isize) {
+        (
+            inner.offset.0 as isize - self.offset.0 as isize,
+            inner.offset.1 as isize - self.offset.1 as isize,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 m pixels with the upper left corner at (300000, 5000000).
+    const GEO_TRANSFORM: GeoTransform = [300_000.0, 10.0, 0.0, 5_000_000.0, 0.0, -10.0];
+
+    fn rect(offset: (usize, usize), size: (usize, usize)) -> PixelWindow {
+        PixelWindow { offset, size }
+    }
+
+    #[test]
+    fn srcwin_is_clipped_to_the_raster() {
+        let srcwin = PixelWindow::from_srcwin([-10, 5, 30, 1000], (100, 50)).unwrap();
+        assert_eq!(srcwin, rect((0, 5), (20, 45)));
+        assert!(PixelWindow::from_srcwin([100, 0, 10, 10], (100, 50)).is_err());
+        assert!(PixelWindow::from_srcwin([0, 0, 0, 10], (100, 50)).is_err());
+    }
+
+    #[test]
+    fn bounds_cover_every_partly_covered_pixel() {
+        let bounds = [300_015.0, 4_999_905.0, 300_100.0, 4_999_990.0];
+        let window = PixelWindow::from_bounds(&GEO_TRANSFORM, bounds, (100, 100)).unwrap();
+        assert_eq!(window, rect((1, 1), (9, 9)));
+    }
+
+    #[test]
+    fn projwin_takes_corners_in_either_order() {
+        let projwin = [300_000.0, 5_000_000.0, 300_200.0, 4_999_900.0];
+        let expected = rect((0, 0), (20, 10));
+        assert_eq!(
+            PixelWindow::from_projwin(&GEO_TRANSFORM, projwin, (100, 100)).unwrap(),
+            expected
+        );
+        let [ulx, uly, lrx, lry] = projwin;
+        assert_eq!(
+            PixelWindow::from_projwin(&GEO_TRANSFORM, [lrx, lry, ulx, uly], (100, 100)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn geo_transform_moves_the_origin_to_the_window() {
+        let transform = rect((3, 4), (5, 5)).geo_transform(&GEO_TRANSFORM);
+        assert_eq!(transform, [300_030.0, 10.0, 0.0, 4_999_960.0, 0.0, -10.0]);
+    }
+
+    #[test]
+    fn intersection() {
+        let a = rect((0, 0), (10, 10));
+        assert_eq!(
+            a.intersection(&rect((5, 8), (10, 10))),
+            Some(rect((5, 8), (5, 2)))
+        );
+        assert_eq!(a.intersection(&rect((10, 0), (5, 5))), None);
+    }
+
+    #[test]
+    fn blocks_are_clipped_to_the_window() {
+        let window = rect((100, 300), (500, 200));
+        let (xs, ys) = window.blocks((256, 256));
+        assert_eq!((xs.clone(), ys.clone()), (0..3, 1..2));
+
+        let regions = ys
+            .flat_map(|y| xs.clone().map(move |x| (x, y)))
+            .map(|block| window.block((256, 256), block))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            regions,
+            [
+                rect((100, 300), (156, 200)),
+                rect((256, 300), (256, 200)),
+                rect((512, 300), (88, 200)),
+            ]
+        );
+        let pixels = regions.iter().map(|r| r.size.0 * r.size.1).sum::<usize>();
+        assert_eq!(pixels, window.size.0 * window.size.1);
+    }
+
+    #[test]
+    fn grow_stops_at_the_raster_edges() {
+        assert_eq!(
+            rect((1, 5), (10, 10)).grow(3, (12, 100)),
+            rect((0, 2), (12, 16))
+        );
+    }
+
+    #[test]
+    fn relative_offset() {
+        let outer = rect((100, 200), (50, 50));
+        assert_eq!(outer.relative_offset(&rect((120, 210), (5, 5))), (20, 10));
+    }
+}
@@ -0,0 +1,297 @@
+use std::time::Instant;
+
+use anyhow::{ensure, Context as _, Result};
+use gdal::{
+    vector::{Feature, LayerAccess as _, LayerOptions, OGRFieldType, OGRwkbGeometryType},
+    Dataset, DriverManager, DriverType,
+};
+
+use crate::{
+    block_reader::ParallelBlockReader,
+    index::{Index, IndexSource},
+    polygons::PolygonLayer,
+    progress,
+    provenance::Provenance,
+    sensor::{Band, SensorArgs},
+    stac,
+    stats::{Histogram, Statistics},
+    vsi,
+    window::PixelWindow,
+    NDVI_RANGE,
+};
+
+/// Computes statistics of NDVI, or of a previously computed index raster, per polygon.
+#[derive(clap::Args)]
+pub struct ZonalArgs {
+    /// NIR band (B08), or `item=<path>` of a STAC Item
+    #[arg(long, default_value = "../data/T33TTG_20250305T100029_B08_10m.jp2")]
+    nir: String,
+
+    /// Red band (B04), or `item=<path>` of a STAC Item
+    #[arg(long, default_value = "../data/T33TTG_20250305T100029_B04_10m.jp2")]
+    red: String,
+
+    /// Index raster written by a previous run, used instead of computing NDVI from the bands.
+    /// Its scale, offset and nodata value are applied to the stored codes
+    #[arg(long)]
+    raster: Option<String>,
+
+    /// Vector file with the zone polygons
+    #[arg(long)]
+    zones: String,
+
+    /// Layer of the zones file to use, the first one by default
+    #[arg(long)]
+    zones_layer: Option<String>,
+
+    /// Output table, a CSV, GeoJSON or GeoPackage file picked by extension. Input fields and
+    /// geometries are copied (CSV output drops the geometries)
+    #[arg(short, long)]
+    output: String,
+
+    /// Percentiles to report, each as a `pNN` field
+    #[arg(long, value_delimiter = ',', default_value = "10,25,75,90")]
+    percentiles: Vec<f64>,
+
+    /// Number of histogram buckets per zone, bounding the error of the median and percentiles to
+    /// the index range divided by this
+    #[arg(long, default_value_t = 1000)]
+    bins: usize,
+
+    #[command(flatten)]
+    sensor: SensorArgs,
+
+    /// Number of threads reading blocks
+    #[arg(long, default_value_t = 8)]
+    io_threads: usize,
+}
+
+impl ZonalArgs {
+    /// Paths the command writes.
+    #[must_use]
+    pub fn outputs(&self) -> Vec<&str> {
+        vec![self.output.as_str()]
+    }
+}
+
+/// Name of the field holding percentile `p`, e.g. `p90` or `p2_5`.
+fn percentile_field(p: f64) -> String {
+    format!("p{p}").replace('.', "_")
+}
+
+/// Copies the zone features to `output` (or its staging file), adding their statistics as
+/// fields.
+fn write_table(args: &ZonalArgs, zones: &[Option<Statistics>]) -> Result<Dataset> {
+    let source = Dataset::open(&args.zones)?;
+    let mut source_layer = match &args.zones_layer {
+        Some(name) => source.layer_by_name(name)?,
+        None => source.layer(0)?,
+    };
+
+    let driver =
+        DriverManager::get_output_driver_for_dataset_name(&args.output, DriverType::Vector)
+            .context("unknown zonal statistics output format")?;
+    let mut output = driver.create_vector_only(vsi::writable_path(&args.output))?;
+    let srs = source_layer.spatial_ref();
+    let layer = output.create_layer(LayerOptions {
+        name: &source_layer.name(),
+        srs: srs.as_ref(),
+        ty: source_layer
+            .defn()
+            .geom_fields()
+            .next()
+            .map_or(OGRwkbGeometryType::wkbNone, |field| field.field_type()),
+        ..Default::default()
+    })?;
+
+    let source_fields = source_layer
+        .defn()
+        .fields()
+        .map(|field| (field.name(), field.field_type()))
+        .collect::<Vec<_>>();
+    let percentile_fields = args
+        .percentiles
+        .iter()
+        .map(|&p| percentile_field(p))
+        .collect::<Vec<_>>();
+    let mut fields = source_fields
+        .iter()
+        .map(|(name, ty)| (name.as_str(), *ty))
+        .collect::<Vec<_>>();
+    fields.extend([
+        ("count", OGRFieldType::OFTInteger64),
+        ("valid_frac", OGRFieldType::OFTReal),
+        ("mean", OGRFieldType::OFTReal),
+        ("median", OGRFieldType::OFTReal),
+        ("stddev", OGRFieldType::OFTReal),
+        ("min", OGRFieldType::OFTReal),
+        ("max", OGRFieldType::OFTReal),
+    ]);
+    fields.extend(
+        percentile_fields
+            .iter()
+            .map(|name| (name.as_str(), OGRFieldType::OFTReal)),
+    );
+    layer.create_defn_fields(&fields)?;
+
+    // Same skipping rule as `PolygonLayer::open`, so that feature `i` here is zone `i` there.
+    let mut zones = zones.iter();
+    for source_feature in source_layer
+        .features()
+        .filter(|feature| feature.geometry().is_some())
+    {
+        let stats = zones.next().context("zones layer changed while reading")?;
+
+        let mut feature = Feature::new(layer.defn())?;
+        for (name, value) in source_feature.fields() {
+            if let Some(value) = value {
+                feature.set_field(&name, &value)?;
+            }
+        }
+        if let Some(geometry) = source_feature.geometry() {
+            feature.set_geometry(geometry.clone())?;
+        }
+
+        let count = stats.as_ref().map_or(0, |stats| stats.valid);
+        feature.set_field_integer64("count", count as i64)?;
+        match stats {
+            Some(stats) if stats.valid > 0 => {
+                let quantile = |q: f64| {
+                    let histogram = stats.histogram.as_ref().unwrap();
+                    histogram.quantile(q).unwrap().clamp(stats.min, stats.max)
+                };
+                feature.set_field_double("valid_frac", stats.valid_percent() / 100.0)?;
+                feature.set_field_double("mean", stats.mean())?;
+                feature.set_field_double("median", quantile(0.5))?;
+                feature.set_field_double("stddev", stats.std_dev())?;
+                feature.set_field_double("min", stats.min)?;
+                feature.set_field_double("max", stats.max)?;
+                for (&p, name) in args.percentiles.iter().zip(&percentile_fields) {
+                    feature.set_field_double(name, quantile(p / 100.0))?;
+                }
+            }
+            Some(stats) => {
+                feature.set_field_double("valid_frac", stats.valid_percent() / 100.0)?;
+            }
+            None => {}
+        }
+        feature.create(&layer)?;
+    }
+
+    Ok(output)
+}
+
+/// Accumulates per-zone statistics block by block and writes them to the output table.
+pub fn run(args: &ZonalArgs) -> Result<()> {
+    ensure!(args.bins >= 1, "--bins must be at least 1");
+    ensure!(
+        args.percentiles.iter().all(|p| (0.0..=100.0).contains(p)),
+        "percentiles must be between 0 and 100"
+    );
+    let start = Instant::now();
+
+    let (source, range, inputs) = match &args.raster {
+        Some(path) => {
+            let (source, range) = IndexSource::open_raster(path)?;
+            (source, range, vec![path.clone()])
+        }
+        None => {
+            let nir = stac::resolve_band(&args.nir, Band::Nir)?;
+            let red = stac::resolve_band(&args.red, Band::Red)?;
+            let calibration = [
+                args.sensor.calibration(&nir)?,
+                args.sensor.calibration(&red)?,
+            ];
+            (
+                IndexSource::Computed(Index::Ndvi, calibration),
+                NDVI_RANGE,
+                vec![nir, red],
+            )
+        }
+    };
+
+    let dataset = Dataset::open(&inputs[0])?;
+    let source_geo_transform = dataset.geo_transform()?;
+    let zones = PolygonLayer::open(
+        &args.zones,
+        args.zones_layer.as_deref(),
+        &dataset.spatial_ref()?,
+    )?;
+    let window =
+        PixelWindow::from_bounds(&source_geo_transform, zones.bounds(), dataset.raster_size())
+            .context("zones do not intersect the raster")?;
+
+    let cancel = progress::interrupt_token();
+    let block_reader = ParallelBlockReader::new(&inputs, args.io_threads, Some(window))?
+        .on_progress(progress::bar())
+        .cancel_on(cancel.clone());
+
+    // Bounded, so that readers wait for the zone statistics instead of queueing up blocks in
+    // memory.
+    let (tx, rx) = flume::bounded(args.io_threads);
+    let dataset_indices = (0..inputs.len()).collect::<Vec<_>>();
+    for y in block_reader.blocks.1.clone() {
+        for x in block_reader.blocks.0.clone() {
+            let tx = tx.clone();
+            block_reader.run(
+                x,
+                y,
+                &dataset_indices,
+                Box::new(move |x, y, blocks| {
+                    tx.send((x, y, blocks)).unwrap();
+                }),
+            );
+        }
+    }
+    drop(tx);
+
+    // Allocated on first use, so zones outside the raster cost no histogram.
+    let mut zone_stats: Vec<Option<Statistics>> = vec![None; zones.len()];
+    let mut values = Vec::new();
+    for (x, y, mut blocks) in rx {
+        let blocks = dataset_indices
+            .iter()
+            .map(|i| blocks.remove(i).unwrap())
+            .collect::<Vec<_>>();
+        source.values(&blocks, &mut values);
+
+        let region = block_reader.region(x, y);
+        let coverages =
+            zones.coverages(&region.geo_transform(&source_geo_transform), region.size)?;
+        for coverage in &coverages {
+            let stats = zone_stats[coverage.polygon]
+                .get_or_insert_with(|| Statistics::new(Some(Histogram::new(range, args.bins))));
+            for value in coverage.values(&values, region.size.0) {
+                stats.add(value);
+            }
+        }
+    }
+
+    block_reader.join();
+    ensure!(
+        !cancel.is_cancelled(),
+        "interrupted, {} not written",
+        args.output
+    );
+
+    let output = write_table(args, &zone_stats)?;
+    let expression = match &args.raster {
+        Some(path) => format!("values of {path}"),
+        None => Index::Ndvi.expression(),
+    };
+    let provenance = Provenance::new(&inputs, format!("zonal statistics of {expression}"))
+        .with_calibration(&source.calibration());
+    let item = stac::vector_item(&output, &args.output, &provenance)?;
+    output.close()?;
+    vsi::commit(&vsi::writable_path(&args.output), &args.output)?;
+    item.write()?;
+
+    println!(
+        "Zonal statistics of {} zones written to {} in {:.3}s",
+        zones.len(),
+        args.output,
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}